@@ -0,0 +1,84 @@
+#![no_main]
+
+use libfuzzer_sys::{
+  arbitrary::{Result, Unstructured},
+  fuzz_target,
+};
+use std::collections::HashMap;
+use whily::{
+  config::{Config, Dialect},
+  debugger::Machine,
+  gen::Generator,
+  run::{run_with_input, RuntimeError},
+};
+
+// Runs generated programs on generated inputs with every way whily has of running a program, and
+// checks that they end in the same state or with the same error. The step limit is set so that
+// runs stop at the same step: without it, `run` caps the iterations of every loop instead.
+
+const MAX_STEPS: u64 = 100_000;
+
+type Outcome = std::result::Result<HashMap<String, u64>, (&'static str, Option<String>)>;
+
+fn outcome(result: std::result::Result<HashMap<String, u64>, RuntimeError>) -> Outcome {
+  result.map_err(|e| (e.kind(), e.variable().map(str::to_owned)))
+}
+
+fn check(u: &mut Unstructured) -> Result<()> {
+  let config = Config {
+    allow_named_vars: u.arbitrary()?,
+    allow_underflow: u.arbitrary()?,
+    allow_constants_everywhere: u.arbitrary()?,
+    extra_operators: u.arbitrary()?,
+    accelerate: false,
+    max_steps: Some(MAX_STEPS),
+    dialect: *u.choose(&Dialect::ALL)?,
+  };
+  let mut generator = Generator::new(
+    &config,
+    u.arbitrary()?,
+    u.int_in_range(1..=5)?,
+    u.int_in_range(0..=3)?,
+    u.arbitrary()?,
+  );
+  let mut state = HashMap::new();
+  for var in generator.variables() {
+    if u.arbitrary()? {
+      // Mostly small values, so that not every run overflows
+      let value = match u.ratio(1, 8)? {
+        true => u.arbitrary()?,
+        false => u.int_in_range(0..=20)?,
+      };
+      state.insert(var.clone(), value);
+    }
+  }
+  let inputs = state.keys().cloned().collect::<Vec<_>>();
+  let prog = generator.program_with_inputs(u.int_in_range(1..=40)?, &inputs);
+
+  let expected = outcome(run_with_input(&config, &prog, state.clone()));
+  let accelerated = Config {
+    accelerate: true,
+    ..config.clone()
+  };
+  let others = [
+    (
+      "accelerated run",
+      outcome(run_with_input(&accelerated, &prog, state.clone())),
+    ),
+    (
+      "debugger",
+      outcome(Machine::new(&config, &prog, state.clone()).finish()),
+    ),
+  ];
+  for (name, result) in others {
+    assert_eq!(
+      expected, result,
+      "The {name} differs on inputs {state:?} for\n{prog}"
+    );
+  }
+  Ok(())
+}
+
+fuzz_target!(|data: &[u8]| {
+  let _ = check(&mut Unstructured::new(data));
+});
@@ -0,0 +1,33 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use whily::{
+  compile,
+  config::{Config, Dialect},
+  error::render_all,
+};
+
+// Symbolizing and parsing never panic, whatever the bytes and options: every problem in the code
+// is reported as a diagnostic, which can be rendered against the code.
+
+fuzz_target!(|data: &[u8]| {
+  let code = String::from_utf8_lossy(data);
+  let everything = Config {
+    allow_named_vars: true,
+    allow_constants_everywhere: true,
+    extra_operators: true,
+    ..Config::default()
+  };
+  let configs =
+    [Config::default(), everything]
+      .into_iter()
+      .chain([Dialect::Schoening, Dialect::Kozen].map(|dialect| Config {
+        dialect,
+        ..Config::default()
+      }));
+  for mut config in configs {
+    if let Err(diagnostics) = compile(&mut config, &code) {
+      render_all(&diagnostics, &code);
+    }
+  }
+});
@@ -0,0 +1,450 @@
+use crate::{
+  config::Config,
+  parser::{Statement, Value},
+  run::{length, RuntimeError},
+  symbolizer::Operator,
+};
+use std::collections::HashMap;
+
+// Recognises linear counting loops like
+//   while a != 0 do r := r + b; a := a - 1 od
+// and runs them in constant time instead of once per iteration.
+//
+// A loop qualifies when its body is a flat sequence of statements that
+//  - decrements the loop variable by a loop-invariant amount exactly once, and
+//  - otherwise only adds loop-invariant amounts to (or subtracts them from) other variables,
+// where loop-invariant means a constant or a variable the body never assigns to.
+// Any error the loop would have run into (overflow, underflow) is reported for the
+// same statement it would have been reported for when running every iteration, with the state
+// and the costs of the iterations up to it.
+// Loops that would take more steps than the budget are left to the normal loop, which
+// stops at exactly the right step.
+//
+// The logarithmic cost and the space the loop takes (see `Counters`) are computed exactly as
+// well: every variable goes through an arithmetic progression, and the length of its values
+// only changes when it reaches a power of two, which happens at most 64 times.
+
+struct Update<'a> {
+  statement: &'a Statement,
+  var: &'a str,
+  operator: &'a Operator,
+  amount: &'a Value,
+}
+
+/// What running an accelerated loop took, see `Counters`.
+pub struct LoopCost {
+  pub steps: u64,
+  // The cost under the logarithmic cost model
+  pub log_cost: u64,
+  // How much the total length of the values in the state changed, at the end and at its highest
+  pub space_change: i64,
+  pub peak_space_change: u64,
+  // How often the condition was checked and every statement of the body ran, in order
+  pub checks: u64,
+  pub executions: Vec<u64>,
+}
+
+/// The statement of a loop body that failed, with its error.
+pub type Failure<'a> = (&'a Statement, RuntimeError);
+
+struct LinearLoop<'a> {
+  // Index in `updates` of the statement decrementing the loop variable
+  counter: usize,
+  updates: Vec<Update<'a>>,
+}
+
+/// Tries to run `while cv != 0 do body od` in closed form, returning what it took and the
+/// statement of the body that failed, if one did.
+/// Returns `None` when the loop isn't a linear counting loop (or can't be accelerated
+/// with the current state or within `budget` steps), in which case it has to be run normally.
+pub fn try_accelerate<'a>(
+  config: &Config,
+  cv: &str,
+  body: &'a Statement,
+  state: &mut HashMap<String, u64>,
+  budget: Option<u64>,
+) -> Option<(LoopCost, Result<(), Failure<'a>>)> {
+  let linear = recognise(cv, body)?;
+  execute(config, cv, &linear, state, budget)
+}
+
+fn flatten<'a>(statement: &'a Statement, out: &mut Vec<&'a Statement>) {
+  match statement {
+    Statement::S(left, right) => {
+      flatten(left, out);
+      flatten(right, out);
+    }
+    s => out.push(s),
+  }
+}
+
+fn recognise<'a>(cv: &str, body: &'a Statement) -> Option<LinearLoop<'a>> {
+  let mut statements = vec![];
+  flatten(body, &mut statements);
+
+  let mut counter = None;
+  let mut updates = vec![];
+  for statement in statements {
+    let update = match statement {
+      // v := v + e, v := v - e
+      Statement::DeclareOperation(
+        v0,
+        Value::Variable(v1),
+        operator @ (Operator::Add | Operator::Subtract),
+        amount,
+      ) if v0 == v1 => Update {
+        statement,
+        var: v0,
+        operator,
+        amount,
+      },
+      // v := e + v
+      Statement::DeclareOperation(v0, amount, operator @ Operator::Add, Value::Variable(v2))
+        if v0 == v2 =>
+      {
+        Update {
+          statement,
+          var: v0,
+          operator,
+          amount,
+        }
+      }
+      _ => return None,
+    };
+    if update.var == cv {
+      if counter.is_some() || !matches!(update.operator, Operator::Subtract) {
+        return None;
+      }
+      counter = Some(updates.len());
+    }
+    if updates.iter().any(|u: &Update| u.var == update.var) {
+      return None;
+    }
+    updates.push(update);
+  }
+
+  // Every amount has to stay the same throughout the loop
+  for update in &updates {
+    if let Value::Variable(var) = update.amount {
+      if updates.iter().any(|u| u.var == var) {
+        return None;
+      }
+    }
+  }
+
+  Some(LinearLoop {
+    counter: counter?,
+    updates,
+  })
+}
+
+fn execute<'a>(
+  config: &Config,
+  cv: &str,
+  linear: &LinearLoop<'a>,
+  state: &mut HashMap<String, u64>,
+  budget: Option<u64>,
+) -> Option<(LoopCost, Result<(), Failure<'a>>)> {
+  let counter_value = *state.get(cv)?;
+  if counter_value == 0 {
+    let cost = LoopCost {
+      steps: 1,
+      log_cost: 1,
+      space_change: 0,
+      peak_space_change: 0,
+      checks: 1,
+      executions: vec![0; linear.updates.len()],
+    };
+    return (budget != Some(0)).then_some((cost, Ok(())));
+  }
+
+  // Unassigned variables are left to the normal loop, so the error shows up at the right time
+  let mut values = vec![];
+  let mut amounts = vec![];
+  for update in &linear.updates {
+    values.push(*state.get(update.var)?);
+    amounts.push(match update.amount {
+      Value::Variable(var) => *state.get(var)?,
+      Value::Constant(c) => *c,
+    });
+  }
+
+  let step = amounts[linear.counter];
+  if step == 0 {
+    // Never terminates, let the normal loop run into its limit
+    return None;
+  }
+  let full_iterations = counter_value / step;
+  let remainder = counter_value % step;
+
+  // Every iteration checks the condition and runs the body, and there's a final check
+  let iterations = full_iterations as u128 + (remainder != 0) as u128;
+  // Finishing takes the most steps, which is what has to fit in the budget
+  let max_steps = iterations * (linear.updates.len() as u128 + 1) + 1;
+  if budget.is_some_and(|budget| max_steps > budget as u128) {
+    return None;
+  }
+
+  // The (iteration, statement) at which the first error would occur, if any
+  let mut first_error: Option<(u128, usize, RuntimeError)> = None;
+  let mut report = |iteration: u128, position: usize, error: RuntimeError| {
+    if first_error
+      .as_ref()
+      .is_none_or(|(i, p, _)| (iteration, position) < (*i, *p))
+    {
+      first_error = Some((iteration, position, error));
+    }
+  };
+
+  if remainder != 0 && !config.underflow_allowed() {
+    report(
+      full_iterations as u128 + 1,
+      linear.counter,
+      RuntimeError::VariableUnderflow(cv.to_owned()),
+    );
+  }
+
+  // How many times each statement gets executed, assuming no errors
+  let executions = |position: usize| -> u128 {
+    let full_iterations = full_iterations as u128;
+    if remainder == 0 {
+      full_iterations
+    } else if config.underflow_allowed() || position < linear.counter {
+      // The last, partial, iteration still runs these statements
+      full_iterations + 1
+    } else {
+      full_iterations
+    }
+  };
+
+  for (position, update) in linear.updates.iter().enumerate() {
+    if position == linear.counter || amounts[position] == 0 {
+      continue;
+    }
+    let value = values[position] as u128;
+    let amount = amounts[position] as u128;
+    let failing_iteration = match update.operator {
+      Operator::Add => (u64::MAX as u128 - value) / amount + 1,
+      Operator::Subtract if !config.underflow_allowed() => value / amount + 1,
+      _ => continue,
+    };
+    if failing_iteration <= executions(position) {
+      let error = match update.operator {
+        Operator::Add => RuntimeError::VariableOverflow(update.var.to_owned()),
+        _ => RuntimeError::VariableUnderflow(update.var.to_owned()),
+      };
+      report(failing_iteration, position, error);
+    }
+  }
+
+  // How often every statement runs, including the one that fails as its step is taken first,
+  // and how often it updates its variable
+  let failure = first_error.as_ref().map(|(i, p, _)| (*i, *p));
+  let runs = |position: usize| match failure {
+    Some((i, p)) => i - 1 + (position <= p) as u128,
+    None => executions(position),
+  };
+  let updates = |position: usize| match failure {
+    Some((i, p)) => i - 1 + (position < p) as u128,
+    None => executions(position),
+  };
+  let checks = failure.map_or(iterations + 1, |(i, _)| i);
+  let steps = checks + (0..linear.updates.len()).map(runs).sum::<u128>();
+
+  // Every check sees the next value of the loop variable, and the last one sees 0 when the loop
+  // finishes
+  let counter_start = values[linear.counter];
+  let mut log_cost = length_sum(counter_start, &Operator::Subtract, step, checks);
+  for (position, update) in linear.updates.iter().enumerate() {
+    let count = runs(position);
+    log_cost += length_sum(values[position], update.operator, amounts[position], count)
+      + count * length(amounts[position]) as u128;
+  }
+
+  // The total length of the updated variables after `i` iterations and the statement at
+  // `position` of the next one
+  let total_length = |i: u128, position: usize| -> u128 {
+    let mut total = 0;
+    for (q, update) in linear.updates.iter().enumerate() {
+      let count = (i + (q <= position) as u128).min(updates(q));
+      let value = value_after(values[q], update.operator, amounts[q], count);
+      total += length(value as u64) as u128;
+    }
+    total
+  };
+  // The total can only reach a new high right when a growing variable reaches a power of two
+  let start_length = values.iter().map(|&v| length(v) as u128).sum::<u128>();
+  let mut peak_length = start_length;
+  for (q, update) in linear.updates.iter().enumerate() {
+    if !matches!(update.operator, Operator::Add) || amounts[q] == 0 {
+      continue;
+    }
+    for b in 1..64 {
+      let threshold = 1u128 << b;
+      if threshold <= values[q] as u128 {
+        continue;
+      }
+      let count = (threshold - values[q] as u128).div_ceil(amounts[q] as u128);
+      if count > updates(q) {
+        break;
+      }
+      peak_length = peak_length.max(total_length(count - 1, q));
+    }
+  }
+  let end_length = total_length(iterations, usize::MAX);
+
+  for (position, update) in linear.updates.iter().enumerate() {
+    let result = value_after(
+      values[position],
+      update.operator,
+      amounts[position],
+      updates(position),
+    );
+    state.insert(update.var.to_owned(), result as u64);
+  }
+  let cost = LoopCost {
+    steps: steps.try_into().unwrap_or(u64::MAX),
+    log_cost: log_cost.try_into().unwrap_or(u64::MAX),
+    space_change: end_length as i64 - start_length as i64,
+    peak_space_change: (peak_length - start_length) as u64,
+    checks: checks.try_into().unwrap_or(u64::MAX),
+    executions: (0..linear.updates.len())
+      .map(|position| runs(position).try_into().unwrap_or(u64::MAX))
+      .collect(),
+  };
+  let result = match first_error {
+    Some((_, position, error)) => Err((linear.updates[position].statement, error)),
+    None => Ok(()),
+  };
+  Some((cost, result))
+}
+
+/// The value of a variable starting at `start` after `count` updates of `amount`.
+fn value_after(start: u64, operator: &Operator, amount: u64, count: u128) -> u128 {
+  match operator {
+    Operator::Add => start as u128 + count * amount as u128,
+    _ => (start as u128).saturating_sub(count * amount as u128),
+  }
+}
+
+/// The sum of the lengths of a variable starting at `start` right before each of `count` updates.
+fn length_sum(start: u64, operator: &Operator, amount: u64, count: u128) -> u128 {
+  if amount == 0 {
+    return count * length(start) as u128;
+  }
+  // Every value has a length of at least 1, plus 1 for every 2^b (b >= 1) it reaches
+  let mut sum = count;
+  let (start, amount) = (start as u128, amount as u128);
+  for b in 1..64 {
+    let threshold = 1u128 << b;
+    sum += match operator {
+      Operator::Add if start >= threshold => count,
+      Operator::Add => count.saturating_sub((threshold - start).div_ceil(amount)),
+      _ if start < threshold => 0,
+      _ => ((start - threshold) / amount + 1).min(count),
+    };
+  }
+  sum
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{
+    compile,
+    config::{Config, Dialect},
+    gen::{Generator, Rng},
+    parser::Statement,
+    profile::Profile,
+    run::{run_counted, CostModel, Counters},
+    symbolizer::Span,
+  };
+  use std::collections::HashMap;
+
+  const SPAN: Span = Span {
+    line: 1,
+    col: 1,
+    end_line: 1,
+    end_col: 1,
+  };
+
+  /// Runs `prog` with and without acceleration and checks that everything that gets counted,
+  /// the result and the statement an error is reported for are the same.
+  fn check(config: &Config, prog: &Statement, state: HashMap<String, u64>) {
+    let spans = vec![SPAN; prog.nodes().len()];
+    for cost_model in [CostModel::Uniform, CostModel::Log] {
+      let mut runs = [true, false].map(|accelerate| {
+        let config = Config {
+          accelerate,
+          ..config.clone()
+        };
+        let mut counters = Counters {
+          cost_model,
+          profile: Some(Profile::new(prog)),
+          ..Default::default()
+        };
+        let result = run_counted(&config, prog, state.clone(), &mut counters)
+          .map_err(|e| (e.kind(), e.variable().map(str::to_owned)));
+        (result, counters)
+      });
+      let [(accelerated, a), (expected, b)] = &mut runs;
+      let context = format!("on {state:?} for\n{prog}");
+      assert_eq!(accelerated, expected, "Different results {context}");
+      assert_eq!(a.steps, b.steps, "Different steps {context}");
+      assert_eq!(a.cost, b.cost, "Different costs {context}");
+      assert_eq!(a.space, b.space, "Different space {context}");
+      assert_eq!(a.max_space, b.max_space, "Different max space {context}");
+      assert_eq!(a.max_value, b.max_value, "Different max values {context}");
+      assert_eq!(a.failed_at, b.failed_at, "Different error spans {context}");
+      let [a, b] = [a, b].map(|c| c.profile.take().unwrap());
+      assert_eq!(
+        a.folded_stacks(prog, &spans),
+        b.folded_stacks(prog, &spans),
+        "Different profiles {context}"
+      );
+    }
+  }
+
+  fn check_code(code: &str) {
+    let mut config = Config::default();
+    let prog = compile(&mut config, code).unwrap_or_else(|_| panic!("Invalid program {code}"));
+    check(&config, &prog, HashMap::new());
+  }
+
+  #[test]
+  fn errors_in_accelerated_loops() {
+    check_code("#allow_constants_everywhere\nx0 := 18446744073709551610; x1 := 10; while x1 != 0 do x0 := x0 + 1; x1 := x1 - 1 od");
+    check_code("#allow_constants_everywhere\nx0 := 18446744073709551610; x1 := 10; while x1 != 0 do x1 := x1 - 1; x0 := 2 + x0 od");
+    check_code("#allow_constants_everywhere\nx0 := 5; x1 := 10; while x1 != 0 do x1 := x1 - 1; x0 := x0 - 1 od");
+    check_code("#allow_constants_everywhere\nx0 := 5; x1 := 10; while x1 != 0 do x0 := x0 - 1; x1 := x1 - 1 od");
+    check_code("#allow_constants_everywhere\nx0 := 0; x1 := 10; while x1 != 0 do x0 := x0 + 1; x1 := x1 - 3; x2 := x2 + 1 od");
+    check_code("#allow_constants_everywhere\nx0 := 0; x1 := 10; x2 := 0; while x1 != 0 do x0 := x0 + 1; x1 := x1 - 3; x2 := x2 + 1 od");
+    check_code("#allow_constants_everywhere\n#allow_underflow\nx0 := 0; x1 := 10; x2 := 0; while x1 != 0 do x0 := x0 + 7; x1 := x1 - 3; x2 := x2 - 1 od");
+    check_code("#allow_constants_everywhere\nx0 := 0; x3 := 1000; while x3 != 0 do x1 := 100; while x1 != 0 do x0 := x0 + 99999999999999; x1 := x1 - 1 od; x3 := x3 - 1 od");
+  }
+
+  #[test]
+  fn generated_programs() {
+    let mut rng = Rng::new(26);
+    for seed in 0..200 {
+      let config = Config {
+        allow_underflow: rng.chance(1, 2),
+        allow_constants_everywhere: rng.chance(1, 2),
+        max_steps: Some(10_000),
+        dialect: *rng.pick(&Dialect::ALL),
+        ..Config::default()
+      };
+      let mut generator = Generator::new(&config, seed, 3, 2, rng.chance(1, 2));
+      let mut state = HashMap::new();
+      for var in generator.variables() {
+        let value = match rng.chance(1, 4) {
+          true => u64::MAX - rng.below(1000),
+          false => rng.below(20),
+        };
+        state.insert(var.clone(), value);
+      }
+      let inputs = state.keys().cloned().collect::<Vec<_>>();
+      let prog = generator.program_with_inputs(1 + rng.below(20) as usize, &inputs);
+      check(&config, &prog, state);
+    }
+  }
+}
@@ -0,0 +1,594 @@
+use crate::{
+  config::Config,
+  equiv::Range,
+  godel::{evaluate_function, pair_u64},
+  output::sorted_variables,
+  parser::{Statement, Value},
+  symbolizer::{Operator, Span},
+};
+use std::{collections::HashMap, fmt::Write};
+
+// Static analysis of which assignments can overflow or underflow. Every variable is tracked as an
+// interval together with a congruence (the value is `rest` modulo `modulus`), which is what
+// proves that a loop like `while x1 != 0 do x1 := x1 - 2 od` can't underflow for even x1.
+//
+// Loops are analysed by running their body on the state at the head of the loop until that
+// state stops growing. After a few rounds, bounds that are still moving are widened to 0 or
+// 2^64 - 1 so this always ends, and then a few more rounds narrow them back where the loop
+// allows it. Only the last round, on the final state, records what the assignments can do.
+//
+// Reading an unassigned variable is an error in the default dialect, so the analysis lets such
+// variables have any value, which is always sound. In the textbook dialects they are 0.
+
+const WIDEN_AFTER: usize = 3;
+const NARROW_ROUNDS: usize = 3;
+
+/// The values a variable can have, as `lo..=hi` intersected with `rest` modulo `modulus`. A
+/// modulus of 0 means the value is exactly `rest`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Abstract {
+  pub lo: u64,
+  pub hi: u64,
+  pub modulus: u64,
+  pub rest: u64,
+}
+
+/// What an assignment can do, from never running to always failing.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Verdict {
+  Unreachable,
+  Safe,
+  Possible,
+  Certain,
+}
+
+type State = HashMap<String, Abstract>;
+
+fn gcd(a: u128, b: u128) -> u128 {
+  if b == 0 {
+    a
+  } else {
+    gcd(b, a % b)
+  }
+}
+
+impl Abstract {
+  fn top() -> Self {
+    Self {
+      lo: 0,
+      hi: u64::MAX,
+      modulus: 1,
+      rest: 0,
+    }
+  }
+
+  fn constant(c: u64) -> Self {
+    Self {
+      lo: c,
+      hi: c,
+      modulus: 0,
+      rest: c,
+    }
+  }
+
+  /// The values in `lo..=hi` that are `rest` modulo `modulus`, or `None` if there are none.
+  fn new(lo: u128, hi: u128, modulus: u128, rest: u128) -> Option<Self> {
+    let hi = hi.min(u64::MAX as u128);
+    if lo > hi {
+      return None;
+    }
+    // A modulus that doesn't fit says next to nothing
+    let (modulus, rest) = if modulus > u64::MAX as u128 {
+      (1, 0)
+    } else {
+      (modulus, rest)
+    };
+    let (lo, hi) = match modulus {
+      0 if (lo..=hi).contains(&rest) => (rest, rest),
+      0 => return None,
+      m => {
+        let rest = rest % m;
+        (
+          lo + (rest + m - lo % m) % m,
+          hi.checked_sub((hi % m + m - rest) % m)?,
+        )
+      }
+    };
+    if lo > hi || hi > u64::MAX as u128 {
+      return None;
+    }
+    if lo == hi {
+      return Some(Self::constant(lo as u64));
+    }
+    Some(Self {
+      lo: lo as u64,
+      hi: hi as u64,
+      modulus: modulus as u64,
+      rest: (rest % modulus) as u64,
+    })
+  }
+
+  fn join(self, other: Self) -> Self {
+    let (modulus, rest) = self.join_congruence(other);
+    let (lo, hi) = (self.lo.min(other.lo), self.hi.max(other.hi));
+    Self::new(lo as u128, hi as u128, modulus, rest).expect("A join is never empty")
+  }
+
+  fn join_congruence(self, other: Self) -> (u128, u128) {
+    let difference = (self.rest as u128).abs_diff(other.rest as u128);
+    let modulus = gcd(gcd(self.modulus as u128, other.modulus as u128), difference);
+    match modulus {
+      0 => (0, self.rest as u128),
+      m => (m, self.rest as u128 % m),
+    }
+  }
+
+  /// Like `join`, but bounds that grow are moved all the way out.
+  fn widen(self, next: Self) -> Self {
+    let (modulus, rest) = self.join_congruence(next);
+    let lo = if next.lo < self.lo { 0 } else { self.lo };
+    let hi = if next.hi > self.hi { u64::MAX } else { self.hi };
+    Self::new(lo as u128, hi as u128, modulus, rest).expect("A widening is never empty")
+  }
+
+  fn nonzero(self) -> Option<Self> {
+    Self::new(
+      self.lo.max(1) as u128,
+      self.hi as u128,
+      self.modulus as u128,
+      self.rest as u128,
+    )
+  }
+
+  fn zero(self) -> Option<Self> {
+    // The bounds always are values the congruence allows
+    (self.lo == 0).then_some(Self::constant(0))
+  }
+
+  fn congruence(self) -> (u128, u128) {
+    (self.modulus as u128, self.rest as u128)
+  }
+}
+
+impl std::fmt::Display for Abstract {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self.modulus {
+      0 => write!(f, "{}", self.rest),
+      1 => write!(f, "{}..={}", self.lo, self.hi),
+      m => write!(f, "{}..={}, {} mod {m}", self.lo, self.hi, self.rest),
+    }
+  }
+}
+
+/// The error an assignment can fail with, if any.
+fn failure(statement: &Statement) -> Option<&'static str> {
+  match statement {
+    Statement::DeclareOperation(_, _, Operator::Subtract, _) => Some("underflow"),
+    Statement::DeclareOperation(
+      _,
+      _,
+      Operator::Add | Operator::Multiply | Operator::Pair | Operator::Cons,
+      _,
+    ) => Some("overflow"),
+    _ => None,
+  }
+}
+
+impl Verdict {
+  fn join(self, other: Self) -> Self {
+    match (self, other) {
+      (Verdict::Unreachable, v) | (v, Verdict::Unreachable) => v,
+      (a, b) if a == b => a,
+      _ => Verdict::Possible,
+    }
+  }
+
+  /// The verdict for an operation whose smallest and largest results are given, and which fails
+  /// above `u64::MAX`.
+  fn of_bounds(lo: u128, hi: u128) -> Self {
+    match (lo > u64::MAX as u128, hi > u64::MAX as u128) {
+      (true, _) => Verdict::Certain,
+      (false, true) => Verdict::Possible,
+      (false, false) => Verdict::Safe,
+    }
+  }
+}
+
+pub struct Analyser<'a> {
+  config: &'a Config,
+  recording: bool,
+  verdicts: HashMap<*const Statement, Verdict>,
+}
+
+impl<'a> Analyser<'a> {
+  pub fn new(config: &'a Config) -> Self {
+    Self {
+      config,
+      recording: true,
+      verdicts: HashMap::new(),
+    }
+  }
+
+  fn unassigned(&self) -> Abstract {
+    if self.config.dialect.is_textbook() {
+      Abstract::constant(0)
+    } else {
+      Abstract::top()
+    }
+  }
+
+  fn read(&self, state: &State, value: &Value) -> Abstract {
+    match value {
+      Value::Constant(c) => Abstract::constant(*c),
+      Value::Variable(var) => state.get(var).copied().unwrap_or(self.unassigned()),
+    }
+  }
+
+  fn merge(
+    &self,
+    a: Option<State>,
+    b: Option<State>,
+    combine: impl Fn(Abstract, Abstract) -> Abstract,
+  ) -> Option<State> {
+    let (mut a, b) = match (a, b) {
+      (Some(a), Some(b)) => (a, b),
+      (a, b) => return a.or(b),
+    };
+    let unassigned = self.unassigned();
+    for (var, value) in &mut a {
+      *value = combine(*value, b.get(var).copied().unwrap_or(unassigned));
+    }
+    for (var, value) in b {
+      a.entry(var).or_insert_with(|| combine(unassigned, value));
+    }
+    Some(a)
+  }
+
+  fn record(&mut self, statement: &Statement, verdict: Verdict) {
+    if self.recording {
+      let entry = self
+        .verdicts
+        .entry(statement as *const Statement)
+        .or_insert(Verdict::Unreachable);
+      *entry = entry.join(verdict);
+    }
+  }
+
+  /// Runs an assignment on the state, returning `None` if it always fails.
+  fn assignment(&mut self, statement: &Statement, mut state: State) -> Option<State> {
+    let (v0, v1, operator, v2) = match statement {
+      Statement::DeclareOperation(v0, v1, operator, v2) => (v0, v1, operator, v2),
+      Statement::DeclareConst(v0, v) => (v0, v, &Operator::Add, &Value::Constant(0)),
+      _ => unreachable!("Not an assignment"),
+    };
+    let (a, b) = (self.read(&state, v1), self.read(&state, v2));
+    let (a_lo, a_hi, b_lo, b_hi) = (a.lo as u128, a.hi as u128, b.lo as u128, b.hi as u128);
+    let ((m1, r1), (m2, r2)) = (a.congruence(), b.congruence());
+    let result = match operator {
+      Operator::Add => {
+        let (lo, hi) = (a_lo + b_lo, a_hi + b_hi);
+        self.record(statement, Verdict::of_bounds(lo, hi));
+        let modulus = gcd(m1, m2);
+        Abstract::new(lo, hi, modulus, r1 + r2)
+      }
+      Operator::Multiply => {
+        let (lo, hi) = (a_lo * b_lo, a_hi * b_hi);
+        self.record(statement, Verdict::of_bounds(lo, hi));
+        let modulus = gcd(gcd(m1 * m2, m1 * r2), m2 * r1);
+        Abstract::new(lo, hi, modulus, r1 * r2)
+      }
+      Operator::Subtract => {
+        let verdict = match (a_hi < b_lo, a_lo < b_hi) {
+          (true, _) => Verdict::Certain,
+          (false, true) => Verdict::Possible,
+          (false, false) => Verdict::Safe,
+        };
+        self.record(statement, verdict);
+        let modulus = gcd(m1, m2);
+        let rest = match modulus {
+          0 => r1.saturating_sub(r2),
+          m => r1 % m + m - r2 % m,
+        };
+        let difference = (a_hi >= b_lo)
+          .then(|| Abstract::new(a_lo.saturating_sub(b_hi), a_hi - b_lo, modulus, rest))
+          .flatten();
+        let truncated = (verdict != Verdict::Safe && self.config.underflow_allowed())
+          .then_some(Abstract::constant(0));
+        match (difference, truncated) {
+          (Some(d), Some(t)) => Some(d.join(t)),
+          (d, t) => d.or(t),
+        }
+      }
+      function if a.modulus == 0 && b.modulus == 0 => {
+        let value = evaluate_function(function, a.rest, b.rest);
+        if matches!(function, Operator::Pair | Operator::Cons) {
+          let verdict = if value.is_some() {
+            Verdict::Safe
+          } else {
+            Verdict::Certain
+          };
+          self.record(statement, verdict);
+        }
+        value.map(Abstract::constant)
+      }
+      Operator::Pair | Operator::Cons => {
+        // Both are increasing in both arguments
+        let cons = u128::from(*operator == Operator::Cons);
+        let bound =
+          |x: u64, y: u64| pair_u64(x, y).map_or(u64::MAX as u128 + 1, |p| p as u128 + cons);
+        let (lo, hi) = (bound(a.lo, b.lo), bound(a.hi, b.hi));
+        self.record(statement, Verdict::of_bounds(lo, hi));
+        Abstract::new(lo, hi, 1, 0)
+      }
+      // Taking apart a pair or list gives something at most as large
+      _ => Abstract::new(0, a_hi, 1, 0),
+    };
+    state.insert(v0.to_owned(), result?);
+    Some(state)
+  }
+
+  /// The state at the head of a loop where the condition is checked, refined by it being `zero`
+  /// or not.
+  fn check(&self, state: &Option<State>, cv: &str, zero: bool) -> Option<State> {
+    let mut state = state.clone()?;
+    let value = state.get(cv).copied().unwrap_or(self.unassigned());
+    let refined = if zero { value.zero() } else { value.nonzero() };
+    state.insert(cv.to_owned(), refined?);
+    Some(state)
+  }
+
+  fn run(&mut self, statement: &Statement, state: Option<State>) -> Option<State> {
+    let state = state?;
+    match statement {
+      Statement::S(left, right) => {
+        let state = self.run(left, Some(state));
+        self.run(right, state)
+      }
+      Statement::While(cv, body) => {
+        let entry = Some(state);
+        let recording = std::mem::replace(&mut self.recording, false);
+        let mut head = entry.clone();
+        for round in 0.. {
+          let after_body = self.run(body, self.check(&head, cv, false));
+          let joined = self.merge(head.clone(), after_body, Abstract::join);
+          let next = if round >= WIDEN_AFTER {
+            self.merge(head.clone(), joined, Abstract::widen)
+          } else {
+            joined
+          };
+          if next == head {
+            break;
+          }
+          head = next;
+        }
+        for _ in 0..NARROW_ROUNDS {
+          let after_body = self.run(body, self.check(&head, cv, false));
+          let next = self.merge(entry.clone(), after_body, Abstract::join);
+          if next == head {
+            break;
+          }
+          head = next;
+        }
+        self.recording = recording;
+        if recording {
+          self.run(body, self.check(&head, cv, false));
+        }
+        self.check(&head, cv, true)
+      }
+      statement => self.assignment(statement, state),
+    }
+  }
+
+  /// Analyses the program for inputs in the given ranges, returning the state at its end, or
+  /// `None` if it can't end without an error.
+  pub fn analyse(&mut self, prog: &Statement, inputs: &[Range]) -> Option<State> {
+    let state = inputs
+      .iter()
+      .map(|r| {
+        let value = Abstract::new(r.start as u128, r.end as u128, 1, 0).expect("Empty range");
+        (r.var.clone(), value)
+      })
+      .collect();
+    self.run(prog, Some(state))
+  }
+
+  /// A table of every assignment that can fail with what it can do, followed by a summary and
+  /// the final state. Also returns whether any assignment may fail.
+  pub fn report(&self, prog: &Statement, spans: &[Span], end: &Option<State>) -> (String, bool) {
+    let mut out = String::new();
+    let checked = prog
+      .nodes()
+      .into_iter()
+      .zip(spans)
+      .filter_map(|(s, span)| Some((s, span, failure(s)?)))
+      .collect::<Vec<_>>();
+    let width = checked
+      .iter()
+      .map(|(_, span, _)| span.to_string().len())
+      .max()
+      .unwrap_or(0)
+      .max(4);
+    writeln!(out, "{:<width$}  {:<18}  Statement", "Span", "Result").unwrap();
+    let (mut underflows, mut overflows) = (0, 0);
+    for (statement, span, error) in checked {
+      let verdict = self
+        .verdicts
+        .get(&(statement as *const Statement))
+        .copied()
+        .unwrap_or(Verdict::Unreachable);
+      let result = match verdict {
+        Verdict::Unreachable => "never runs".to_owned(),
+        Verdict::Safe => format!("can't {error}"),
+        Verdict::Possible => format!("may {error}"),
+        Verdict::Certain => format!("always {error}s"),
+      };
+      if matches!(verdict, Verdict::Possible | Verdict::Certain) {
+        match error {
+          "underflow" => underflows += 1,
+          _ => overflows += 1,
+        }
+      }
+      writeln!(
+        out,
+        "{:<width$}  {result:<18}  {statement}",
+        span.to_string()
+      )
+      .unwrap();
+    }
+    writeln!(out).unwrap();
+    let underflow = match underflows {
+      0 => "No subtraction can underflow, so the program doesn't need allow_underflow.".to_owned(),
+      n if self.config.underflow_allowed() => {
+        format!("{n} subtraction(s) may underflow, which allow_underflow turns into 0.")
+      }
+      n => format!("{n} subtraction(s) may underflow, the program may need allow_underflow."),
+    };
+    writeln!(out, "{underflow}").unwrap();
+    match overflows {
+      0 => writeln!(out, "No assignment can overflow.").unwrap(),
+      n => writeln!(out, "{n} assignment(s) may overflow.").unwrap(),
+    }
+    match end {
+      None => writeln!(out, "The program never finishes without an error.").unwrap(),
+      Some(state) => {
+        writeln!(out, "When it finishes:").unwrap();
+        let width = state.keys().map(String::len).max().unwrap_or(0);
+        for var in sorted_variables(state) {
+          let relation = if state[var].modulus == 0 { "=" } else { "in" };
+          writeln!(out, "  {var:<width$} {relation} {}", state[var]).unwrap();
+        }
+      }
+    }
+    let failing = overflows > 0 || (underflows > 0 && !self.config.underflow_allowed());
+    (out, failing)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    compile,
+    run::{run_counted, Counters, RuntimeError},
+    testing::{cases, random_config, MAX_STEPS},
+  };
+
+  const MAX: u128 = u64::MAX as u128;
+
+  fn range(var: &str, start: u64, end: u64) -> Range {
+    Range {
+      var: var.to_owned(),
+      start,
+      end,
+    }
+  }
+
+  /// The verdicts of the assignments that can fail, in the order of `Statement::nodes`.
+  fn verdicts(config: &Config, prog: &Statement, inputs: &[Range]) -> Vec<Verdict> {
+    let mut analyser = Analyser::new(config);
+    analyser.analyse(prog, inputs);
+    prog
+      .nodes()
+      .into_iter()
+      .filter(|s| failure(s).is_some())
+      .map(|s| {
+        let verdict = analyser.verdicts.get(&(s as *const Statement)).copied();
+        verdict.unwrap_or(Verdict::Unreachable)
+      })
+      .collect()
+  }
+
+  fn compiled(code: &str) -> (Config, Statement) {
+    let mut config = Config::default();
+    let prog = compile(&mut config, code).expect("Invalid test program");
+    (config, prog)
+  }
+
+  #[test]
+  fn parity_proves_loops_safe() {
+    let (config, prog) = compiled("#allow_constants_everywhere\nwhile x1 != 0 do x1 := x1 - 2 od");
+    assert_eq!(
+      verdicts(&config, &prog, &[range("x1", 10, 10)]),
+      [Verdict::Safe]
+    );
+    assert_eq!(
+      verdicts(&config, &prog, &[range("x1", 7, 7)]),
+      [Verdict::Possible]
+    );
+    assert_eq!(
+      verdicts(&config, &prog, &[range("x1", 0, 100)]),
+      [Verdict::Possible]
+    );
+    // Doubling any value gives an even one
+    let code = "#allow_constants_everywhere\n#extra_operators\nx1 := x2 * 2;\nwhile x1 != 0 do x1 := x1 - 2 od";
+    let (config, prog) = compiled(code);
+    let safe = [Verdict::Safe, Verdict::Safe];
+    assert_eq!(verdicts(&config, &prog, &[range("x2", 0, 1000)]), safe);
+  }
+
+  #[test]
+  fn bounds_near_the_maximum() {
+    let new = |lo, hi, modulus, rest| Abstract::new(lo, hi, modulus, rest);
+    let abstract_ = |lo, hi, modulus, rest| Abstract {
+      lo,
+      hi,
+      modulus,
+      rest,
+    };
+    // Bounds above 64 bits are cut off, on a value the congruence allows
+    assert_eq!(new(0, MAX + 5, 1, 0), Some(abstract_(0, u64::MAX, 1, 0)));
+    assert_eq!(
+      new(0, MAX + 5, 2, 0),
+      Some(abstract_(0, u64::MAX - 1, 2, 0))
+    );
+    assert_eq!(
+      new(MAX - 3, MAX + 10, 4, 1),
+      Some(Abstract::constant(u64::MAX - 2))
+    );
+    assert_eq!(new(MAX, MAX, 2, 0), None);
+    assert_eq!(new(MAX + 1, MAX + 1, 0, MAX + 1), None);
+    // A modulus that doesn't fit is dropped
+    assert_eq!(new(5, 10, MAX + 1, 7), Some(abstract_(5, 10, 1, 0)));
+
+    let widened = abstract_(4, 10, 2, 0).widen(abstract_(2, 12, 2, 0));
+    assert_eq!(widened, abstract_(0, u64::MAX - 1, 2, 0));
+    let widened = Abstract::constant(u64::MAX - 1).widen(Abstract::constant(u64::MAX));
+    assert_eq!(widened, abstract_(u64::MAX - 1, u64::MAX, 1, 0));
+    let top = Abstract::top();
+    assert_eq!(top.widen(top), top);
+  }
+
+  #[test]
+  fn failures_are_never_missed() {
+    for case in cases(43, 500, |rng| random_config(rng, true)) {
+      let config = Config {
+        max_steps: Some(MAX_STEPS),
+        ..case.config
+      };
+      let mut counters = Counters::default();
+      let ran = run_counted(&config, &case.prog, case.input.clone(), &mut counters);
+      let failed = matches!(
+        ran,
+        Err(RuntimeError::VariableOverflow(_) | RuntimeError::VariableUnderflow(_))
+      );
+      if !failed || config.underflow_allowed() {
+        continue;
+      }
+      let inputs = case
+        .input
+        .iter()
+        .map(|(var, &value)| range(var, value, value))
+        .collect::<Vec<_>>();
+      let mut analyser = Analyser::new(&config);
+      analyser.analyse(&case.prog, &inputs);
+      let statement = case.prog.nodes()[counters.failed_at.expect("No failing statement")];
+      let verdict = analyser.verdicts.get(&(statement as *const Statement));
+      assert!(
+        matches!(verdict, Some(Verdict::Possible | Verdict::Certain)),
+        "{statement} can fail for {:?} in\n{}",
+        case.input,
+        case.prog
+      );
+    }
+  }
+}
@@ -0,0 +1,666 @@
+use crate::{
+  config::{Config, OptionValue, OPTIONS},
+  godel::var_index,
+  output::json_string,
+  parser::{Statement, Value},
+  symbolizer::{Operator, Span},
+};
+use std::fmt::Write;
+
+// Programs as JSON, for tools that generate or inspect them. A file looks like
+//
+//   {
+//     "format": "whily-ast",
+//     "version": 1,
+//     "options": {"extra_operators": true},
+//     "requires": ["extra_operators"],
+//     "body": [
+//       {"type": "assign", "target": "x2", "value": "x1"},
+//       {"type": "operation", "target": "x0", "operator": "pair", "operands": ["x1", 3]},
+//       {"type": "while", "variable": "x1", "body": [...]}
+//     ]
+//   }
+//
+// Operands are variable names (strings) or constants (numbers). Operators are written as in the
+// source code (`+`, `-`, `*`, `pair`, `fst`, ...), and unary ones take a single operand.
+// "options" sets options like pragmas do, with `null` to turn off `max_steps`, and is optional.
+// "requires" lists the options the body needs and is ignored when reading: instead the body is
+// checked against the config, reporting every option it needs that isn't enabled.
+//
+// Every statement gets the span of its JSON object as its span, so runtime errors point into the
+// JSON file. Variables like `x01` are the same as `x1`, like in the source code. The reader is
+// recursive, so arrays and objects may only be nested MAX_DEPTH deep.
+
+const VERSION: u64 = 1;
+const MAX_DEPTH: usize = 256;
+
+/// A JSON value along with where it is in the file.
+struct Json {
+  value: JsonValue,
+  span: Span,
+}
+
+enum JsonValue {
+  Null,
+  Bool(bool),
+  // Only natural numbers are supported
+  Number(u64),
+  String(String),
+  Array(Vec<Json>),
+  Object(Vec<(String, Json)>),
+}
+
+#[derive(Debug)]
+pub struct AstError {
+  pub message: String,
+  pub span: Span,
+}
+
+impl std::fmt::Display for AstError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "Invalid AST on line {}:{}: {}",
+      self.span.line, self.span.col, self.message
+    )
+  }
+}
+
+impl std::error::Error for AstError {}
+
+fn error<T>(message: impl Into<String>, span: Span) -> Result<T, AstError> {
+  Err(AstError {
+    message: message.into(),
+    span,
+  })
+}
+
+struct Reader<'a> {
+  chars: std::iter::Peekable<std::str::Chars<'a>>,
+  line: usize,
+  col: usize,
+  // How many arrays and objects the reader is in
+  depth: usize,
+}
+
+impl Reader<'_> {
+  /// The position of the next character, as an empty span.
+  fn here(&self) -> Span {
+    Span {
+      line: self.line,
+      col: self.col,
+      end_line: self.line,
+      end_col: self.col,
+    }
+  }
+
+  fn next(&mut self) -> Option<char> {
+    let c = self.chars.next()?;
+    if c == '\n' {
+      self.line += 1;
+      self.col = 1;
+    } else {
+      self.col += 1;
+    }
+    Some(c)
+  }
+
+  fn skip_whitespace(&mut self) {
+    while self.chars.peek().is_some_and(|c| c.is_whitespace()) {
+      self.next();
+    }
+  }
+
+  fn expect(&mut self, expected: char) -> Result<(), AstError> {
+    self.skip_whitespace();
+    let here = self.here();
+    match self.next() {
+      Some(c) if c == expected => Ok(()),
+      Some(c) => error(format!("Expected '{expected}', found '{c}'."), here),
+      None => error(
+        format!("Expected '{expected}', found the end of the file."),
+        here,
+      ),
+    }
+  }
+
+  /// The code point of the four hex digits after `\u`.
+  fn hex_escape(&mut self, start: Span) -> Result<u32, AstError> {
+    let hex = (0..4).filter_map(|_| self.next()).collect::<String>();
+    match hex.len() == 4 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+      true => Ok(u32::from_str_radix(&hex, 16).unwrap()),
+      false => error(format!("Invalid escape '\\u{hex}'."), start),
+    }
+  }
+
+  /// The character of a `\u` escape, which takes two of them for a surrogate pair.
+  fn unicode_escape(&mut self, start: Span) -> Result<char, AstError> {
+    let mut code = self.hex_escape(start)?;
+    if (0xd800..0xdc00).contains(&code) {
+      if self.next() != Some('\\') || self.next() != Some('u') {
+        return error("Expected a low surrogate after a high one.", start);
+      }
+      let low = self.hex_escape(start)?;
+      if !(0xdc00..0xe000).contains(&low) {
+        return error("Expected a low surrogate after a high one.", start);
+      }
+      code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+    }
+    match char::from_u32(code) {
+      Some(c) => Ok(c),
+      None => error(format!("Invalid escape '\\u{code:04x}'."), start),
+    }
+  }
+
+  fn value(&mut self) -> Result<Json, AstError> {
+    self.skip_whitespace();
+    let start = self.here();
+    let nested = matches!(self.chars.peek(), Some('{' | '['));
+    if nested {
+      if self.depth == MAX_DEPTH {
+        return error(
+          format!("Arrays and objects can be nested at most {MAX_DEPTH} deep."),
+          start,
+        );
+      }
+      self.depth += 1;
+    }
+    let value = match self.chars.peek().copied() {
+      Some('{') => {
+        self.next();
+        let mut fields = vec![];
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&'}') {
+          self.next();
+        } else {
+          loop {
+            self.skip_whitespace();
+            let key_span = self.here();
+            let JsonValue::String(key) = self.value()?.value else {
+              return error("Expected a field name.", key_span);
+            };
+            self.expect(':')?;
+            fields.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.next() {
+              Some(',') => {}
+              Some('}') => break,
+              _ => return error("Expected ',' or '}' after a field.", self.here()),
+            }
+          }
+        }
+        JsonValue::Object(fields)
+      }
+      Some('[') => {
+        self.next();
+        let mut items = vec![];
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&']') {
+          self.next();
+        } else {
+          loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.next() {
+              Some(',') => {}
+              Some(']') => break,
+              _ => return error("Expected ',' or ']' after an item.", self.here()),
+            }
+          }
+        }
+        JsonValue::Array(items)
+      }
+      Some('"') => {
+        self.next();
+        let mut text = String::new();
+        loop {
+          match self.next() {
+            Some('"') => break,
+            Some('\\') => match self.next() {
+              Some('n') => text.push('\n'),
+              Some('t') => text.push('\t'),
+              Some('r') => text.push('\r'),
+              Some('b') => text.push('\u{8}'),
+              Some('f') => text.push('\u{c}'),
+              Some('u') => text.push(self.unicode_escape(start)?),
+              Some(c @ ('"' | '\\' | '/')) => text.push(c),
+              _ => return error("Invalid escape in a string.", start),
+            },
+            Some(c) => text.push(c),
+            None => return error("The string is never closed.", start),
+          }
+        }
+        JsonValue::String(text)
+      }
+      Some(c) if c.is_ascii_alphanumeric() || c == '-' => {
+        let mut word = String::new();
+        while let Some(&c) = self.chars.peek() {
+          if !(c.is_ascii_alphanumeric() || matches!(c, '-' | '+' | '.')) {
+            break;
+          }
+          word.push(c);
+          self.next();
+        }
+        match word.as_str() {
+          "null" => JsonValue::Null,
+          "true" => JsonValue::Bool(true),
+          "false" => JsonValue::Bool(false),
+          number => match number.parse() {
+            Ok(n) => JsonValue::Number(n),
+            Err(_) => {
+              return error(
+                format!("'{word}' is not a natural number below 2^64."),
+                start,
+              )
+            }
+          },
+        }
+      }
+      Some(c) => return error(format!("Unexpected '{c}'."), start),
+      None => return error("Expected a value, found the end of the file.", start),
+    };
+    if nested {
+      self.depth -= 1;
+    }
+    let end = self.here();
+    let span = Span {
+      end_line: end.line,
+      end_col: end.col.saturating_sub(1).max(1),
+      ..start
+    };
+    Ok(Json { value, span })
+  }
+}
+
+fn parse_json(text: &str) -> Result<Json, AstError> {
+  let mut reader = Reader {
+    chars: text.chars().peekable(),
+    line: 1,
+    col: 1,
+    depth: 0,
+  };
+  let json = reader.value()?;
+  reader.skip_whitespace();
+  if reader.chars.peek().is_some() {
+    return error("Expected the end of the file.", reader.here());
+  }
+  Ok(json)
+}
+
+impl Json {
+  fn kind(&self) -> &'static str {
+    match self.value {
+      JsonValue::Null => "null",
+      JsonValue::Bool(_) => "a boolean",
+      JsonValue::Number(_) => "a number",
+      JsonValue::String(_) => "a string",
+      JsonValue::Array(_) => "an array",
+      JsonValue::Object(_) => "an object",
+    }
+  }
+
+  fn object(&self, what: &str) -> Result<&[(String, Json)], AstError> {
+    match &self.value {
+      JsonValue::Object(fields) => Ok(fields),
+      _ => error(
+        format!("Expected {what} object, found {}.", self.kind()),
+        self.span,
+      ),
+    }
+  }
+
+  fn array(&self, what: &str) -> Result<&[Json], AstError> {
+    match &self.value {
+      JsonValue::Array(items) => Ok(items),
+      _ => error(
+        format!("Expected {what}, found {}.", self.kind()),
+        self.span,
+      ),
+    }
+  }
+
+  fn string(&self, what: &str) -> Result<&str, AstError> {
+    match &self.value {
+      JsonValue::String(text) => Ok(text),
+      _ => error(
+        format!("Expected {what}, found {}.", self.kind()),
+        self.span,
+      ),
+    }
+  }
+
+  /// The field `name` of an object.
+  fn field<'a>(&self, fields: &'a [(String, Json)], name: &str) -> Result<&'a Json, AstError> {
+    match fields.iter().find(|(key, _)| key == name) {
+      Some((_, value)) => Ok(value),
+      None => error(format!("Missing the field \"{name}\"."), self.span),
+    }
+  }
+}
+
+fn variable(json: &Json) -> Result<String, AstError> {
+  let name = json.string("a variable name")?;
+  // Like in the source code, x followed by a digit is always an xi variable
+  if name.starts_with('x') && name[1..].starts_with(|c: char| c.is_ascii_digit()) {
+    return match name[1..].chars().all(|c| c.is_ascii_digit()) {
+      true => match var_index(name) {
+        Some(i) => Ok(format!("x{i}")),
+        None => error(
+          format!("The index of '{name}' can be at most {}.", u64::MAX),
+          json.span,
+        ),
+      },
+      false => error(format!("'{name}' is not a valid variable name."), json.span),
+    };
+  }
+  let mut chars = name.chars();
+  let valid = chars
+    .next()
+    .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+    && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+  if !valid || ["while", "do", "od"].contains(&name) {
+    return error(format!("'{name}' is not a valid variable name."), json.span);
+  }
+  Ok(name.to_owned())
+}
+
+fn value(json: &Json) -> Result<Value, AstError> {
+  match &json.value {
+    JsonValue::Number(n) => Ok(Value::Constant(*n)),
+    JsonValue::String(_) => Ok(Value::Variable(variable(json)?)),
+    _ => error(
+      format!(
+        "Expected a variable name or a number, found {}.",
+        json.kind()
+      ),
+      json.span,
+    ),
+  }
+}
+
+fn operator(json: &Json) -> Result<Operator, AstError> {
+  let name = json.string("an operator")?;
+  let operators = [
+    Operator::Add,
+    Operator::Subtract,
+    Operator::Multiply,
+    Operator::Pair,
+    Operator::Fst,
+    Operator::Snd,
+    Operator::Cons,
+    Operator::Head,
+    Operator::Tail,
+  ];
+  match operators.into_iter().find(|o| o.to_string() == name) {
+    Some(operator) => Ok(operator),
+    None => error(format!("Unknown operator '{name}'."), json.span),
+  }
+}
+
+/// Reads a list of statements, adding their spans in the order of `Statement::nodes`.
+fn statements(json: &Json, spans: &mut Vec<Span>) -> Result<Statement, AstError> {
+  let items = json.array("a list of statements")?;
+  let mut body = vec![];
+  for item in items {
+    body.push(statement(item, spans)?);
+  }
+  match Statement::from_statements(body) {
+    Some(body) => Ok(body),
+    None => error("Expected at least one statement.", json.span),
+  }
+}
+
+fn statement(json: &Json, spans: &mut Vec<Span>) -> Result<Statement, AstError> {
+  let fields = json.object("a statement")?;
+  let target = || variable(json.field(fields, "target")?);
+  spans.push(json.span);
+  match json.field(fields, "type")?.string("a statement type")? {
+    "assign" => Ok(Statement::DeclareConst(
+      target()?,
+      value(json.field(fields, "value")?)?,
+    )),
+    "operation" => {
+      let operator = operator(json.field(fields, "operator")?)?;
+      let operands = json.field(fields, "operands")?;
+      let expected = if operator.is_unary() { 1 } else { 2 };
+      let values = operands.array("a list of operands")?;
+      if values.len() != expected {
+        return error(
+          format!(
+            "'{operator}' takes {expected} operand(s), but {} were given.",
+            values.len()
+          ),
+          operands.span,
+        );
+      }
+      let v1 = value(&values[0])?;
+      let v2 = values.get(1).map_or(Ok(Value::Constant(0)), value)?;
+      Ok(Statement::DeclareOperation(target()?, v1, operator, v2))
+    }
+    "while" => {
+      let cv = variable(json.field(fields, "variable")?)?;
+      let body = statements(json.field(fields, "body")?, spans)?;
+      Ok(Statement::While(cv, Box::new(body)))
+    }
+    other => error(
+      format!("Unknown statement type '{other}', expected assign, operation or while."),
+      json.span,
+    ),
+  }
+}
+
+/// Applies the "options" of an AST to the config.
+fn apply_options(json: &Json, config: &mut Config) -> Result<(), AstError> {
+  for (name, value) in json.object("an options")? {
+    let result = match &value.value {
+      JsonValue::Null => config.set(&format!("no_{name}"), None),
+      JsonValue::Bool(b) => config.set(name, Some(&b.to_string())),
+      JsonValue::Number(n) => config.set(name, Some(&n.to_string())),
+      JsonValue::String(text) => config.set(name, Some(text)),
+      _ => {
+        return error(
+          format!("Invalid value for the option '{name}'."),
+          value.span,
+        )
+      }
+    };
+    if let Err(e) = result {
+      return error(e.to_string(), value.span);
+    }
+  }
+  Ok(())
+}
+
+/// The options the program needs that aren't enabled in the config.
+pub fn missing_options(config: &Config, prog: &Statement) -> Vec<&'static str> {
+  prog
+    .required_options()
+    .into_iter()
+    .filter(|&option| match option {
+      "allow_constants_everywhere" => !config.constants_everywhere(),
+      "allow_named_vars" => !config.allow_named_vars,
+      "extra_operators" => !config.extra_operators,
+      _ => false,
+    })
+    .collect()
+}
+
+/// Reads a program from JSON, applying its options to the config. Also returns the spans of the
+/// statements, see `statement_spans`.
+pub fn from_json(config: &mut Config, text: &str) -> Result<(Statement, Vec<Span>), AstError> {
+  let json = parse_json(text)?;
+  let fields = json.object("a program")?;
+  if let Some((_, format)) = fields.iter().find(|(key, _)| key == "format") {
+    if format.string("the format")? != "whily-ast" {
+      return error("The format should be \"whily-ast\".", format.span);
+    }
+  }
+  if let Some((_, version)) = fields.iter().find(|(key, _)| key == "version") {
+    if !matches!(version.value, JsonValue::Number(VERSION)) {
+      return error(
+        format!("Only version {VERSION} is supported."),
+        version.span,
+      );
+    }
+  }
+  if let Some((_, options)) = fields.iter().find(|(key, _)| key == "options") {
+    apply_options(options, config)?;
+  }
+  let mut spans = vec![];
+  let prog = statements(json.field(fields, "body")?, &mut spans)?;
+  let missing = missing_options(config, &prog);
+  if !missing.is_empty() {
+    return error(
+      format!(
+        "The program needs {}, which can be enabled in \"options\" or with --set.",
+        missing.join(", ")
+      ),
+      json.span,
+    );
+  }
+  Ok((prog, spans))
+}
+
+fn write_value(out: &mut String, value: &Value) {
+  match value {
+    Value::Variable(var) => out.push_str(&json_string(var)),
+    Value::Constant(c) => write!(out, "{c}").unwrap(),
+  }
+}
+
+fn write_statements(out: &mut String, prog: &Statement, depth: usize) {
+  let statements = match prog {
+    Statement::S(..) => prog.clone().into_statements(),
+    s => vec![s.clone()],
+  };
+  let indent = "  ".repeat(depth + 1);
+  out.push('[');
+  for (i, statement) in statements.iter().enumerate() {
+    out.push_str(if i == 0 { "\n" } else { ",\n" });
+    out.push_str(&indent);
+    match statement {
+      Statement::DeclareConst(v0, v) => {
+        write!(
+          out,
+          "{{\"type\": \"assign\", \"target\": {}, \"value\": ",
+          json_string(v0)
+        )
+        .unwrap();
+        write_value(out, v);
+        out.push('}');
+      }
+      Statement::DeclareOperation(v0, v1, operator, v2) => {
+        write!(
+          out,
+          "{{\"type\": \"operation\", \"target\": {}, \"operator\": {}, \"operands\": [",
+          json_string(v0),
+          json_string(&operator.to_string())
+        )
+        .unwrap();
+        write_value(out, v1);
+        if !operator.is_unary() {
+          out.push_str(", ");
+          write_value(out, v2);
+        }
+        out.push_str("]}");
+      }
+      Statement::While(cv, body) => {
+        write!(
+          out,
+          "{{\"type\": \"while\", \"variable\": {}, \"body\": ",
+          json_string(cv)
+        )
+        .unwrap();
+        write_statements(out, body, depth + 1);
+        out.push('}');
+      }
+      Statement::S(..) => unreachable!("Sequences are flattened"),
+    }
+  }
+  write!(out, "\n{}]", "  ".repeat(depth)).unwrap();
+}
+
+/// The program as JSON, with the options of the config that differ from the defaults.
+pub fn to_json(config: &Config, prog: &Statement) -> String {
+  let default = Config::default();
+  let options = OPTIONS
+    .iter()
+    .filter(|option| option.get(config) != option.get(&default))
+    .map(|option| {
+      let value = match (option.value, option.get(config).as_str()) {
+        (OptionValue::Dialect(_), value) => json_string(value),
+        (_, "none") => "null".to_owned(),
+        (_, value) => value.to_owned(),
+      };
+      format!("{}: {value}", json_string(option.name))
+    })
+    .collect::<Vec<_>>();
+  let requires = prog
+    .required_options()
+    .into_iter()
+    .map(json_string)
+    .collect::<Vec<_>>();
+  let mut out = String::new();
+  writeln!(out, "{{").unwrap();
+  writeln!(out, "  \"format\": \"whily-ast\",").unwrap();
+  writeln!(out, "  \"version\": {VERSION},").unwrap();
+  writeln!(out, "  \"options\": {{{}}},", options.join(", ")).unwrap();
+  writeln!(out, "  \"requires\": [{}],", requires.join(", ")).unwrap();
+  out.push_str("  \"body\": ");
+  write_statements(&mut out, prog, 1);
+  out.push_str("\n}\n");
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::compile;
+
+  fn read(body: &str) -> Result<Statement, AstError> {
+    let text = format!("{{\"format\": \"whily-ast\", \"version\": 1, \"body\": {body}}}");
+    from_json(&mut Config::default(), &text).map(|(prog, _)| prog)
+  }
+
+  #[test]
+  fn round_trip() {
+    let code = "#allow_named_vars\n#extra_operators\n#allow_constants_everywhere\n\
+      a := 3; b := pair(a, 2); x0 := fst(b); while a != 0 do x1 := x1 * 2; a := a - 1 od";
+    let mut config = Config::default();
+    let prog = compile(&mut config, code).unwrap();
+    let json = to_json(&config, &prog);
+    let mut read_config = Config::default();
+    let (read, spans) = from_json(&mut read_config, &json).unwrap();
+    assert_eq!(read.to_string(), prog.to_string());
+    assert_eq!(spans.len(), prog.nodes().len());
+    assert_eq!(to_json(&read_config, &read), json);
+  }
+
+  #[test]
+  fn deep_nesting_is_an_error() {
+    let text = "[".repeat(200_000);
+    let e = from_json(&mut Config::default(), &text).unwrap_err();
+    assert!(e.message.contains("nested"), "{e}");
+    let nested = "[".repeat(MAX_DEPTH) + &"]".repeat(MAX_DEPTH);
+    assert!(parse_json(&nested).is_ok());
+  }
+
+  #[test]
+  fn unicode_escapes() {
+    let json = parse_json("\"\\ud83d\\ude00 \\u00e9\\b\\f\"").unwrap();
+    assert!(matches!(json.value, JsonValue::String(s) if s == "\u{1f600} \u{e9}\u{8}\u{c}"));
+    assert!(parse_json("\"\\ud83d\"").is_err());
+    assert!(parse_json("\"\\ude00\"").is_err());
+    assert!(parse_json("\"\\u+123\"").is_err());
+  }
+
+  #[test]
+  fn xi_variables_are_normalised() {
+    let prog = read(r#"[{"type": "assign", "target": "x01", "value": 1}]"#).unwrap();
+    assert_eq!(prog.to_string(), "x1 := 1");
+    for name in ["x1a", "x18446744073709551616"] {
+      let body = format!(r#"[{{"type": "assign", "target": "{name}", "value": 1}}]"#);
+      assert!(read(&body).is_err(), "{name} was accepted");
+    }
+  }
+}
@@ -0,0 +1,326 @@
+use crate::{parser::Statement, profile::Profile};
+use std::fmt::Write;
+
+// The control-flow graph of a program, for drawing it. Consecutive assignments form one basic
+// block, every loop gets a decision node testing its variable, and the last blocks of a loop body
+// lead back to that node. The graph can be printed for Graphviz (dot) or Mermaid.
+//
+// With a profile of a run, blocks are annotated with how often they ran and the edges out of a
+// loop head with how often they were taken. A loop is left once for every time it is started
+// (unless the run stopped with an error inside it), and taken once for every time its body
+// started.
+
+enum Node<'a> {
+  Start,
+  End,
+  Block(Vec<&'a Statement>),
+  Head(&'a Statement),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Branch {
+  Always,
+  NonZero,
+  Zero,
+}
+
+struct Edge {
+  from: usize,
+  to: usize,
+  branch: Branch,
+  // Whether the edge goes back to the head of a loop
+  back: bool,
+}
+
+pub struct Graph<'a> {
+  nodes: Vec<Node<'a>>,
+  edges: Vec<Edge>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+  Dot,
+  Mermaid,
+}
+
+/// The statements of a sequence, with nested sequences flattened.
+fn flatten<'a>(statement: &'a Statement, out: &mut Vec<&'a Statement>) {
+  match statement {
+    Statement::S(left, right) => {
+      flatten(left, out);
+      flatten(right, out);
+    }
+    s => out.push(s),
+  }
+}
+
+/// The first statement that runs in the body of a loop.
+fn first(statement: &Statement) -> &Statement {
+  match statement {
+    Statement::S(left, _) => first(left),
+    s => s,
+  }
+}
+
+impl<'a> Graph<'a> {
+  pub fn new(prog: &'a Statement) -> Self {
+    let mut graph = Self {
+      nodes: vec![Node::Start, Node::End],
+      edges: vec![],
+    };
+    let out = graph.sequence(prog, vec![(0, Branch::Always)]);
+    graph.connect(out, 1, false);
+    graph
+  }
+
+  fn add(&mut self, node: Node<'a>) -> usize {
+    self.nodes.push(node);
+    self.nodes.len() - 1
+  }
+
+  fn connect(&mut self, from: Vec<(usize, Branch)>, to: usize, back: bool) {
+    for (from, branch) in from {
+      self.edges.push(Edge {
+        from,
+        to,
+        branch,
+        back,
+      });
+    }
+  }
+
+  /// Adds the nodes of a sequence of statements, which the `incoming` edges lead into. Returns
+  /// the edges that leave it.
+  fn sequence(
+    &mut self,
+    statement: &'a Statement,
+    mut incoming: Vec<(usize, Branch)>,
+  ) -> Vec<(usize, Branch)> {
+    let mut statements = vec![];
+    flatten(statement, &mut statements);
+    let mut block = vec![];
+    for statement in statements {
+      if let Statement::While(_, body) = statement {
+        if !block.is_empty() {
+          let node = self.add(Node::Block(std::mem::take(&mut block)));
+          self.connect(incoming, node, false);
+          incoming = vec![(node, Branch::Always)];
+        }
+        let head = self.add(Node::Head(statement));
+        self.connect(incoming, head, false);
+        let body_out = self.sequence(body, vec![(head, Branch::NonZero)]);
+        self.connect(body_out, head, true);
+        incoming = vec![(head, Branch::Zero)];
+      } else {
+        block.push(statement);
+      }
+    }
+    if !block.is_empty() {
+      let node = self.add(Node::Block(block));
+      self.connect(incoming, node, false);
+      incoming = vec![(node, Branch::Always)];
+    }
+    incoming
+  }
+
+  /// The lines of text in a node, with how often it ran if there is a profile.
+  fn label(&self, node: usize, profile: Option<&Profile>) -> Vec<String> {
+    let mut lines = match &self.nodes[node] {
+      Node::Start => vec!["start".to_owned()],
+      Node::End => vec!["end".to_owned()],
+      Node::Block(statements) => statements.iter().map(|s| s.to_string()).collect(),
+      Node::Head(Statement::While(cv, _)) => vec![format!("{cv} != 0")],
+      Node::Head(_) => unreachable!("Not a loop"),
+    };
+    if let (Some(profile), Node::Block(statements)) = (profile, &self.nodes[node]) {
+      lines.push(format!("({}x)", profile.executions(statements[0])));
+    }
+    lines
+  }
+
+  /// The label of an edge, with how often it was taken if there is a profile.
+  fn edge_label(&self, edge: &Edge, profile: Option<&Profile>) -> Option<String> {
+    let branch = match edge.branch {
+      Branch::Always => return None,
+      Branch::NonZero => "!= 0",
+      Branch::Zero => "= 0",
+    };
+    let (Some(profile), Node::Head(head @ Statement::While(_, body))) =
+      (profile, &self.nodes[edge.from])
+    else {
+      return Some(branch.to_owned());
+    };
+    let count = match edge.branch {
+      Branch::NonZero => profile.executions(first(body)),
+      _ => profile.executions(head),
+    };
+    Some(format!("{branch} ({count}x)"))
+  }
+
+  pub fn render(&self, format: GraphFormat, profile: Option<&Profile>) -> String {
+    let mut out = String::new();
+    match format {
+      GraphFormat::Dot => {
+        writeln!(out, "digraph cfg {{").unwrap();
+        writeln!(out, "  node [shape=box, fontname=\"monospace\"];").unwrap();
+        for (id, node) in self.nodes.iter().enumerate() {
+          let shape = match node {
+            Node::Start | Node::End => ", shape=oval",
+            Node::Head(_) => ", shape=diamond",
+            Node::Block(_) => "",
+          };
+          let label = self
+            .label(id, profile)
+            .iter()
+            .map(|line| line.replace('\\', "\\\\").replace('"', "\\\"") + "\\l")
+            .collect::<String>();
+          writeln!(out, "  n{id} [label=\"{label}\"{shape}];").unwrap();
+        }
+        for edge in &self.edges {
+          let mut attributes = vec![];
+          if let Some(label) = self.edge_label(edge, profile) {
+            attributes.push(format!("label=\"{label}\""));
+          }
+          if edge.back {
+            attributes.push("style=dashed".to_owned());
+          }
+          let attributes = match attributes.is_empty() {
+            true => String::new(),
+            false => format!(" [{}]", attributes.join(", ")),
+          };
+          writeln!(out, "  n{} -> n{}{attributes};", edge.from, edge.to).unwrap();
+        }
+        writeln!(out, "}}").unwrap();
+      }
+      GraphFormat::Mermaid => {
+        writeln!(out, "flowchart TD").unwrap();
+        for (id, node) in self.nodes.iter().enumerate() {
+          let label = self
+            .label(id, profile)
+            .iter()
+            .map(|line| line.replace('"', "#quot;"))
+            .collect::<Vec<_>>()
+            .join("<br/>");
+          let node = match node {
+            Node::Start | Node::End => format!("([\"{label}\"])"),
+            Node::Head(_) => format!("{{\"{label}\"}}"),
+            Node::Block(_) => format!("[\"{label}\"]"),
+          };
+          writeln!(out, "  n{id}{node}").unwrap();
+        }
+        for edge in &self.edges {
+          let arrow = if edge.back { "-.->" } else { "-->" };
+          let label = self
+            .edge_label(edge, profile)
+            .map_or(String::new(), |label| format!("|\"{label}\"|"));
+          writeln!(out, "  n{} {arrow}{label} n{}", edge.from, edge.to).unwrap();
+        }
+      }
+    }
+    out
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    compile,
+    config::Config,
+    run::{run_counted, Counters},
+  };
+  use std::collections::HashMap;
+
+  const NESTED: &str = "x0 := x1 + x4;
+while x1 != 0 do
+  x2 := x0 + x4;
+  while x2 != 0 do
+    x2 := x2 - x3
+  od;
+  x1 := x1 - x3
+od;
+x0 := x2 + x4";
+
+  #[test]
+  fn nested_loops_as_dot() {
+    let prog = compile(&mut Config::default(), NESTED).unwrap();
+    let expected = r#"digraph cfg {
+  node [shape=box, fontname="monospace"];
+  n0 [label="start\l", shape=oval];
+  n1 [label="end\l", shape=oval];
+  n2 [label="x0 := x1 + x4\l"];
+  n3 [label="x1 != 0\l", shape=diamond];
+  n4 [label="x2 := x0 + x4\l"];
+  n5 [label="x2 != 0\l", shape=diamond];
+  n6 [label="x2 := x2 - x3\l"];
+  n7 [label="x1 := x1 - x3\l"];
+  n8 [label="x0 := x2 + x4\l"];
+  n0 -> n2;
+  n2 -> n3;
+  n3 -> n4 [label="!= 0"];
+  n4 -> n5;
+  n5 -> n6 [label="!= 0"];
+  n6 -> n5 [style=dashed];
+  n5 -> n7 [label="= 0"];
+  n7 -> n3 [style=dashed];
+  n3 -> n8 [label="= 0"];
+  n8 -> n1;
+}
+"#;
+    assert_eq!(Graph::new(&prog).render(GraphFormat::Dot, None), expected);
+  }
+
+  #[test]
+  fn nested_loops_as_mermaid() {
+    let prog = compile(&mut Config::default(), NESTED).unwrap();
+    let expected = r#"flowchart TD
+  n0(["start"])
+  n1(["end"])
+  n2["x0 := x1 + x4"]
+  n3{"x1 != 0"}
+  n4["x2 := x0 + x4"]
+  n5{"x2 != 0"}
+  n6["x2 := x2 - x3"]
+  n7["x1 := x1 - x3"]
+  n8["x0 := x2 + x4"]
+  n0 --> n2
+  n2 --> n3
+  n3 -->|"!= 0"| n4
+  n4 --> n5
+  n5 -->|"!= 0"| n6
+  n6 -.-> n5
+  n5 -->|"= 0"| n7
+  n7 -.-> n3
+  n3 -->|"= 0"| n8
+  n8 --> n1
+"#;
+    assert_eq!(
+      Graph::new(&prog).render(GraphFormat::Mermaid, None),
+      expected
+    );
+  }
+
+  #[test]
+  fn nested_loops_with_counts() {
+    let config = Config::default();
+    let prog = compile(&mut config.clone(), NESTED).unwrap();
+    let mut counters = Counters {
+      profile: Some(Profile::new(&prog)),
+      ..Default::default()
+    };
+    let input = HashMap::from([("x1".into(), 2), ("x3".into(), 1), ("x4".into(), 0)]);
+    run_counted(&config, &prog, input, &mut counters).unwrap();
+    let dot = Graph::new(&prog).render(GraphFormat::Dot, counters.profile.as_ref());
+    // The outer body runs twice, the inner one twice per outer iteration
+    for line in [
+      r#"n4 [label="x2 := x0 + x4\l(2x)\l"];"#,
+      r#"n6 [label="x2 := x2 - x3\l(4x)\l"];"#,
+      r#"n3 -> n4 [label="!= 0 (2x)"];"#,
+      r#"n5 -> n6 [label="!= 0 (4x)"];"#,
+      r#"n5 -> n7 [label="= 0 (2x)"];"#,
+      r#"n3 -> n8 [label="= 0 (1x)"];"#,
+    ] {
+      assert!(dot.contains(line), "Missing {line} in\n{dot}");
+    }
+  }
+}
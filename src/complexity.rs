@@ -0,0 +1,298 @@
+use crate::{
+  equiv::Range,
+  parser::Statement,
+  run::{run_counted, Counters},
+  Config,
+};
+use std::{collections::HashMap, fmt::Write};
+
+// Measures how the amount of steps (see `run`) and the size of the largest value grow with one
+// of the inputs, by running the program for a range of values of that input and fitting the
+// measurements to a set of growth classes.
+//
+// Every class is fitted as a * g(n) + b with least squares, weighted to minimise the relative
+// errors, except for the exponential class, which is fitted as e^(c * n + d) in log space. The
+// fits are compared by their mean squared relative error, and a class that grows faster only
+// wins when it halves the error of the best slower growing class that doesn't already fit well,
+// so that noise doesn't turn n into n log n.
+
+// About a 1% difference between the fit and the measurements
+const GOOD_FIT: f64 = 1e-4;
+
+pub struct Measurement {
+  pub n: u64,
+  pub result: Result<(u64, u64), String>,
+}
+
+#[derive(Clone, Copy)]
+enum Class {
+  Constant,
+  Log,
+  Linear,
+  NLogN,
+  Polynomial(i32),
+  Exponential,
+}
+
+impl Class {
+  fn all() -> Vec<Class> {
+    let mut classes = vec![Class::Constant, Class::Log, Class::Linear, Class::NLogN];
+    classes.extend((2..=6).map(Class::Polynomial));
+    classes.push(Class::Exponential);
+    classes
+  }
+
+  fn g(self, n: f64) -> f64 {
+    match self {
+      Class::Constant => 0.0,
+      Class::Log => n.ln(),
+      Class::Linear => n,
+      Class::NLogN => n * n.ln(),
+      Class::Polynomial(k) => n.powi(k),
+      Class::Exponential => unreachable!("Exponential growth isn't fitted linearly"),
+    }
+  }
+}
+
+impl std::fmt::Display for Class {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Class::Constant => write!(f, "constant"),
+      Class::Log => write!(f, "log n"),
+      Class::Linear => write!(f, "n"),
+      Class::NLogN => write!(f, "n log n"),
+      Class::Polynomial(k) => write!(f, "n^{k}"),
+      Class::Exponential => write!(f, "exponential"),
+    }
+  }
+}
+
+struct Fit {
+  class: Class,
+  formula: String,
+  error: f64,
+}
+
+/// Least squares fit of y = a * x + b, with every point counting `weight` times.
+fn linear_regression(points: &[(f64, f64)], weight: impl Fn(f64) -> f64) -> (f64, f64) {
+  let weights = points.iter().map(|p| weight(p.1)).collect::<Vec<_>>();
+  let total = weights.iter().sum::<f64>();
+  let mean = |f: &dyn Fn(&(f64, f64)) -> f64| {
+    points
+      .iter()
+      .zip(&weights)
+      .map(|(p, w)| w * f(p))
+      .sum::<f64>()
+      / total
+  };
+  let mean_x = mean(&|p| p.0);
+  let mean_y = mean(&|p| p.1);
+  let covariance = mean(&|p| (p.0 - mean_x) * (p.1 - mean_y));
+  let variance = mean(&|p| (p.0 - mean_x).powi(2));
+  let a = if variance == 0.0 {
+    0.0
+  } else {
+    covariance / variance
+  };
+  (a, mean_y - a * mean_x)
+}
+
+fn relative_error(points: &[(f64, f64)], predict: impl Fn(f64) -> f64) -> f64 {
+  let total = points
+    .iter()
+    .map(|&(n, y)| ((predict(n) - y) / y.max(1.0)).powi(2))
+    .sum::<f64>();
+  total / points.len() as f64
+}
+
+fn fit_class(class: Class, points: &[(f64, f64)]) -> Option<Fit> {
+  if let Class::Exponential = class {
+    let logs = points
+      .iter()
+      .map(|&(n, y)| (n, y.max(1.0).ln()))
+      .collect::<Vec<_>>();
+    let (c, d) = linear_regression(&logs, |_| 1.0);
+    if c <= 0.0 {
+      return None;
+    }
+    return Some(Fit {
+      class,
+      formula: format!("{:.3} * {:.4}^n", d.exp(), c.exp()),
+      error: relative_error(points, |n| (c * n + d).exp()),
+    });
+  }
+  let transformed = points
+    .iter()
+    .map(|&(n, y)| (class.g(n), y))
+    .collect::<Vec<_>>();
+  // Weighted so that the relative errors are minimised
+  let (a, b) = linear_regression(&transformed, |y| y.max(1.0).powi(-2));
+  if a < 0.0 {
+    return None;
+  }
+  let formula = match class {
+    Class::Constant => format!("{b:.3}"),
+    _ => format!("{a:.3} * {class} + {b:.3}"),
+  };
+  Some(Fit {
+    class,
+    formula,
+    error: relative_error(points, |n| a * class.g(n) + b),
+  })
+}
+
+fn best_fit(points: &[(f64, f64)]) -> Option<Fit> {
+  let mut best: Option<Fit> = None;
+  for fit in Class::all()
+    .into_iter()
+    .filter_map(|c| fit_class(c, points))
+  {
+    if best
+      .as_ref()
+      .is_none_or(|b| b.error > GOOD_FIT && fit.error < b.error / 2.0)
+    {
+      best = Some(fit);
+    }
+  }
+  best
+}
+
+/// Picks up to `samples` values in the range, spread evenly on a logarithmic scale.
+pub fn sample_values(range: &Range, samples: u64) -> Vec<u64> {
+  let (start, end) = (range.start, range.end);
+  if end - start < samples {
+    return (start..=end).collect();
+  }
+  let low = (start.max(1) as f64).ln();
+  let high = (end as f64).ln();
+  let mut values = (0..samples)
+    .map(|i| {
+      let value = (low + (high - low) * i as f64 / (samples - 1) as f64)
+        .exp()
+        .round();
+      (value as u64).clamp(start, end)
+    })
+    .collect::<Vec<_>>();
+  values.insert(0, start);
+  values.dedup();
+  values
+}
+
+pub fn measure(
+  config: &Config,
+  prog: &Statement,
+  var: &str,
+  values: &[u64],
+  fixed: &[(String, u64)],
+) -> Vec<Measurement> {
+  values
+    .iter()
+    .map(|&n| {
+      let mut input = fixed.iter().cloned().collect::<HashMap<_, _>>();
+      input.insert(var.to_owned(), n);
+      let mut counters = Counters::default();
+      let result = match run_counted(config, prog, input, &mut counters) {
+        Ok(_) => Ok((counters.steps, counters.max_value)),
+        Err(e) => Err(format!("{e:?}")),
+      };
+      Measurement { n, result }
+    })
+    .collect()
+}
+
+fn bits(value: u64) -> u32 {
+  64 - value.leading_zeros()
+}
+
+pub fn report(var: &str, measurements: &[Measurement]) -> String {
+  let mut out = String::new();
+  writeln!(
+    out,
+    "{var:>12} {:>16} {:>22} {:>5}",
+    "steps", "max value", "bits"
+  )
+  .unwrap();
+  for m in measurements {
+    match &m.result {
+      Ok((steps, max_value)) => writeln!(
+        out,
+        "{:>12} {steps:>16} {max_value:>22} {:>5}",
+        m.n,
+        bits(*max_value)
+      ),
+      Err(e) => writeln!(out, "{:>12} {e}", m.n),
+    }
+    .unwrap();
+  }
+
+  let successes = measurements
+    .iter()
+    .filter(|m| m.n > 0)
+    .filter_map(|m| m.result.as_ref().ok().map(|r| (m.n as f64, r)))
+    .collect::<Vec<_>>();
+  if successes.len() < 3 {
+    writeln!(
+      out,
+      "\nNot enough successful runs with {var} > 0 to fit a growth class."
+    )
+    .unwrap();
+    return out;
+  }
+  let steps = successes
+    .iter()
+    .map(|&(n, &(steps, _))| (n, steps as f64))
+    .collect::<Vec<_>>();
+  let sizes = successes
+    .iter()
+    .map(|&(n, &(_, max_value))| (n, bits(max_value) as f64))
+    .collect::<Vec<_>>();
+  writeln!(out).unwrap();
+  for (name, points) in [("Steps", steps), ("Bits of the max value", sizes)] {
+    match best_fit(&points) {
+      Some(fit) => writeln!(
+        out,
+        "{name} grow like {} (~ {}, mean squared relative error {:.2e})",
+        fit.class, fit.formula, fit.error
+      ),
+      None => writeln!(out, "{name} don't match any growth class."),
+    }
+    .unwrap();
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::compile;
+
+  /// The growth class of the steps of the code when varying x1, with x2 = 1 and x4 = 0.
+  fn steps_class(code: &str) -> String {
+    let mut config = Config::default();
+    let prog = compile(&mut config, code).unwrap();
+    let range = Range {
+      var: "x1".to_owned(),
+      start: 1,
+      end: 200,
+    };
+    let fixed = [("x2".to_owned(), 1), ("x4".to_owned(), 0)];
+    let measurements = measure(&config, &prog, "x1", &sample_values(&range, 12), &fixed);
+    let points = measurements
+      .iter()
+      .map(|m| (m.n as f64, m.result.as_ref().unwrap().0 as f64))
+      .collect::<Vec<_>>();
+    best_fit(&points).unwrap().class.to_string()
+  }
+
+  #[test]
+  fn linear_and_quadratic_programs() {
+    let linear = "while x1 != 0 do x1 := x1 - x2 od";
+    assert_eq!(steps_class(linear), "n");
+    let quadratic = "while x1 != 0 do
+  x3 := x1 + x4;
+  while x3 != 0 do x3 := x3 - x2 od;
+  x1 := x1 - x2
+od";
+    assert_eq!(steps_class(quadratic), "n^2");
+  }
+}
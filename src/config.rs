@@ -1,52 +1,630 @@
-use clap::{arg, ArgMatches, Command};
+use crate::error::OptionError;
+use clap::{arg, Arg, ArgAction, ArgMatches, Command};
 use clio::Input;
+use std::{
+  fmt::Write,
+  path::{Path, PathBuf},
+  time::Duration,
+};
+
+fn file_arg(help: &'static str) -> Arg {
+  Arg::new("FILE")
+    .help(help)
+    .required(true)
+    .value_parser(clap::value_parser!(Input))
+}
+
+/// Parses a natural number, also allowing scientific notation like 1e6.
+fn parse_count(text: &str) -> Result<u64, String> {
+  if let Ok(n) = text.parse() {
+    return Ok(n);
+  }
+  match text.parse::<f64>() {
+    Ok(n) if n >= 0.0 && n.fract() == 0.0 && n < u64::MAX as f64 => Ok(n as u64),
+    _ => Err(format!("'{text}' is not a natural number")),
+  }
+}
+
+/// Parses a positive amount of seconds.
+fn parse_seconds(text: &str) -> Result<Duration, String> {
+  match text.parse::<f64>() {
+    Ok(seconds) if seconds > 0.0 && seconds.is_finite() => Ok(Duration::from_secs_f64(seconds)),
+    _ => Err(format!("'{text}' is not a positive amount of seconds")),
+  }
+}
+
+fn option_arg(option: &ConfigOption) -> Arg {
+  let arg = Arg::new(option.cli).long(option.cli).global(true);
+  match option.value {
+    OptionValue::Flag(field) if *field(&mut Config::default()) => arg
+      .action(ArgAction::SetTrue)
+      .help(format!("Disables {}, see `whily options`", option.name)),
+    OptionValue::Flag(_) => arg.action(ArgAction::SetTrue).help(option.description),
+    OptionValue::Count(_) => arg
+      .value_name("N")
+      .value_parser(parse_count)
+      .help(option.description),
+    OptionValue::Dialect(_) => arg
+      .value_name("NAME")
+      .value_parser(Dialect::ALL.map(Dialect::name))
+      .help(option.description),
+  }
+}
+
+/// The arguments for running a program, which `whily run` and `whily FILE` share.
+fn run_args() -> Vec<Arg> {
+  vec![
+    file_arg("The file path of the program to run"),
+    arg!(--ast "Read FILE as a syntax tree in JSON, like `whily ast` prints"),
+    arg!(--desugar "Instead of running the program, print it with the extra operators expanded into plain WHILE loops"),
+    arg!(--cost <MODEL> "How to count the cost of running the program: one per step, or the total amount of bits of the values every step uses").value_parser(["uniform", "log"]).default_value("uniform"),
+    arg!(--profile "Count how often every statement runs and how many steps it takes, and print the statements taking the most steps"),
+    arg!(--output <FORMAT> "How to print the final state: for people, as a JSON object, as CSV, as name=value lines, or not at all").value_parser(["pretty", "json", "csv", "plain", "quiet"]).default_value("pretty"),
+    arg!(--print <VARS> "Only print these variables, like x0,x3"),
+    arg!(--verbose "Print what whily is doing on stderr"),
+    arg!(--folded <FILE> "Write the steps per stack of loops to FILE, in the folded format flame graph tools use (implies --profile)").value_parser(clap::value_parser!(PathBuf)),
+  ]
+}
 
 pub fn cli() -> Command {
-  Command::new("git")
+  Command::new("whily")
     .about("A simple interpreter for WHILE-programs")
     .arg_required_else_help(true)
-    .arg(
-      arg!(<FILE> "The file path of the program to run").value_parser(clap::value_parser!(Input)),
+    .subcommand_negates_reqs(true)
+    .args(run_args())
+    .args(OPTIONS.iter().map(option_arg))
+    .arg(arg!(--set <OPTION> "Sets an option, like max_steps=1e6 or no_accelerate (see `whily options`)").action(ArgAction::Append).global(true))
+    .subcommand(Command::new("options").about("Lists all options with their defaults"))
+    .subcommand(Command::new("run").about("Runs a program, like `whily FILE`").args(run_args()))
+    .subcommand(
+      Command::new("ast")
+        .about("Prints the syntax tree of a program as JSON")
+        .arg(file_arg("The file path of the program to print")),
+    )
+    .subcommand(
+      Command::new("opt")
+        .about("Optimises a program and prints the resulting WHILE code, with statistics on stderr")
+        .arg(file_arg("The file path of the program to optimise")),
+    )
+    .subcommand(
+      Command::new("lower")
+        .about("Rewrites a program into core WHILE, which runs with all options disabled")
+        .arg(file_arg("The file path of the program to lower")),
+    )
+    .subcommand(
+      Command::new("encode")
+        .about("Prints the Gödel number of a program, renaming named variables to xi first")
+        .arg(file_arg("The file path of the program to encode")),
+    )
+    .subcommand(
+      Command::new("decode")
+        .about("Prints the program with the given Gödel number")
+        .arg(arg!(<NUMBER> "The Gödel number to decode")),
+    )
+    .subcommand(
+      Command::new("universal")
+        .about("Prints a universal WHILE program, which runs the program encoded in x1 on the input list in x2")
+        .args([
+          arg!(--bounded "Generate the variant that only simulates x3 steps, setting x4 to 1 if the program finished"),
+          arg!(--vars <N> "The amount of variables the simulated program may use")
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .default_value("8"),
+          arg!(--stack <N> "The maximum amount of statements on the simulation stack")
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .default_value("32"),
+          arg!(--validate "Instead of printing it, check the universal program against running some small programs directly"),
+        ]),
+    )
+    .subcommand(
+      Command::new("gen")
+        .about("Prints a random program, using only what the options allow")
+        .args([
+          arg!(--size <N> "The amount of statements, counting assignments and loops")
+            .value_parser(clap::value_parser!(usize))
+            .default_value("20"),
+          arg!(--vars <N> "The amount of variables to assign to, besides loop counters")
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .default_value("3"),
+          arg!(--"max-depth" <N> "How deep loops may be nested")
+            .value_parser(clap::value_parser!(usize))
+            .default_value("2"),
+          arg!(--seed <N> "The seed of the random generator, which is random and printed on stderr if not given")
+            .value_parser(clap::value_parser!(u64)),
+          arg!(--terminating "Only loop over counters that are decremented every iteration, so the program always stops"),
+        ]),
+    )
+    .subcommand(
+      Command::new("enumerate")
+        .about("Runs every distinct program up to a size from zeroed variables, and reports the ones with the largest x0 and the most steps")
+        .args([
+          arg!(--"max-size" <N> "The largest amount of statements, counting assignments and loops")
+            .value_parser(clap::value_parser!(usize))
+            .default_value("4"),
+          arg!(--vars <N> "The amount of variables, x0 to x(N-1)")
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .default_value("2"),
+          arg!(--steps <N> "The amount of steps after which a run counts as not halting")
+            .value_parser(parse_count)
+            .default_value("1000"),
+          arg!(--"max-constant" <N> "The largest constant to assign")
+            .value_parser(parse_count)
+            .default_value("1"),
+        ]),
+    )
+    .subcommand(
+      Command::new("test")
+        .about("Runs the test cases written in [test: ...] comments of every .while file in a directory")
+        .arg(arg!(<PATH> "The directory (or file) to test").value_parser(clap::value_parser!(PathBuf)))
+        .arg(arg!(--junit <FILE> "Also write the results as a JUnit XML report").value_parser(clap::value_parser!(PathBuf))),
+    )
+    .subcommand(
+      Command::new("equiv")
+        .about("Runs two programs on a grid of inputs and reports the smallest input where they behave differently")
+        .args([
+          arg!(<A> "The file path of the first program").value_parser(clap::value_parser!(PathBuf)),
+          arg!(<B> "The file path of the second program").value_parser(clap::value_parser!(PathBuf)),
+          arg!(--inputs <RANGES> "The inputs to try, like x1=0..50,x2=0..=50,x3=7").default_value(""),
+          arg!(--outputs <VARS> "The variables to compare when both programs finish").default_value("x0"),
+          arg!(--steps <N> "The amount of steps after which a run counts as not terminating")
+            .value_parser(parse_count)
+            .default_value("1e6"),
+        ]),
+    )
+    .subcommand(
+      Command::new("grade")
+        .about("Grades a submission by comparing it with a reference solution on every test, and prints a report")
+        .args([
+          arg!(<SUBMISSION> "The file path of the submission").value_parser(clap::value_parser!(PathBuf)),
+          arg!(--reference <FILE> "The file path of the reference solution").required(true).value_parser(clap::value_parser!(PathBuf)),
+          arg!(--inputs <FILE> "A file with a test on every line, like x1=3,x2=5, where ranges like x1=0..10 give a test for every value").required(true).value_parser(clap::value_parser!(PathBuf)),
+          arg!(--outputs <VARS> "The variables to compare when both programs finish").default_value("x0"),
+          arg!(--steps <N> "The most steps a test may take").value_parser(parse_count).default_value("1e7"),
+          arg!(--time <SECONDS> "The most time a test of the submission may take").value_parser(parse_seconds).default_value("1"),
+          arg!(--memory <BITS> "The most bits the variables of the submission may take together").value_parser(parse_count),
+          arg!(--format <FORMAT> "How to print the report").value_parser(["markdown", "json"]).default_value("markdown"),
+        ]),
+    )
+    .subcommand(
+      Command::new("cfg")
+        .about("Prints the control-flow graph of a program, for Graphviz or Mermaid")
+        .arg(file_arg("The file path of the program to draw"))
+        .args([
+          arg!(--format <FORMAT> "The format of the graph").value_parser(["dot", "mermaid"]).default_value("dot"),
+          arg!(--counts "Run the program and annotate the graph with how often every block ran"),
+          arg!(--inputs <VALUES> "The values of the inputs for --counts, like x1=5,x2=3").default_value(""),
+        ]),
+    )
+    .subcommand(
+      Command::new("debug")
+        .about("Runs a program step by step, forwards and backwards, reading commands from stdin")
+        .arg(file_arg("The file path of the program to debug"))
+        .arg(arg!(--inputs <VALUES> "The values of the inputs, like x1=5,x2=3").default_value("")),
+    )
+    .subcommand(
+      Command::new("analyse")
+        .about("Finds the assignments that may overflow or underflow, for inputs in the given ranges")
+        .arg(file_arg("The file path of the program to analyse"))
+        .arg(arg!(--inputs <RANGES> "The ranges of the inputs, like x1=0..100,x2=7; other variables are unassigned").default_value("")),
+    )
+    .subcommand(
+      Command::new("symex")
+        .about("Runs a program on symbolic inputs and prints every path with its condition and final state")
+        .arg(file_arg("The file path of the program to run"))
+        .args([
+          arg!(--symbolic <VARS> "The inputs to keep symbolic, like x1,x2").required(true),
+          arg!(--unroll <N> "The maximum amount of iterations of every loop")
+            .value_parser(parse_count)
+            .default_value("5"),
+          arg!(--bound <N> "The largest value of an input when checking for overflow")
+            .value_parser(parse_count)
+            .default_value("4294967295"),
+        ]),
+    )
+    .subcommand(
+      Command::new("complexity")
+        .about("Runs a program for a range of values of one input and fits how its steps and largest value grow")
+        .arg(file_arg("The file path of the program to measure"))
+        .args([
+          arg!(--vary <RANGE> "The input to vary, like x1=1..1000").required(true),
+          arg!(--inputs <VALUES> "The values of the other inputs, like x2=3,x3=0").default_value(""),
+          arg!(--samples <N> "The maximum amount of values to try, spread on a logarithmic scale")
+            .value_parser(clap::value_parser!(u64).range(2..))
+            .default_value("20"),
+          arg!(--steps <N> "The amount of steps after which a run is stopped")
+            .value_parser(parse_count)
+            .default_value("1e8"),
+        ]),
     )
-    .args([
-      arg!(--allow_named_vars "Enabled named variables"),
-      arg!(--allow_underflow "Allows subtraction to underflow, setting the result to max(0,res)"),
-      arg!(--allow_constants_everywhere "Allows the use of constants everywhere a variable is used for its value (and vice versa)"),
-      arg!(--extra_operators "Enabled extra operations, right now that is just * for multiplication"),
-    ])
 }
+#[derive(Clone)]
 pub struct Config {
   pub allow_named_vars: bool,
   pub allow_underflow: bool,
   pub allow_constants_everywhere: bool,
   pub extra_operators: bool,
+  pub accelerate: bool,
+  // The amount of steps after which running stops, see `run`
+  pub max_steps: Option<u64>,
+  pub dialect: Dialect,
 }
 
-impl Config {
-  pub fn from(args: &ArgMatches) -> Self {
+impl Default for Config {
+  fn default() -> Self {
     Self {
-      allow_named_vars: *args
-        .get_one("allow_named_vars")
-        .expect("Missing arg allow_named_vars"),
-      allow_underflow: *args
-        .get_one("allow_underflow")
-        .expect("Missing arg allow_underflow"),
-      allow_constants_everywhere: *args
-        .get_one("allow_constants_everywhere")
-        .expect("Missing arg allow_underflow"),
-      extra_operators: *args
-        .get_one("extra_operators")
-        .expect("Missing arg allow_underflow"),
-    }
-  }
-  pub fn enable(&mut self, arg: &str) -> Result<(), ()> {
-    match arg {
-      "allow_named_vars" => self.allow_named_vars = true,
-      "allow_underflow" => self.allow_named_vars = true,
-      "allow_constants_everywhere" => self.allow_constants_everywhere = true,
-      "extra_operators" => self.extra_operators = true,
-      _ => return Err(()),
+      allow_named_vars: false,
+      allow_underflow: false,
+      allow_constants_everywhere: false,
+      extra_operators: false,
+      accelerate: true,
+      max_steps: None,
+      dialect: Dialect::Whily,
+    }
+  }
+}
+
+// WHILE programs are defined slightly differently by different textbooks. The textbook dialects
+// accept `end` for `od`, `≠` for `!=`, `−` for `-`, `x_1` for `x1` and keywords in any case. In
+// them, variables start at 0 instead of being unassigned, subtraction stops at 0 and constants
+// are allowed everywhere. Schöning only has assignments of the form `xi := xj + c` and
+// `xi := xj - c`, while Kozen has all of the assignments whily has.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dialect {
+  Whily,
+  Schoening,
+  Kozen,
+}
+
+impl Dialect {
+  pub const ALL: [Dialect; 3] = [Dialect::Whily, Dialect::Schoening, Dialect::Kozen];
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Dialect::Whily => "whily",
+      Dialect::Schoening => "schoening",
+      Dialect::Kozen => "kozen",
+    }
+  }
+
+  pub fn is_textbook(self) -> bool {
+    self != Dialect::Whily
+  }
+}
+
+// Every option can be set in four places, each overriding the ones before it: a whily.toml file
+// in the directory of the program or one of its parents (the current directory for stdin), its
+// command line flag, --set and pragmas in the program itself. Flags are turned on with `#name`
+// and off with `#no_name`, valued options are set with `#name value` and unset with `#no_name`.
+
+#[derive(Clone, Copy)]
+pub enum OptionValue {
+  Flag(fn(&mut Config) -> &mut bool),
+  Count(fn(&mut Config) -> &mut Option<u64>),
+  Dialect(fn(&mut Config) -> &mut Dialect),
+}
+
+pub struct ConfigOption {
+  pub name: &'static str,
+  // The command line flag, which sets the option to the opposite of its default for flags
+  pub cli: &'static str,
+  pub description: &'static str,
+  pub value: OptionValue,
+}
+
+pub const OPTIONS: [ConfigOption; 7] = [
+  ConfigOption {
+    name: "allow_named_vars",
+    cli: "allow_named_vars",
+    description: "Enables named variables",
+    value: OptionValue::Flag(|c| &mut c.allow_named_vars),
+  },
+  ConfigOption {
+    name: "allow_underflow",
+    cli: "allow_underflow",
+    description: "Allows subtraction to underflow, setting the result to max(0,res)",
+    value: OptionValue::Flag(|c| &mut c.allow_underflow),
+  },
+  ConfigOption {
+    name: "allow_constants_everywhere",
+    cli: "allow_constants_everywhere",
+    description: "Allows the use of constants everywhere a variable is used for its value (and vice versa)",
+    value: OptionValue::Flag(|c| &mut c.allow_constants_everywhere),
+  },
+  ConfigOption {
+    name: "extra_operators",
+    cli: "extra_operators",
+    description: "Enables extra operations: * for multiplication, pair(a, b), fst(p) and snd(p) for Cantor pairing, and cons(h, t), head(l) and tail(l) for lists",
+    value: OptionValue::Flag(|c| &mut c.extra_operators),
+  },
+  ConfigOption {
+    name: "accelerate",
+    cli: "no-accelerate",
+    description: "Runs simple counting loops (like multiplication by repeated addition) in a single step",
+    value: OptionValue::Flag(|c| &mut c.accelerate),
+  },
+  ConfigOption {
+    name: "max_steps",
+    cli: "max_steps",
+    description: "The amount of steps after which running stops, instead of stopping loops after 131072 iterations",
+    value: OptionValue::Count(|c| &mut c.max_steps),
+  },
+  ConfigOption {
+    name: "dialect",
+    cli: "dialect",
+    description: "Which textbook's WHILE programs to accept and how to run them: whily, schoening or kozen",
+    value: OptionValue::Dialect(|c| &mut c.dialect),
+  },
+];
+
+impl ConfigOption {
+  pub fn find(name: &str) -> Option<&'static ConfigOption> {
+    OPTIONS.iter().find(|o| o.name == name)
+  }
+
+  pub fn takes_value(&self) -> bool {
+    !matches!(self.value, OptionValue::Flag(_))
+  }
+
+  /// The value of the option in `config`, as written in a pragma.
+  pub fn get(&self, config: &Config) -> String {
+    let mut config = config.clone();
+    match self.value {
+      OptionValue::Flag(field) => field(&mut config).to_string(),
+      OptionValue::Count(field) => field(&mut config).map_or("none".to_owned(), |n| n.to_string()),
+      OptionValue::Dialect(field) => field(&mut config).name().to_owned(),
+    }
+  }
+
+  /// The pragma that sets the option to its value in `config`.
+  pub fn pragma(&self, config: &Config) -> String {
+    let mut config = config.clone();
+    match self.value {
+      OptionValue::Flag(field) if *field(&mut config) => format!("#{}", self.name),
+      OptionValue::Count(field) if field(&mut config).is_some() => {
+        format!("#{} {}", self.name, self.get(&config))
+      }
+      OptionValue::Dialect(_) => format!("#{} {}", self.name, self.get(&config)),
+      _ => format!("#no_{}", self.name),
+    }
+  }
+}
+
+fn parse_flag(text: &str) -> Result<bool, String> {
+  match text {
+    "true" | "on" => Ok(true),
+    "false" | "off" => Ok(false),
+    _ => Err(format!("'{text}' is not true or false")),
+  }
+}
+
+/// The amount of single character edits to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+  let b = b.chars().collect::<Vec<_>>();
+  let mut row = (0..=b.len()).collect::<Vec<_>>();
+  for (i, ca) in a.chars().enumerate() {
+    let mut previous = row[0];
+    row[0] = i + 1;
+    for (j, cb) in b.iter().enumerate() {
+      let substitution = previous + usize::from(ca != *cb);
+      previous = row[j + 1];
+      row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+    }
+  }
+  row[b.len()]
+}
+
+/// The option (or its negation) closest to `name`, if any is close enough to be a typo.
+fn suggest(name: &str) -> Option<String> {
+  OPTIONS
+    .iter()
+    .flat_map(|o| [o.name.to_owned(), format!("no_{}", o.name)])
+    .map(|candidate| (edit_distance(name, &candidate), candidate))
+    .filter(|(distance, _)| *distance <= 2.max(name.len() / 4))
+    .min()
+    .map(|(_, candidate)| candidate)
+}
+
+impl Config {
+  /// The config for running `program`, which is `None` for stdin or when there is no program.
+  pub fn from(args: &ArgMatches, program: Option<&Path>) -> Result<Self, String> {
+    let mut config = Config::for_program(program)?;
+    for option in &OPTIONS {
+      match option.value {
+        OptionValue::Flag(field) => {
+          if args.get_flag(option.cli) {
+            let default = *field(&mut Config::default());
+            *field(&mut config) = !default;
+          }
+        }
+        OptionValue::Count(field) => {
+          if let Some(n) = args.get_one::<u64>(option.cli) {
+            *field(&mut config) = Some(*n);
+          }
+        }
+        OptionValue::Dialect(_) => {
+          if let Some(name) = args.get_one::<String>(option.cli) {
+            config
+              .set(option.name, Some(name))
+              .map_err(|e| e.to_string())?;
+          }
+        }
+      }
+    }
+    for setting in args.get_many::<String>("set").into_iter().flatten() {
+      let (name, value) = match setting.split_once('=') {
+        Some((name, value)) => (name, Some(value)),
+        None => (setting.as_str(), None),
+      };
+      config
+        .set(name, value)
+        .map_err(|e| format!("In --set {setting}: {e}"))?;
+    }
+    Ok(config)
+  }
+
+  /// The default config with the options of the whily.toml that applies to `program`.
+  pub fn for_program(program: Option<&Path>) -> Result<Self, String> {
+    let mut config = Config::default();
+    if let Some(path) = find_project_file(program) {
+      config.read_project_file(&path)?;
+    }
+    Ok(config)
+  }
+
+  /// Sets the option `name`, which may be `no_` followed by the name of an option to turn it off.
+  pub fn set(&mut self, name: &str, value: Option<&str>) -> Result<(), OptionError> {
+    let name = name.replace('-', "_");
+    let (option, negated) = match name.strip_prefix("no_").and_then(ConfigOption::find) {
+      Some(option) => (option, true),
+      None => match ConfigOption::find(&name) {
+        Some(option) => (option, false),
+        None => {
+          return Err(OptionError::Unknown {
+            suggestion: suggest(&name),
+            name,
+          })
+        }
+      },
+    };
+    let invalid = |reason| OptionError::InvalidValue {
+      name: option.name,
+      value: value.unwrap_or_default().to_owned(),
+      reason,
+    };
+    match (option.value, negated, value) {
+      (_, true, Some(_)) => {
+        return Err(OptionError::UnexpectedValue {
+          name: format!("no_{}", option.name),
+        })
+      }
+      (OptionValue::Flag(field), _, None) => *field(self) = !negated,
+      (OptionValue::Flag(field), false, Some(value)) => {
+        *field(self) = parse_flag(value).map_err(invalid)?
+      }
+      (OptionValue::Count(field), true, None) => *field(self) = None,
+      (OptionValue::Count(field), false, Some(value)) => {
+        *field(self) = Some(parse_count(value).map_err(invalid)?)
+      }
+      (OptionValue::Dialect(field), true, None) => *field(self) = Dialect::Whily,
+      (OptionValue::Dialect(field), false, Some(value)) => {
+        *field(self) = Dialect::ALL
+          .into_iter()
+          .find(|d| d.name() == value)
+          .ok_or_else(|| invalid("it is not whily, schoening or kozen".to_owned()))?
+      }
+      (OptionValue::Count(_) | OptionValue::Dialect(_), false, None) => {
+        return Err(OptionError::MissingValue { name: option.name })
+      }
+    }
+    Ok(())
+  }
+
+  /// Whether subtraction stops at 0 instead of underflowing.
+  pub fn underflow_allowed(&self) -> bool {
+    self.allow_underflow || self.dialect.is_textbook()
+  }
+
+  pub fn constants_everywhere(&self) -> bool {
+    self.allow_constants_everywhere || self.dialect.is_textbook()
+  }
+
+  /// Reads the options in a whily.toml file. Only `name = value` lines are supported, optionally
+  /// in an `[options]` table.
+  fn read_project_file(&mut self, path: &Path) -> Result<(), String> {
+    let text = std::fs::read_to_string(path)
+      .map_err(|e| format!("Error occurred while reading {}: {e}", path.display()))?;
+    for (i, line) in text.lines().enumerate() {
+      let error = |e: String| format!("{}:{}: {e}", path.display(), i + 1);
+      let line = line.split('#').next().unwrap_or_default().trim();
+      if line.is_empty() || line == "[options]" {
+        continue;
+      }
+      let Some((name, value)) = line.split_once('=') else {
+        return Err(error(format!("Expected 'name = value', found '{line}'")));
+      };
+      let value = value.trim().trim_matches('"');
+      self
+        .set(name.trim(), Some(value))
+        .map_err(|e| error(e.to_string()))?;
     }
     Ok(())
   }
 }
+
+/// The nearest whily.toml, looking in the directory of `program` and then its parents. Without a
+/// program, the search starts in the current directory.
+fn find_project_file(program: Option<&Path>) -> Option<PathBuf> {
+  let cwd = std::env::current_dir().ok()?;
+  let start = match program {
+    Some(path) if path.is_dir() => cwd.join(path),
+    Some(path) => cwd.join(path).parent()?.to_owned(),
+    None => cwd,
+  };
+  start
+    .ancestors()
+    .map(|d| d.join("whily.toml"))
+    .find(|p| p.is_file())
+}
+
+/// A table of all options with their defaults, for `whily options`.
+pub fn options_table() -> String {
+  let default = Config::default();
+  let mut out = String::new();
+  writeln!(
+    out,
+    "Options are set in a whily.toml file (name = value), with command line flags or --set name=value,\nor with pragmas in a program (#name, #no_name or #name value), which override each other in that order.\n"
+  )
+  .unwrap();
+  writeln!(
+    out,
+    "{:<28} {:<8} {:<30} Description",
+    "Option", "Default", "Flag"
+  )
+  .unwrap();
+  for option in &OPTIONS {
+    let (name, flag) = match option.value {
+      OptionValue::Flag(_) => (option.name.to_owned(), format!("--{}", option.cli)),
+      OptionValue::Count(_) => (
+        format!("{} <N>", option.name),
+        format!("--{} <N>", option.cli),
+      ),
+      OptionValue::Dialect(_) => (
+        format!("{} <NAME>", option.name),
+        format!("--{} <NAME>", option.cli),
+      ),
+    };
+    writeln!(
+      out,
+      "{name:<28} {:<8} {flag:<30} {}",
+      option.get(&default),
+      option.description
+    )
+    .unwrap();
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn project_file_is_found_from_the_program() {
+    let root = std::env::temp_dir().join(format!("whily-config-{}", std::process::id()));
+    let dir = root.join("exercises").join("one");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(root.join("whily.toml"), "allow_underflow = true\n").unwrap();
+    let program = dir.join("program.while");
+    assert_eq!(
+      find_project_file(Some(&program)),
+      Some(root.join("whily.toml"))
+    );
+    assert_eq!(find_project_file(Some(&dir)), Some(root.join("whily.toml")));
+    std::fs::write(dir.join("whily.toml"), "").unwrap();
+    assert_eq!(
+      find_project_file(Some(&program)),
+      Some(dir.join("whily.toml"))
+    );
+    std::fs::remove_dir_all(&root).unwrap();
+  }
+}
@@ -0,0 +1,485 @@
+use crate::{
+  output::sorted_variables,
+  parser::Statement,
+  run::{apply, read, RuntimeError},
+  symbolizer::Span,
+  Config,
+};
+use std::{
+  collections::HashMap,
+  io::{BufRead, Write},
+};
+
+// An interactive debugger that runs a program one step at a time (see `run` for what a step is)
+// and can also run it backwards.
+//
+// The program counter is a stack of the statements that still have to run, with the next one on
+// top. Every step appends how to undo it to a log: the old value of the variable it wrote and the
+// changes it made to the stack. To keep memory bounded on long runs, the log is cleared every
+// LOG_LIMIT steps after saving a checkpoint with a full copy of the state and stack. When there
+// are too many checkpoints, every other one is dropped. Stepping back past the start of the log
+// restores the last checkpoint before it and runs forward again, which rebuilds the log.
+
+const LOG_LIMIT: usize = 4096;
+const MAX_CHECKPOINTS: usize = 64;
+// The amount of steps `continue` takes before pausing, unless `max_steps` is set
+const CONTINUE_LIMIT: u64 = 10_000_000;
+
+const HELP: &str = "\
+Commands:
+  step [N]               Run the next N steps (s)
+  continue               Run until a breakpoint or the end (c)
+  reverse-step [N]       Undo the last N steps (rs)
+  reverse-continue [VAR] Run backwards to the last write of VAR, or to a breakpoint (rc)
+  break LINE             Pause before running statements on LINE (b)
+  delete LINE            Remove the breakpoint on LINE (d)
+  print [VAR]            Print the state, or one variable (p)
+  where                  Print the step count and the next statement (w)
+  quit                   Stop debugging (q)";
+
+struct Undo<'a> {
+  // The variable that was written, with its old value
+  write: Option<(String, Option<u64>)>,
+  // What the step did to the stack: the statements it popped, and how many it pushed after that
+  popped: Vec<&'a Statement>,
+  pushed: usize,
+}
+
+struct Checkpoint<'a> {
+  steps: u64,
+  state: HashMap<String, u64>,
+  stack: Vec<&'a Statement>,
+}
+
+pub struct Machine<'a> {
+  config: &'a Config,
+  state: HashMap<String, u64>,
+  stack: Vec<&'a Statement>,
+  steps: u64,
+  log: Vec<Undo<'a>>,
+  checkpoints: Vec<Checkpoint<'a>>,
+}
+
+impl<'a> Machine<'a> {
+  pub fn new(config: &'a Config, prog: &'a Statement, mut state: HashMap<String, u64>) -> Self {
+    if config.dialect.is_textbook() {
+      for var in prog.variables() {
+        state.entry(var.to_owned()).or_insert(0);
+      }
+    }
+    let mut machine = Self {
+      config,
+      state,
+      stack: vec![prog],
+      steps: 0,
+      log: vec![],
+      checkpoints: vec![],
+    };
+    let mut undo = Undo {
+      write: None,
+      popped: vec![],
+      pushed: 0,
+    };
+    machine.expand(&mut undo);
+    machine.checkpoint();
+    machine
+  }
+
+  /// The statement that runs next, if the program hasn't finished.
+  pub fn next(&self) -> Option<&'a Statement> {
+    self.stack.last().copied()
+  }
+
+  /// Replaces sequences on top of the stack by their parts, so that the next statement is an
+  /// assignment or a loop.
+  fn expand(&mut self, undo: &mut Undo<'a>) {
+    while let Some(sequence @ Statement::S(left, right)) = self.stack.last().copied() {
+      self.stack.pop();
+      // Popping something this step pushed just takes back the push
+      if undo.pushed > 0 {
+        undo.pushed -= 1;
+      } else {
+        undo.popped.push(sequence);
+      }
+      self.stack.push(right);
+      self.stack.push(left);
+      undo.pushed += 2;
+    }
+  }
+
+  /// Runs the next statement, returning `Ok(false)` if the program had already finished.
+  pub fn step(&mut self) -> Result<bool, RuntimeError> {
+    let Some(statement) = self.next() else {
+      return Ok(false);
+    };
+    if self
+      .config
+      .max_steps
+      .is_some_and(|max_steps| self.steps >= max_steps)
+    {
+      return Err(RuntimeError::StepLimitReached);
+    }
+    let mut undo = Undo {
+      write: None,
+      popped: vec![statement],
+      pushed: 0,
+    };
+    match statement {
+      Statement::While(cv, body) => {
+        let value = self
+          .state
+          .get(cv)
+          .ok_or_else(|| RuntimeError::UnassignedVariable(cv.to_owned()))?;
+        self.stack.pop();
+        if *value != 0 {
+          self.stack.push(statement);
+          self.stack.push(body);
+          undo.pushed = 2;
+        }
+      }
+      Statement::DeclareOperation(v0, v1, operator, v2) => {
+        let (v1, v2) = (read(&self.state, v1)?, read(&self.state, v2)?);
+        let value = apply(self.config, v0, operator, v1, v2)?;
+        self.stack.pop();
+        undo.write = Some((v0.to_owned(), self.state.insert(v0.to_owned(), value)));
+      }
+      Statement::DeclareConst(v0, v) => {
+        let value = read(&self.state, v)?;
+        self.stack.pop();
+        undo.write = Some((v0.to_owned(), self.state.insert(v0.to_owned(), value)));
+      }
+      Statement::S(..) => unreachable!("Sequences are expanded before they run"),
+    }
+    self.expand(&mut undo);
+    self.steps += 1;
+    self.log.push(undo);
+    if self.log.len() >= LOG_LIMIT {
+      self.checkpoint();
+    }
+    Ok(true)
+  }
+
+  /// Runs the rest of the program, returning the final state.
+  pub fn finish(mut self) -> Result<HashMap<String, u64>, RuntimeError> {
+    while self.step()? {}
+    Ok(self.state)
+  }
+
+  fn checkpoint(&mut self) {
+    self.log.clear();
+    let index = self.checkpoints.partition_point(|c| c.steps < self.steps);
+    if self
+      .checkpoints
+      .get(index)
+      .is_some_and(|c| c.steps == self.steps)
+    {
+      return;
+    }
+    self.checkpoints.insert(
+      index,
+      Checkpoint {
+        steps: self.steps,
+        state: self.state.clone(),
+        stack: self.stack.clone(),
+      },
+    );
+    if self.checkpoints.len() > MAX_CHECKPOINTS {
+      // Keeps the first one, at step 0
+      let mut i = 0;
+      self.checkpoints.retain(|_| {
+        i += 1;
+        i % 2 == 1
+      });
+    }
+  }
+
+  /// Undoes the last step, returning the variable it wrote. Returns `None` at the start.
+  pub fn step_back(&mut self) -> Option<Option<String>> {
+    if self.steps == 0 {
+      return None;
+    }
+    let Some(undo) = self.log.pop() else {
+      // Before the start of the log, so run forward from an earlier checkpoint
+      let target = self.steps - 1;
+      let checkpoint = self.checkpoints.iter().rfind(|c| c.steps <= target)?;
+      self.state = checkpoint.state.clone();
+      self.stack = checkpoint.stack.clone();
+      self.steps = checkpoint.steps;
+      self.log.clear();
+      while self.steps < target {
+        self.step().ok()?;
+      }
+      return Some(self.written_by_next());
+    };
+    let written = undo.write.map(|(var, old)| {
+      match old {
+        Some(old) => self.state.insert(var.clone(), old),
+        None => self.state.remove(&var),
+      };
+      var
+    });
+    self.steps -= 1;
+    self.undo_stack(undo.popped, undo.pushed);
+    Some(written)
+  }
+
+  fn undo_stack(&mut self, popped: Vec<&'a Statement>, pushed: usize) {
+    self.stack.truncate(self.stack.len() - pushed);
+    self.stack.extend(popped.into_iter().rev());
+  }
+
+  /// The variable the next statement writes, if it is an assignment.
+  fn written_by_next(&self) -> Option<String> {
+    match self.next()? {
+      Statement::DeclareOperation(v0, ..) | Statement::DeclareConst(v0, _) => Some(v0.to_owned()),
+      _ => None,
+    }
+  }
+}
+
+pub struct Debugger<'a> {
+  machine: Machine<'a>,
+  ids: HashMap<*const Statement, usize>,
+  spans: &'a [Span],
+  breakpoints: Vec<usize>,
+}
+
+impl<'a> Debugger<'a> {
+  pub fn new(machine: Machine<'a>, prog: &'a Statement, spans: &'a [Span]) -> Self {
+    Self {
+      machine,
+      ids: prog
+        .nodes()
+        .into_iter()
+        .enumerate()
+        .map(|(id, s)| (s as *const Statement, id))
+        .collect(),
+      spans,
+      breakpoints: vec![],
+    }
+  }
+
+  fn span(&self, statement: &Statement) -> Span {
+    self.spans[self.ids[&(statement as *const Statement)]]
+  }
+
+  fn at_breakpoint(&self) -> bool {
+    self
+      .machine
+      .next()
+      .is_some_and(|s| self.breakpoints.contains(&self.span(s).line))
+  }
+
+  fn position(&self) -> String {
+    match self.machine.next() {
+      None => format!("Finished after {} steps.", self.machine.steps),
+      Some(statement) => {
+        let text = match statement {
+          Statement::While(cv, _) => format!("while {cv} != 0"),
+          s => s.to_string(),
+        };
+        format!(
+          "Step {}, at {}: {text}",
+          self.machine.steps,
+          self.span(statement)
+        )
+      }
+    }
+  }
+
+  /// Runs forward until `stop` says so after a step, returning why it stopped.
+  fn forward(&mut self, max: u64, stop: impl Fn(&Self) -> bool) -> Option<String> {
+    for _ in 0..max {
+      match self.machine.step() {
+        Ok(true) if stop(self) => return None,
+        Ok(true) => {}
+        Ok(false) => return None,
+        Err(e) => return Some(format!("A runtime error occurred: {e:?}")),
+      }
+    }
+    None
+  }
+
+  /// Runs one command, returning `None` if the session should end.
+  fn command(&mut self, line: &str) -> Option<String> {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or("");
+    let argument = words.next();
+    let count = || argument.map_or(Ok(1), str::parse::<u64>);
+    let out = match command {
+      "" => String::new(),
+      "q" | "quit" => return None,
+      "h" | "help" => HELP.to_owned(),
+      "s" | "step" => match count() {
+        Ok(n) => {
+          let error = self.forward(n, |_| false);
+          error.map_or(self.position(), |e| format!("{e}\n{}", self.position()))
+        }
+        Err(_) => "Expected a number of steps.".to_owned(),
+      },
+      "c" | "continue" => {
+        let max = self.machine.config.max_steps.unwrap_or(CONTINUE_LIMIT);
+        let error = self.forward(max, Self::at_breakpoint);
+        error.map_or(self.position(), |e| format!("{e}\n{}", self.position()))
+      }
+      "rs" | "reverse-step" => match count() {
+        Ok(n) => {
+          for _ in 0..n {
+            if self.machine.step_back().is_none() {
+              break;
+            }
+          }
+          self.position()
+        }
+        Err(_) => "Expected a number of steps.".to_owned(),
+      },
+      "rc" | "reverse-continue" => {
+        while let Some(written) = self.machine.step_back() {
+          let stop = match argument {
+            Some(var) => written.as_deref() == Some(var),
+            None => self.at_breakpoint(),
+          };
+          if stop {
+            break;
+          }
+        }
+        self.position()
+      }
+      "b" | "break" | "d" | "delete" => match argument.map(str::parse::<usize>) {
+        Some(Ok(line)) if command.starts_with('b') => {
+          self.breakpoints.push(line);
+          format!("Breakpoint on line {line}.")
+        }
+        Some(Ok(line)) => {
+          self.breakpoints.retain(|&b| b != line);
+          format!("Removed the breakpoint on line {line}.")
+        }
+        _ => "Expected a line number.".to_owned(),
+      },
+      "p" | "print" => match argument {
+        Some(var) => match self.machine.state.get(var) {
+          Some(value) => format!("{var} = {value}"),
+          None => format!("{var} is unassigned"),
+        },
+        None => {
+          let state = &self.machine.state;
+          let width = state.keys().map(String::len).max().unwrap_or(0);
+          sorted_variables(state)
+            .into_iter()
+            .map(|var| format!("{var:<width$} = {}", state[var]))
+            .collect::<Vec<_>>()
+            .join("\n")
+        }
+      },
+      "w" | "where" => self.position(),
+      _ => format!("Unknown command '{command}', type help for a list of commands."),
+    };
+    Some(out)
+  }
+
+  /// Reads commands from `input` until it ends or says quit.
+  pub fn session(&mut self, input: impl BufRead) {
+    println!("{}", self.position());
+    print!("(whily) ");
+    std::io::stdout().flush().unwrap();
+    for line in input.lines() {
+      let Ok(line) = line else { break };
+      match self.command(line.trim()) {
+        Some(out) if out.is_empty() => {}
+        Some(out) => println!("{out}"),
+        None => return,
+      }
+      print!("(whily) ");
+      std::io::stdout().flush().unwrap();
+    }
+    println!();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    run::{run_counted, Counters},
+    testing::{cases, random_config, MAX_STEPS},
+  };
+
+  #[test]
+  fn finish_matches_run() {
+    for case in cases(44, 500, |rng| random_config(rng, true)) {
+      // Steps only match without acceleration
+      let config = Config {
+        accelerate: false,
+        max_steps: Some(MAX_STEPS),
+        ..case.config
+      };
+      let mut counters = Counters::default();
+      let ran = run_counted(&config, &case.prog, case.input.clone(), &mut counters);
+      let finished = Machine::new(&config, &case.prog, case.input.clone()).finish();
+      let error = |e: &RuntimeError| (e.kind(), e.variable().map(str::to_owned));
+      match (&ran, &finished) {
+        (Ok(a), Ok(b)) => assert_eq!(a, b, "Different states for\n{}", case.prog),
+        (Err(a), Err(b)) => assert_eq!(error(a), error(b), "Different errors for\n{}", case.prog),
+        _ => panic!("Only one of run and the debugger failed on\n{}", case.prog),
+      }
+      // `run` also counts the step that failed, the debugger only the steps it can undo
+      if ran.is_ok() {
+        let mut machine = Machine::new(&config, &case.prog, case.input.clone());
+        while let Ok(true) = machine.step() {}
+        assert_eq!(
+          counters.steps, machine.steps,
+          "Different steps for\n{}",
+          case.prog
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn reverse_steps_across_checkpoints() {
+    let config = Config {
+      allow_constants_everywhere: true,
+      ..Config::default()
+    };
+    // Enough steps for the checkpoints to be thinned out
+    let code = "x1 := 100000; x2 := 0; while x1 != 0 do x2 := x2 + 3; x1 := x1 - 1 od";
+    let prog = crate::compile(&mut config.clone(), code).unwrap();
+    let mut machine = Machine::new(&config, &prog, HashMap::new());
+    let position = |machine: &Machine| {
+      let value = |var| machine.state.get(var).copied();
+      (
+        value("x1"),
+        value("x2"),
+        machine.next().map(|s| s as *const _),
+      )
+    };
+    let mut history = vec![];
+    loop {
+      let written = machine.written_by_next();
+      history.push((position(&machine), written));
+      if !machine.step().unwrap() {
+        break;
+      }
+    }
+    assert!(machine.steps > (LOG_LIMIT * MAX_CHECKPOINTS) as u64);
+    assert!(machine.checkpoints.len() <= MAX_CHECKPOINTS);
+    let end = history.pop().unwrap().0;
+    // Back past the start of the log and forward again
+    for _ in 0..LOG_LIMIT + 10 {
+      machine.step_back().unwrap();
+    }
+    while machine.step().unwrap() {}
+    assert_eq!(position(&machine), end);
+    while let Some((before, written)) = history.pop() {
+      assert_eq!(machine.step_back(), Some(written));
+      assert_eq!(
+        position(&machine),
+        before,
+        "Wrong position at step {}",
+        machine.steps
+      );
+      assert_eq!(machine.steps, history.len() as u64);
+    }
+    assert_eq!(machine.step_back(), None);
+  }
+}
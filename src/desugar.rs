@@ -0,0 +1,386 @@
+use crate::{
+  godel::var_index,
+  parser::{apply, assign, constant, looped, var, Statement, Value},
+  symbolizer::Operator,
+};
+
+// Expands the extra operators (*, pair, fst, snd, cons, head and tail) into plain WHILE loops.
+// The expansions only use `xi := c`, `xi := xj + xk`, `xi := xj - xk` and loops, with fresh
+// x-indexed temporaries, so they don't need any of the other config options and never underflow.
+// They take time proportional to the values involved, except for multiplication, which `run`
+// accelerates. Errors caused by an expansion can mention one of its temporaries instead of the
+// assigned variable.
+//
+// Lowering goes further and also rewrites everything else the config options allow into core
+// WHILE, which parses with all of them disabled: constant operands are first assigned to
+// temporaries, copies `xi := xj` become `xi := xj + 0`, subtraction that may stop at 0 becomes a
+// loop that only decrements while the result isn't 0 yet, and named variables are renamed to
+// unused `xi`. Variables starting at 0 in the textbook dialects aren't lowered, so those programs
+// have to assign their variables before using them.
+
+struct Desugarer {
+  next_temp: u64,
+  // Temporaries holding 0 and 1, only assigned if they get used
+  zero: String,
+  one: String,
+  used: bool,
+  // Whether to lower to core WHILE instead of only expanding the extra operators
+  lower: bool,
+  underflow: bool,
+}
+
+impl Desugarer {
+  fn temp(&mut self) -> String {
+    let temp = format!("x{}", self.next_temp);
+    self.next_temp += 1;
+    temp
+  }
+
+  fn zero(&mut self) -> Value {
+    self.used = true;
+    var(&self.zero)
+  }
+
+  fn one(&mut self) -> Value {
+    self.used = true;
+    var(&self.one)
+  }
+
+  /// dst := value, without copying variables directly
+  fn copy(&mut self, dst: &str, value: &Value) -> Statement {
+    match value {
+      Value::Constant(c) => assign(dst, constant(*c)),
+      Value::Variable(_) => apply(dst, value.clone(), Operator::Add, self.zero()),
+    }
+  }
+
+  /// A variable holding the value, assigning constants to a temporary in `out` first.
+  fn operand(&mut self, value: Value, out: &mut Vec<Statement>) -> Value {
+    match value {
+      Value::Constant(c) => {
+        let temp = self.temp();
+        out.push(assign(&temp, constant(c)));
+        var(&temp)
+      }
+      v => v,
+    }
+  }
+
+  /// dst := max(0, v1 - v2) without underflowing.
+  fn monus(&mut self, dst: &str, v1: &Value, v2: &Value) -> Vec<Statement> {
+    let (r, n, t) = (self.temp(), self.temp(), self.temp());
+    vec![
+      self.copy(&r, v1),
+      self.copy(&n, v2),
+      looped(
+        &n,
+        vec![
+          apply(&t, var(&r), Operator::Add, self.zero()),
+          looped(
+            &t,
+            vec![
+              apply(&r, var(&r), Operator::Subtract, self.one()),
+              assign(&t, constant(0)),
+            ],
+          ),
+          apply(&n, var(&n), Operator::Subtract, self.one()),
+        ],
+      ),
+      apply(dst, var(&r), Operator::Add, self.zero()),
+    ]
+  }
+
+  fn multiply(&mut self, dst: &str, v1: &Value, v2: &Value) -> Vec<Statement> {
+    let (m, n, b) = (self.temp(), self.temp(), self.temp());
+    vec![
+      assign(&m, constant(0)),
+      self.copy(&n, v1),
+      self.copy(&b, v2),
+      looped(
+        &n,
+        vec![
+          apply(&m, var(&m), Operator::Add, var(&b)),
+          apply(&n, var(&n), Operator::Subtract, self.one()),
+        ],
+      ),
+      apply(dst, var(&m), Operator::Add, self.zero()),
+    ]
+  }
+
+  /// dst := pair(v1, v2) + extra, with pair(a, b) = 1 + 2 + .. + (a + b) + b
+  fn pair(&mut self, dst: &str, v1: &Value, v2: &Value, extra: u64) -> Vec<Statement> {
+    let (a, b, s, t) = (self.temp(), self.temp(), self.temp(), self.temp());
+    let mut out = vec![
+      self.copy(&a, v1),
+      self.copy(&b, v2),
+      apply(&s, var(&a), Operator::Add, var(&b)),
+      assign(&t, constant(0)),
+      looped(
+        &s,
+        vec![
+          apply(&t, var(&t), Operator::Add, var(&s)),
+          apply(&s, var(&s), Operator::Subtract, self.one()),
+        ],
+      ),
+      apply(dst, var(&t), Operator::Add, var(&b)),
+    ];
+    for _ in 0..extra {
+      out.push(apply(dst, var(dst), Operator::Add, self.one()));
+    }
+    out
+  }
+
+  /// dst := the first (or second) half of the pair v, or of v - 1 if `list` is set (with 0 for
+  /// the empty list). Walks along the diagonals of the pairing function, starting at <0, 0>:
+  /// the successor of <a, b> is <a - 1, b + 1>, or <b + 1, 0> if a is 0.
+  fn unpair(&mut self, dst: &str, v: &Value, second: bool, list: bool) -> Vec<Statement> {
+    let (n, a, b, is_zero, t) = (
+      self.temp(),
+      self.temp(),
+      self.temp(),
+      self.temp(),
+      self.temp(),
+    );
+    let mut out = vec![self.copy(&n, v)];
+    if list {
+      let l = self.temp();
+      out.extend([
+        apply(&l, var(&n), Operator::Add, self.zero()),
+        assign(&n, constant(0)),
+        looped(
+          &l,
+          vec![
+            apply(&n, var(&l), Operator::Subtract, self.one()),
+            assign(&l, constant(0)),
+          ],
+        ),
+      ]);
+    }
+    out.extend([
+      assign(&a, constant(0)),
+      assign(&b, constant(0)),
+      looped(
+        &n,
+        vec![
+          assign(&is_zero, constant(1)),
+          apply(&t, var(&a), Operator::Add, self.zero()),
+          looped(
+            &t,
+            vec![assign(&is_zero, constant(0)), assign(&t, constant(0))],
+          ),
+          apply(&t, var(&a), Operator::Add, self.zero()),
+          looped(
+            &t,
+            vec![
+              apply(&a, var(&a), Operator::Subtract, self.one()),
+              apply(&b, var(&b), Operator::Add, self.one()),
+              assign(&t, constant(0)),
+            ],
+          ),
+          looped(
+            &is_zero,
+            vec![
+              apply(&a, var(&b), Operator::Add, self.one()),
+              assign(&b, constant(0)),
+              assign(&is_zero, constant(0)),
+            ],
+          ),
+          apply(&n, var(&n), Operator::Subtract, self.one()),
+        ],
+      ),
+    ]);
+    let half = if second { b } else { a };
+    out.push(apply(dst, var(&half), Operator::Add, self.zero()));
+    out
+  }
+
+  fn statement(&mut self, statement: Statement) -> Vec<Statement> {
+    match statement {
+      Statement::S(left, right) => {
+        let mut out = self.statement(*left);
+        out.extend(self.statement(*right));
+        out
+      }
+      Statement::While(cv, body) => vec![looped(&cv, self.statement(*body))],
+      Statement::DeclareOperation(v0, v1, operator, v2) => match operator {
+        Operator::Subtract if self.lower && self.underflow => self.monus(&v0, &v1, &v2),
+        Operator::Add | Operator::Subtract if self.lower => {
+          let mut out = vec![];
+          let v1 = self.operand(v1, &mut out);
+          let v2 = self.operand(v2, &mut out);
+          out.push(Statement::DeclareOperation(v0, v1, operator, v2));
+          out
+        }
+        Operator::Add | Operator::Subtract => {
+          vec![Statement::DeclareOperation(v0, v1, operator, v2)]
+        }
+        Operator::Multiply => self.multiply(&v0, &v1, &v2),
+        Operator::Pair => self.pair(&v0, &v1, &v2, 0),
+        Operator::Cons => self.pair(&v0, &v1, &v2, 1),
+        Operator::Fst => self.unpair(&v0, &v1, false, false),
+        Operator::Snd => self.unpair(&v0, &v1, true, false),
+        Operator::Head => self.unpair(&v0, &v1, false, true),
+        Operator::Tail => self.unpair(&v0, &v1, true, true),
+      },
+      Statement::DeclareConst(v0, v @ Value::Variable(_)) if self.lower => vec![self.copy(&v0, &v)],
+      s => vec![s],
+    }
+  }
+}
+
+fn max_var_index(prog: &Statement) -> Option<u64> {
+  let value_index = |v: &Value| match v {
+    Value::Variable(var) => var_index(var),
+    Value::Constant(_) => None,
+  };
+  match prog {
+    Statement::S(left, right) => max_var_index(left).max(max_var_index(right)),
+    Statement::DeclareOperation(v0, v1, _, v2) => {
+      var_index(v0).max(value_index(v1)).max(value_index(v2))
+    }
+    Statement::DeclareConst(v0, v) => var_index(v0).max(value_index(v)),
+    Statement::While(cv, body) => var_index(cv).max(max_var_index(body)),
+  }
+}
+
+/// Rewrites the program so it doesn't use any of the extra operators.
+pub fn desugar(prog: Statement) -> Statement {
+  rewrite(prog, false, false)
+}
+
+/// Rewrites the program into core WHILE, which doesn't need any config options. The program has
+/// to be canonicalised first, see `godel::canonicalise`. `underflow` is whether subtraction
+/// stops at 0 in the config the program was written for.
+pub fn lower(prog: Statement, underflow: bool) -> Statement {
+  rewrite(prog, true, underflow)
+}
+
+fn rewrite(prog: Statement, lower: bool, underflow: bool) -> Statement {
+  let next = max_var_index(&prog).map_or(0, |i| i + 1);
+  let mut desugarer = Desugarer {
+    next_temp: next + 2,
+    zero: format!("x{next}"),
+    one: format!("x{}", next + 1),
+    used: false,
+    lower,
+    underflow,
+  };
+  let mut statements = desugarer.statement(prog);
+  if desugarer.used {
+    statements.insert(0, assign(&desugarer.one, constant(1)));
+    statements.insert(0, assign(&desugarer.zero, constant(0)));
+  }
+  Statement::from_statements(statements).expect("Desugaring removed every statement")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    compile,
+    config::Config,
+    godel::canonicalise,
+    testing::{cases, outcome, random_config, Outcome},
+  };
+
+  /// Whether the rewritten program ends like the original. Errors in expansions can be about
+  /// temporaries, so only their kind is compared, and temporaries are left out of the states.
+  fn same_outcome(before: &Outcome, after: &Outcome) -> bool {
+    match (before, after) {
+      (Ok(before), Ok(after)) => before
+        .iter()
+        .all(|(var, value)| after.get(var) == Some(value)),
+      (Err((before, _)), Err((after, _))) => before == after,
+      _ => false,
+    }
+  }
+
+  #[test]
+  fn pair_expansion() {
+    // x8 sums 1 to x1 + x2, the temporaries are numbered after the largest variable
+    let pair = "x3 := 0;
+x4 := 1;
+x5 := x1 + x3;
+x6 := x2 + x3;
+x7 := x5 + x6;
+x8 := 0;
+while x7 != 0 do
+  x8 := x8 + x7;
+  x7 := x7 - x4
+od;
+x0 := x8 + x6";
+    let expand = |code: &str| {
+      let code = format!("#extra_operators\n{code}");
+      desugar(compile(&mut Config::default(), &code).unwrap()).to_string()
+    };
+    assert_eq!(expand("x0 := pair(x1, x2)"), pair);
+    assert_eq!(
+      expand("x0 := cons(x1, x2)"),
+      format!("{pair};\nx0 := x0 + x4")
+    );
+  }
+
+  #[test]
+  fn desugaring_preserves_behaviour() {
+    let configs = |rng: &mut _| Config {
+      extra_operators: true,
+      ..random_config(rng, false)
+    };
+    for case in cases(30, 300, configs) {
+      let before = outcome(&case.config, &case.prog, &case.input);
+      let desugared = desugar(case.prog.clone());
+      let after = outcome(&case.config, &desugared, &case.input);
+      // The expansions take time proportional to the values, so they can run out of steps
+      if [&before, &after]
+        .iter()
+        .any(|o| matches!(o, Err(("StepLimitReached", _))))
+      {
+        continue;
+      }
+      assert!(
+        same_outcome(&before, &after),
+        "Desugaring changed what\n{}\ndoes from {before:?} to {after:?}, giving\n{desugared}",
+        case.prog
+      );
+    }
+  }
+
+  #[test]
+  fn lowering_preserves_behaviour() {
+    for case in cases(40, 300, |rng| random_config(rng, false)) {
+      let (canonical, renames) = canonicalise(&case.prog);
+      let rename = |var: &String| {
+        let renamed = renames.iter().find(|(old, _)| old == var);
+        renamed.map_or(var, |(_, new)| new).clone()
+      };
+      let input = (case.input.iter())
+        .map(|(var, value)| (rename(var), *value))
+        .collect();
+      let before = outcome(&case.config, &case.prog, &case.input).map(|state| {
+        (state.into_iter())
+          .map(|(var, value)| (rename(&var), value))
+          .collect()
+      });
+      let lowered = lower(canonical, case.config.underflow_allowed());
+      let mut core = Config::default();
+      let parsed = crate::compile(&mut core, &lowered.to_string());
+      assert!(
+        parsed.is_ok(),
+        "Lowering\n{}\ngave\n{lowered}\nwhich isn't core WHILE",
+        case.prog
+      );
+      let after = outcome(&core, &lowered, &input);
+      if [&before, &after]
+        .iter()
+        .any(|o| matches!(o, Err(("StepLimitReached", _))))
+      {
+        continue;
+      }
+      assert!(
+        same_outcome(&before, &after),
+        "Lowering changed what\n{}\ndoes from {before:?} to {after:?}, giving\n{lowered}",
+        case.prog
+      );
+    }
+  }
+}
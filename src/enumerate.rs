@@ -0,0 +1,384 @@
+use crate::{
+  config::Dialect,
+  godel::var_index,
+  parser::{Statement, Value},
+  run::{run_counted, Counters},
+  symbolizer::Operator,
+  Config,
+};
+use std::{collections::HashMap, fmt::Write};
+
+// Busy beaver style enumeration: runs every program up to a size, counting assignments and loops
+// like `whily gen` does, and reports the ones ending with the largest x0 and taking the most steps
+// among those that halt. Programs use the variables x0 to x(k-1), which all start at 0, and only
+// the assignments the config allows, with constants up to a maximum.
+//
+// Programs that only differ in the names of x1 to x(k-1) (x0 is the output) or in the order of
+// the operands of + and * do the same thing, so only one of them is run. The canonical form of a
+// program is the smallest one it can be turned into that way, comparing the sequences of their
+// tokens, and only programs that are already in canonical form are run. This needs no memory,
+// at the cost of trying every renaming for every program.
+
+const COMMUTATIVE: [Operator; 2] = [Operator::Add, Operator::Multiply];
+
+pub struct Best {
+  pub value: u64,
+  pub steps: u64,
+  pub prog: Statement,
+}
+
+/// What was found among the programs of one size.
+#[derive(Default)]
+pub struct SizeReport {
+  pub size: usize,
+  pub programs: u64,
+  pub distinct: u64,
+  pub halted: u64,
+  pub errors: u64,
+  pub out_of_steps: u64,
+  pub largest: Option<Best>,
+  pub longest: Option<Best>,
+}
+
+pub struct Enumerator<'a> {
+  config: &'a Config,
+  vars: usize,
+  // Every assignment the config allows
+  assignments: Vec<Statement>,
+  // Every renaming of the variables that keeps x0
+  renamings: Vec<Vec<u64>>,
+}
+
+fn var(i: usize) -> String {
+  format!("x{i}")
+}
+
+/// Every permutation of `items`.
+fn permutations(items: Vec<u64>) -> Vec<Vec<u64>> {
+  if items.len() <= 1 {
+    return vec![items];
+  }
+  let mut all = vec![];
+  for i in 0..items.len() {
+    let mut rest = items.clone();
+    let first = rest.remove(i);
+    for mut permutation in permutations(rest) {
+      permutation.insert(0, first);
+      all.push(permutation);
+    }
+  }
+  all
+}
+
+/// The tokens of a sequence of statements with the variables renamed, which identify it, with
+/// the operands of commutative operators in order if `sort` is set.
+fn tokens(statements: &[Statement], renaming: &[u64], sort: bool, out: &mut Vec<u64>) {
+  let var = |name: &str| renaming[var_index(name).expect("Only xi variables are used") as usize];
+  let operand = |value: &Value| match value {
+    Value::Variable(name) => [0, var(name)],
+    Value::Constant(c) => [1, *c],
+  };
+  for statement in statements {
+    match statement {
+      Statement::DeclareConst(v0, v) => {
+        out.extend([0, var(v0)]);
+        out.extend(operand(v));
+      }
+      Statement::DeclareOperation(v0, v1, operator, v2) => {
+        let (mut a, mut b) = (operand(v1), operand(v2));
+        if sort && COMMUTATIVE.contains(operator) && b < a {
+          (a, b) = (b, a);
+        }
+        out.extend([1, var(v0), operator_index(operator)]);
+        out.extend(a);
+        out.extend(b);
+      }
+      Statement::While(cv, body) => {
+        out.extend([2, var(cv)]);
+        tokens(&body.clone().into_statements(), renaming, sort, out);
+        out.push(3);
+      }
+      Statement::S(..) => tokens(&statement.clone().into_statements(), renaming, sort, out),
+    }
+  }
+}
+
+fn operator_index(operator: &Operator) -> u64 {
+  match operator {
+    Operator::Subtract => 0,
+    Operator::Add => 1,
+    Operator::Multiply => 2,
+    Operator::Pair => 3,
+    Operator::Fst => 4,
+    Operator::Snd => 5,
+    Operator::Cons => 6,
+    Operator::Head => 7,
+    Operator::Tail => 8,
+  }
+}
+
+impl<'a> Enumerator<'a> {
+  pub fn new(config: &'a Config, vars: usize, max_constant: u64) -> Self {
+    let vars = vars.max(1);
+    let constants = (0..=max_constant).map(Value::Constant).collect::<Vec<_>>();
+    let variables = (0..vars)
+      .map(|i| Value::Variable(var(i)))
+      .collect::<Vec<_>>();
+    let mut assignments = vec![];
+    if config.dialect == Dialect::Schoening {
+      for target in 0..vars {
+        for source in &variables {
+          for operator in [Operator::Add, Operator::Subtract] {
+            for c in &constants {
+              assignments.push(Statement::DeclareOperation(
+                var(target),
+                source.clone(),
+                operator.clone(),
+                c.clone(),
+              ));
+            }
+          }
+        }
+      }
+    } else {
+      let operands = match config.constants_everywhere() {
+        true => [variables.clone(), constants.clone()].concat(),
+        false => variables.clone(),
+      };
+      let mut operators = vec![Operator::Add, Operator::Subtract];
+      if config.extra_operators {
+        operators.extend([
+          Operator::Multiply,
+          Operator::Pair,
+          Operator::Fst,
+          Operator::Snd,
+          Operator::Cons,
+          Operator::Head,
+          Operator::Tail,
+        ]);
+      }
+      for target in 0..vars {
+        let sources = match config.constants_everywhere() {
+          true => &operands,
+          false => &constants,
+        };
+        for source in sources {
+          assignments.push(Statement::DeclareConst(var(target), source.clone()));
+        }
+        for operator in &operators {
+          for v1 in &operands {
+            let seconds = match operator.is_unary() {
+              true => vec![Value::Constant(0)],
+              false => operands.clone(),
+            };
+            for v2 in seconds {
+              assignments.push(Statement::DeclareOperation(
+                var(target),
+                v1.clone(),
+                operator.clone(),
+                v2,
+              ));
+            }
+          }
+        }
+      }
+    }
+    let renamings = permutations((1..vars as u64).collect())
+      .into_iter()
+      .map(|mut renaming| {
+        renaming.insert(0, 0);
+        renaming
+      })
+      .collect();
+    Self {
+      config,
+      vars,
+      assignments,
+      renamings,
+    }
+  }
+
+  /// Calls `f` with every sequence of `size` statements, each added to `prefix`.
+  fn sequences(&self, size: usize, prefix: &mut Vec<Statement>, f: &mut dyn FnMut(&[Statement])) {
+    if size == 0 {
+      return f(prefix);
+    }
+    for first in 1..=size {
+      self.items(first, &mut |item| {
+        prefix.push(item);
+        self.sequences(size - first, prefix, f);
+        prefix.pop();
+      });
+    }
+  }
+
+  /// Calls `f` with every assignment or loop of `size` statements.
+  fn items(&self, size: usize, f: &mut dyn FnMut(Statement)) {
+    if size == 1 {
+      for assignment in &self.assignments {
+        f(assignment.clone());
+      }
+      return;
+    }
+    for cv in 0..self.vars {
+      self.sequences(size - 1, &mut vec![], &mut |body| {
+        let body = Statement::from_statements(body.to_vec()).expect("Bodies aren't empty");
+        f(Statement::While(var(cv), Box::new(body)));
+      });
+    }
+  }
+
+  fn is_canonical(&self, statements: &[Statement]) -> bool {
+    let mut own = vec![];
+    tokens(statements, &self.renamings[0], false, &mut own);
+    self.renamings.iter().all(|renaming| {
+      let mut renamed = vec![];
+      tokens(statements, renaming, true, &mut renamed);
+      own <= renamed
+    })
+  }
+
+  /// Runs every distinct program of `size` statements.
+  pub fn run_size(&self, size: usize) -> SizeReport {
+    let mut report = SizeReport {
+      size,
+      ..Default::default()
+    };
+    let state = (0..self.vars)
+      .map(|i| (var(i), 0))
+      .collect::<HashMap<_, _>>();
+    self.sequences(size, &mut vec![], &mut |statements| {
+      report.programs += 1;
+      if !self.is_canonical(statements) {
+        return;
+      }
+      report.distinct += 1;
+      let prog = Statement::from_statements(statements.to_vec()).expect("Programs aren't empty");
+      let mut counters = Counters::default();
+      match run_counted(self.config, &prog, state.clone(), &mut counters) {
+        Ok(end) => {
+          report.halted += 1;
+          let best = || Best {
+            value: end["x0"],
+            steps: counters.steps,
+            prog: prog.clone(),
+          };
+          if report.largest.as_ref().is_none_or(|b| end["x0"] > b.value) {
+            report.largest = Some(best());
+          }
+          if report
+            .longest
+            .as_ref()
+            .is_none_or(|b| counters.steps > b.steps)
+          {
+            report.longest = Some(best());
+          }
+        }
+        Err(e) if e.kind() == "StepLimitReached" => report.out_of_steps += 1,
+        Err(_) => report.errors += 1,
+      }
+    });
+    report
+  }
+}
+
+pub fn render(reports: &[SizeReport]) -> String {
+  let mut out = String::new();
+  writeln!(
+    out,
+    "{:>4} {:>12} {:>12} {:>12} {:>10} {:>12} {:>22} {:>12}",
+    "size", "programs", "distinct", "halted", "errors", "out of steps", "largest x0", "most steps"
+  )
+  .unwrap();
+  for r in reports {
+    writeln!(
+      out,
+      "{:>4} {:>12} {:>12} {:>12} {:>10} {:>12} {:>22} {:>12}",
+      r.size,
+      r.programs,
+      r.distinct,
+      r.halted,
+      r.errors,
+      r.out_of_steps,
+      r.largest
+        .as_ref()
+        .map_or("-".to_owned(), |b| b.value.to_string()),
+      r.longest
+        .as_ref()
+        .map_or("-".to_owned(), |b| b.steps.to_string()),
+    )
+    .unwrap();
+  }
+  // The first of the best programs, so the smallest
+  let best = |key: fn(&Best) -> u64, pick: fn(&SizeReport) -> Option<&Best>| {
+    reports
+      .iter()
+      .filter_map(pick)
+      .fold(None::<&Best>, |best, b| match best {
+        Some(best) if key(best) >= key(b) => Some(best),
+        _ => Some(b),
+      })
+  };
+  let largest = best(|b| b.value, |r| r.largest.as_ref());
+  let longest = best(|b| b.steps, |r| r.longest.as_ref());
+  for (title, best) in [("largest x0", largest), ("most steps", longest)] {
+    if let Some(best) = best {
+      writeln!(
+        out,
+        "\nThe {title}: x0 = {} after {} steps, by\n{}",
+        best.value, best.steps, best.prog
+      )
+      .unwrap();
+    }
+  }
+  if largest.is_none() {
+    writeln!(out, "\nNo program halted.").unwrap();
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::HashSet;
+
+  #[test]
+  fn distinct_matches_deduplicating() {
+    let config = Config {
+      max_steps: Some(1000),
+      ..Config::default()
+    };
+    let enumerator = Enumerator::new(&config, 3, 1);
+    for size in 1..=3 {
+      let (mut programs, mut forms) = (0, HashSet::new());
+      enumerator.sequences(size, &mut vec![], &mut |statements| {
+        programs += 1;
+        let form = enumerator
+          .renamings
+          .iter()
+          .map(|renaming| {
+            let mut renamed = vec![];
+            tokens(statements, renaming, true, &mut renamed);
+            renamed
+          })
+          .min()
+          .unwrap();
+        forms.insert(form);
+      });
+      let report = enumerator.run_size(size);
+      assert_eq!(report.programs, programs);
+      assert_eq!(report.distinct, forms.len() as u64, "For size {size}");
+    }
+  }
+
+  #[test]
+  fn best_programs_are_known() {
+    let config = Config::default();
+    let enumerator = Enumerator::new(&config, 2, 1);
+    // x0 := 1, then doubling it with x0 := x0 + x0
+    let largest = (1..=2)
+      .map(|size| enumerator.run_size(size).largest.map(|b| b.value))
+      .collect::<Vec<_>>();
+    assert_eq!(largest, [Some(1), Some(2)]);
+  }
+}
@@ -0,0 +1,242 @@
+use crate::{godel::var_index, parser::Statement, run::run_with_input, Config};
+use std::{
+  collections::HashMap,
+  sync::atomic::{AtomicUsize, Ordering},
+  thread,
+};
+
+// Bounded equivalence checking: runs two programs on every input in a grid and compares what
+// they do. Two runs behave the same when they both finish with the same values for the output
+// variables, both stop with the same kind of error, or both run out of steps.
+//
+// The inputs are tried from the smallest to the largest sum (ties broken by the values of the
+// first variables), so the first difference that is found is also a minimal counterexample.
+
+pub struct Range {
+  pub var: String,
+  pub start: u64,
+  // Inclusive
+  pub end: u64,
+}
+
+#[derive(PartialEq, Eq)]
+enum Behaviour {
+  Finished(Vec<Option<u64>>),
+  Error(&'static str),
+  OutOfSteps,
+}
+
+pub struct Program<'a> {
+  pub name: &'a str,
+  pub config: Config,
+  pub prog: Statement,
+}
+
+/// Parses a comma separated list of `var=a..b`, `var=a..=b` or `var=a`.
+pub fn parse_ranges(text: &str) -> Result<Vec<Range>, String> {
+  let mut ranges: Vec<Range> = vec![];
+  for range in text.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+    let Some((var, values)) = range.split_once('=') else {
+      return Err(format!("Expected 'variable=start..end', found '{range}'."));
+    };
+    let var = var.trim();
+    let var = match var_index(var) {
+      Some(i) => format!("x{i}"),
+      None => var.to_owned(),
+    };
+    if ranges.iter().any(|r| r.var == var) {
+      return Err(format!("{var} is given more than once."));
+    }
+    let number = |n: &str| {
+      n.trim()
+        .parse::<u64>()
+        .map_err(|_| format!("'{}' is not a natural number below 2^64.", n.trim()))
+    };
+    let (start, end) = if let Some((start, end)) = values.split_once("..=") {
+      (number(start)?, number(end)?)
+    } else if let Some((start, end)) = values.split_once("..") {
+      let end = number(end)?;
+      if end == 0 {
+        return Err(format!("The range of {var} is empty."));
+      }
+      (number(start)?, end - 1)
+    } else {
+      let value = number(values)?;
+      (value, value)
+    };
+    if start > end {
+      return Err(format!("The range of {var} is empty."));
+    }
+    ranges.push(Range { var, start, end });
+  }
+  Ok(ranges)
+}
+
+fn behaviour(program: &Program, input: &[(String, u64)], outputs: &[String]) -> Behaviour {
+  let input = input.iter().cloned().collect::<HashMap<_, _>>();
+  match run_with_input(&program.config, &program.prog, input) {
+    Ok(state) => Behaviour::Finished(outputs.iter().map(|v| state.get(v).copied()).collect()),
+    Err(e) if e.kind() == "StepLimitReached" => Behaviour::OutOfSteps,
+    Err(e) => Behaviour::Error(e.kind()),
+  }
+}
+
+fn describe(program: &Program, input: &[(String, u64)], outputs: &[String]) -> String {
+  let input = input.iter().cloned().collect::<HashMap<_, _>>();
+  match run_with_input(&program.config, &program.prog, input) {
+    Ok(state) => outputs
+      .iter()
+      .map(|v| match state.get(v) {
+        Some(value) => format!("{v} = {value}"),
+        None => format!("{v} is unassigned"),
+      })
+      .collect::<Vec<_>>()
+      .join(", "),
+    Err(e) if e.kind() == "StepLimitReached" => "did not finish within the step limit".to_owned(),
+    Err(e) => format!("stopped with {e:?}"),
+  }
+}
+
+/// Returns every input in the grid, ordered by sum and then by value.
+pub fn grid(ranges: &[Range]) -> Result<Vec<Vec<u64>>, String> {
+  let size = ranges.iter().try_fold(1usize, |size, r| {
+    usize::try_from(r.end - r.start)
+      .ok()
+      .and_then(|len| len.checked_add(1))
+      .and_then(|len| size.checked_mul(len))
+  });
+  match size {
+    Some(size) if size <= 1 << 26 => {}
+    _ => return Err("The input grid is too large.".to_owned()),
+  }
+  let mut inputs = vec![vec![]];
+  for range in ranges {
+    inputs = inputs
+      .into_iter()
+      .flat_map(|input| {
+        (range.start..=range.end).map(move |value| {
+          let mut input = input.clone();
+          input.push(value);
+          input
+        })
+      })
+      .collect();
+  }
+  inputs.sort_by_key(|input| {
+    (
+      input.iter().map(|&v| v as u128).sum::<u128>(),
+      input.clone(),
+    )
+  });
+  Ok(inputs)
+}
+
+/// The inputs of a grid with the names of their variables.
+fn named(ranges: &[Range], input: &[u64]) -> Vec<(String, u64)> {
+  ranges
+    .iter()
+    .zip(input)
+    .map(|(r, &v)| (r.var.clone(), v))
+    .collect()
+}
+
+/// The index in `inputs` of the first input where the programs behave differently, if any.
+fn first_difference(
+  a: &Program,
+  b: &Program,
+  ranges: &[Range],
+  inputs: &[Vec<u64>],
+  outputs: &[String],
+) -> Option<usize> {
+  // Every thread takes the next input, until one of the inputs before it is a counterexample
+  let next = AtomicUsize::new(0);
+  let first_difference = AtomicUsize::new(usize::MAX);
+  let threads = thread::available_parallelism().map_or(1, |n| n.get());
+  thread::scope(|scope| {
+    for _ in 0..threads {
+      scope.spawn(|| loop {
+        let index = next.fetch_add(1, Ordering::Relaxed);
+        if index >= inputs.len() || index > first_difference.load(Ordering::Relaxed) {
+          break;
+        }
+        let input = named(ranges, &inputs[index]);
+        if behaviour(a, &input, outputs) != behaviour(b, &input, outputs) {
+          first_difference.fetch_min(index, Ordering::Relaxed);
+        }
+      });
+    }
+  });
+  let index = first_difference.load(Ordering::Relaxed);
+  (index != usize::MAX).then_some(index)
+}
+
+/// Checks the programs on every input, printing the result. Returns whether they behaved the
+/// same everywhere.
+pub fn check(
+  a: &Program,
+  b: &Program,
+  ranges: &[Range],
+  outputs: &[String],
+) -> Result<bool, String> {
+  let inputs = grid(ranges)?;
+  let Some(index) = first_difference(a, b, ranges, &inputs, outputs) else {
+    println!(
+      "No differences found in {} inputs (comparing {}).",
+      inputs.len(),
+      outputs.join(", ")
+    );
+    return Ok(true);
+  };
+  let input = named(ranges, &inputs[index]);
+  let shown = input
+    .iter()
+    .map(|(var, value)| format!("{var}={value}"))
+    .collect::<Vec<_>>();
+  println!(
+    "The programs differ, minimal counterexample: {}",
+    shown.join(", ")
+  );
+  for program in [a, b] {
+    println!("  {}: {}", program.name, describe(program, &input, outputs));
+  }
+  Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::compile;
+
+  fn program<'a>(name: &'a str, code: &str) -> Program<'a> {
+    let mut config = Config::default();
+    let prog = compile(&mut config, code).expect("Invalid test program");
+    Program { name, config, prog }
+  }
+
+  #[test]
+  fn grid_is_ordered_by_sum_then_value() {
+    let ranges = parse_ranges("x1=0..=2, x2=0..2").unwrap();
+    assert_eq!(
+      grid(&ranges).unwrap(),
+      [[0, 0], [0, 1], [1, 0], [1, 1], [2, 0], [2, 1]]
+    );
+  }
+
+  #[test]
+  fn smallest_difference_is_found() {
+    // The sum and the product only agree on (0, 0) and (2, 2)
+    let a = program("a", "x0 := x1 + x2");
+    let b = program("b", "#extra_operators\nx0 := x1 * x2");
+    let outputs = ["x0".to_owned()];
+    let first = |ranges: &str| {
+      let ranges = parse_ranges(ranges).unwrap();
+      let inputs = grid(&ranges).unwrap();
+      first_difference(&a, &b, &ranges, &inputs, &outputs).map(|i| named(&ranges, &inputs[i]))
+    };
+    let input = |x1, x2| Some(vec![("x1".to_owned(), x1), ("x2".to_owned(), x2)]);
+    assert_eq!(first("x1=0..=50, x2=0..=50"), input(0, 1));
+    assert_eq!(first("x1=1..=50, x2=1..=50"), input(1, 1));
+    assert_eq!(first("x1=2..=50, x2=2..=50"), input(2, 3));
+    assert_eq!(first("x1=2, x2=2"), None);
+  }
+}
@@ -0,0 +1,326 @@
+use crate::symbolizer::{Operator, Span, Symbol};
+
+// Errors found while reading a program. Both kinds carry the span of the code they are about, and
+// `Diagnostic` wraps either of them for code that symbolizes and parses in one go.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LexError {
+  /// A pragma that doesn't set an option.
+  InvalidOption {
+    error: OptionError,
+    span: Span,
+  },
+  /// Something that can only be used with a config option enabled.
+  FeatureDisabled {
+    feature: String,
+    option: &'static str,
+    span: Span,
+  },
+  InvalidCharacter {
+    character: char,
+    span: Span,
+  },
+  /// A constant or variable index that doesn't fit in 64 bits.
+  NumberTooLarge {
+    span: Span,
+  },
+}
+
+/// Setting an option by name, from a pragma, whily.toml or --set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OptionError {
+  Unknown {
+    name: String,
+    suggestion: Option<String>,
+  },
+  MissingValue {
+    name: &'static str,
+  },
+  UnexpectedValue {
+    name: String,
+  },
+  InvalidValue {
+    name: &'static str,
+    value: String,
+    reason: String,
+  },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+  /// `found` is `None` at the end of the program.
+  Unexpected {
+    expected: &'static str,
+    found: Option<Symbol>,
+    span: Span,
+  },
+  /// A loop that isn't closed before the end of the program.
+  MissingOd { while_span: Span, span: Span },
+  /// Two statements in a row, the second one starting at `span`.
+  MissingSemicolon { span: Span },
+  /// An `od` without a loop to close.
+  UnmatchedOd { span: Span },
+  /// Something that can only be used with a config option enabled.
+  FeatureDisabled {
+    feature: String,
+    option: &'static str,
+    span: Span,
+  },
+  /// Something the dialect set with `#dialect` doesn't have.
+  NotInDialect {
+    feature: &'static str,
+    dialect: &'static str,
+    span: Span,
+  },
+  WrongArgumentCount {
+    function: Operator,
+    expected: usize,
+    found: usize,
+    span: Span,
+  },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Diagnostic {
+  Lex(LexError),
+  Parse(ParseError),
+}
+
+impl LexError {
+  /// The name of the variant.
+  pub fn kind(&self) -> &'static str {
+    match self {
+      Self::InvalidOption { .. } => "InvalidOption",
+      Self::FeatureDisabled { .. } => "FeatureDisabled",
+      Self::InvalidCharacter { .. } => "InvalidCharacter",
+      Self::NumberTooLarge { .. } => "NumberTooLarge",
+    }
+  }
+
+  pub fn span(&self) -> Span {
+    match self {
+      Self::InvalidOption { span, .. }
+      | Self::FeatureDisabled { span, .. }
+      | Self::InvalidCharacter { span, .. }
+      | Self::NumberTooLarge { span } => *span,
+    }
+  }
+}
+
+impl ParseError {
+  /// The name of the variant.
+  pub fn kind(&self) -> &'static str {
+    match self {
+      Self::Unexpected { .. } => "Unexpected",
+      Self::MissingOd { .. } => "MissingOd",
+      Self::MissingSemicolon { .. } => "MissingSemicolon",
+      Self::UnmatchedOd { .. } => "UnmatchedOd",
+      Self::FeatureDisabled { .. } => "FeatureDisabled",
+      Self::NotInDialect { .. } => "NotInDialect",
+      Self::WrongArgumentCount { .. } => "WrongArgumentCount",
+    }
+  }
+
+  pub fn span(&self) -> Span {
+    match self {
+      Self::Unexpected { span, .. }
+      | Self::MissingOd { span, .. }
+      | Self::MissingSemicolon { span }
+      | Self::UnmatchedOd { span }
+      | Self::FeatureDisabled { span, .. }
+      | Self::NotInDialect { span, .. }
+      | Self::WrongArgumentCount { span, .. } => *span,
+    }
+  }
+}
+
+impl Diagnostic {
+  pub fn kind(&self) -> &'static str {
+    match self {
+      Self::Lex(e) => e.kind(),
+      Self::Parse(e) => e.kind(),
+    }
+  }
+
+  /// The error without its position.
+  pub fn message(&self) -> String {
+    match self {
+      Self::Lex(e) => e.to_string(),
+      Self::Parse(e) => e.to_string(),
+    }
+  }
+
+  pub fn span(&self) -> Span {
+    match self {
+      Self::Lex(e) => e.span(),
+      Self::Parse(e) => e.span(),
+    }
+  }
+
+  /// The error along with the line of `source` it is on, with the span underlined.
+  pub fn render(&self, source: &str) -> String {
+    let span = self.span();
+    let Some(line) = source.lines().nth(span.line - 1) else {
+      return self.to_string();
+    };
+    let end = if span.end_line == span.line {
+      span.end_col
+    } else {
+      line.chars().count()
+    };
+    let indent = " ".repeat(span.col.saturating_sub(1));
+    let underline = "^".repeat((end + 1).saturating_sub(span.col).max(1));
+    format!("{self}\n{line}\n{indent}{underline}")
+  }
+}
+
+/// Renders every diagnostic, followed by how many there are if there is more than one.
+pub fn render_all(diagnostics: &[Diagnostic], source: &str) -> String {
+  let mut out = diagnostics
+    .iter()
+    .map(|d| d.render(source))
+    .collect::<Vec<_>>()
+    .join("\n\n");
+  if diagnostics.len() > 1 {
+    out += &format!("\n\nFound {} errors.", diagnostics.len());
+  }
+  out
+}
+
+/// The message of `FeatureDisabled`, which symbolizing and parsing share.
+fn feature_disabled(
+  f: &mut std::fmt::Formatter<'_>,
+  feature: &str,
+  option: &str,
+) -> std::fmt::Result {
+  write!(
+    f,
+    "Using {feature} is not allowed without '{option}' enabled."
+  )
+}
+
+impl std::fmt::Display for LexError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::InvalidOption { error, .. } => write!(f, "{error}"),
+      Self::FeatureDisabled {
+        feature, option, ..
+      } => feature_disabled(f, feature, option),
+      Self::InvalidCharacter { character, .. } => {
+        write!(
+          f,
+          "Unknown keyword or invalid variable name starting with '{character}'."
+        )
+      }
+      Self::NumberTooLarge { .. } => write!(f, "Numbers can be at most {}.", u64::MAX),
+    }
+  }
+}
+
+impl std::fmt::Display for OptionError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Unknown { name, suggestion } => {
+        write!(f, "Unknown option '{name}'.")?;
+        if let Some(suggestion) = suggestion {
+          write!(f, " Did you mean '{suggestion}'?")?;
+        }
+        write!(f, " Run `whily options` to see all options.")
+      }
+      Self::MissingValue { name } => write!(f, "The option '{name}' needs a value."),
+      Self::UnexpectedValue { name } => write!(f, "The option '{name}' doesn't take a value."),
+      Self::InvalidValue {
+        name,
+        value,
+        reason,
+      } => write!(f, "Invalid value '{value}' for '{name}': {reason}."),
+    }
+  }
+}
+
+impl std::fmt::Display for ParseError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Unexpected {
+        expected,
+        found: Some(found),
+        ..
+      } => write!(f, "Expected {expected}, found '{found:?}'."),
+      Self::Unexpected {
+        expected,
+        found: None,
+        ..
+      } => write!(f, "Expected {expected}, found the end of the program."),
+      Self::MissingOd { while_span, .. } => write!(
+        f,
+        "The loop starting at {}:{} is never closed with 'od'.",
+        while_span.line, while_span.col
+      ),
+      Self::MissingSemicolon { .. } => {
+        write!(f, "Found two statements in a row, did you miss a ; symbol?")
+      }
+      Self::UnmatchedOd { .. } => write!(f, "Found 'od' without a loop to close."),
+      Self::FeatureDisabled {
+        feature, option, ..
+      } => feature_disabled(f, feature, option),
+      Self::NotInDialect {
+        feature, dialect, ..
+      } => write!(f, "The {dialect} dialect has no {feature}."),
+      Self::WrongArgumentCount {
+        function,
+        expected,
+        found,
+        ..
+      } => write!(
+        f,
+        "'{function}' takes {expected} argument(s), but {found} were given."
+      ),
+    }
+  }
+}
+
+impl std::fmt::Display for Diagnostic {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let span = self.span();
+    match self {
+      Self::Lex(e) => write!(f, "Syntax error on line {}:{}: {e}", span.line, span.col),
+      Self::Parse(e) => write!(f, "Parse error on line {}:{}: {e}", span.line, span.col),
+    }
+  }
+}
+
+impl std::error::Error for OptionError {}
+impl std::error::Error for LexError {}
+impl std::error::Error for ParseError {}
+impl std::error::Error for Diagnostic {}
+
+impl From<LexError> for Diagnostic {
+  fn from(e: LexError) -> Self {
+    Self::Lex(e)
+  }
+}
+
+impl From<ParseError> for Diagnostic {
+  fn from(e: ParseError) -> Self {
+    Self::Parse(e)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{compile, config::Config};
+
+  #[test]
+  fn disabled_features_read_the_same() {
+    // A named variable is found while symbolizing, multiplication while parsing
+    let message = |code| compile(&mut Config::default(), code).unwrap_err()[0].to_string();
+    assert_eq!(
+      message("abc := 1"),
+      "Syntax error on line 1:1: Using the named variable 'abc' is not allowed without 'allow_named_vars' enabled."
+    );
+    assert_eq!(
+      message("x0 := x1 * x2"),
+      "Parse error on line 1:10: Using the operator '*' is not allowed without 'extra_operators' enabled."
+    );
+  }
+}
@@ -0,0 +1,307 @@
+use crate::{
+  config::{Config, Dialect},
+  parser::{Statement, Value},
+  symbolizer::Operator,
+};
+
+// Random programs, for exercises and for fuzzing. Programs are built top-down from a budget of
+// statements: every statement is an assignment or, while loops may still be nested, sometimes a
+// loop that gets part of the budget for its body. Only assignments the config allows are
+// generated, and in the whily dialect only variables that have been assigned on every path to
+// them are read, so the only runtime errors are underflows, overflows and running too long.
+//
+// Terminating programs only loop over counters: a variable the rest of the program never writes,
+// set to a constant right before the loop and decremented at the end of its body, so they also
+// take a bounded amount of steps. Counters are named after the depth of their loop, so loops next
+// to each other share one. In core WHILE, where `c := c - 1` can't be written, the decrement
+// subtracts a variable `one` that is set at the start.
+
+const MAX_CONSTANT: u64 = 9;
+const WORDS: [&str; 8] = ["a", "b", "n", "acc", "tmp", "sum", "y", "z"];
+
+/// A small pseudorandom generator (SplitMix64), so that seeds give the same programs everywhere.
+pub struct Rng(u64);
+
+impl Rng {
+  pub fn new(seed: u64) -> Self {
+    Self(seed)
+  }
+
+  pub fn next_u64(&mut self) -> u64 {
+    self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = self.0;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+  }
+
+  /// A number below `n`, which must not be 0.
+  pub fn below(&mut self, n: u64) -> u64 {
+    self.next_u64() % n
+  }
+
+  pub fn chance(&mut self, numerator: u64, denominator: u64) -> bool {
+    self.below(denominator) < numerator
+  }
+
+  pub fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+    &items[self.below(items.len() as u64) as usize]
+  }
+}
+
+pub struct Generator<'a> {
+  config: &'a Config,
+  rng: Rng,
+  // The variables assignments write to
+  variables: Vec<String>,
+  max_depth: usize,
+  terminating: bool,
+  // Whether the decrements of counters use the variable `one`
+  uses_one: bool,
+}
+
+impl<'a> Generator<'a> {
+  pub fn new(
+    config: &'a Config,
+    seed: u64,
+    variables: usize,
+    max_depth: usize,
+    terminating: bool,
+  ) -> Self {
+    let variables = (0..variables.max(1))
+      .map(|i| match (config.allow_named_vars, i) {
+        (true, i) if i > 0 && i <= WORDS.len() => WORDS[i - 1].to_owned(),
+        _ => format!("x{i}"),
+      })
+      .collect::<Vec<_>>();
+    Self {
+      config,
+      rng: Rng::new(seed),
+      max_depth,
+      terminating,
+      uses_one: false,
+      variables,
+    }
+  }
+
+  fn counter(&self, depth: usize) -> String {
+    match self.config.allow_named_vars {
+      true => format!("i{depth}"),
+      false => format!("x{}", self.variables.len() + depth),
+    }
+  }
+
+  fn one(&self) -> String {
+    match self.config.allow_named_vars {
+      true => "one".to_owned(),
+      false => format!("x{}", self.variables.len() + self.max_depth),
+    }
+  }
+
+  fn constant(&mut self) -> Value {
+    Value::Constant(self.rng.below(MAX_CONSTANT + 1))
+  }
+
+  /// A variable that can be read, or `None` if there are none.
+  fn read(&mut self, readable: &[String]) -> Option<Value> {
+    match readable.is_empty() {
+      true => None,
+      false => Some(Value::Variable(self.rng.pick(readable).clone())),
+    }
+  }
+
+  /// An operand, which is a variable unless constants are allowed everywhere.
+  fn operand(&mut self, readable: &[String]) -> Option<Value> {
+    if self.config.constants_everywhere() && (readable.is_empty() || self.rng.chance(1, 3)) {
+      return Some(self.constant());
+    }
+    self.read(readable)
+  }
+
+  fn assignment(&mut self, readable: &[String]) -> Statement {
+    let target = self.rng.pick(&self.variables).clone();
+    if self.config.dialect == Dialect::Schoening {
+      let operator = match self.rng.chance(1, 2) {
+        true => Operator::Add,
+        false => Operator::Subtract,
+      };
+      let source = self.read(readable).expect("Variables start at 0");
+      return Statement::DeclareOperation(target, source, operator, self.constant());
+    }
+    let mut operators = vec![Operator::Add, Operator::Subtract];
+    if self.config.extra_operators {
+      operators.extend([
+        Operator::Multiply,
+        Operator::Pair,
+        Operator::Fst,
+        Operator::Snd,
+        Operator::Cons,
+        Operator::Head,
+        Operator::Tail,
+      ]);
+    }
+    match self.rng.below(4) {
+      0 => Statement::DeclareConst(target, self.constant()),
+      1 if self.config.constants_everywhere() && !readable.is_empty() => {
+        let source = self.read(readable).unwrap();
+        Statement::DeclareConst(target, source)
+      }
+      _ => {
+        let operator = self.rng.pick(&operators).clone();
+        let v1 = self.operand(readable);
+        let v2 = match operator.is_unary() {
+          true => Some(Value::Constant(0)),
+          false => self.operand(readable),
+        };
+        match (v1, v2) {
+          (Some(v1), Some(v2)) => Statement::DeclareOperation(target, v1, operator, v2),
+          _ => Statement::DeclareConst(target, self.constant()),
+        }
+      }
+    }
+  }
+
+  /// Generates `budget` statements at the given depth of loops. `readable` holds the variables
+  /// that have been assigned, and gets the ones these statements always assign.
+  fn sequence(
+    &mut self,
+    mut budget: usize,
+    depth: usize,
+    readable: &mut Vec<String>,
+  ) -> Vec<Statement> {
+    let mut statements = vec![];
+    while budget > 0 {
+      // The statements a loop needs besides its body, and the least its body needs
+      let (overhead, least) = match self.terminating {
+        true if !self.config.constants_everywhere() && !self.uses_one => (4, 0),
+        true => (3, 0),
+        false => (1, 1),
+      };
+      let can_loop = depth < self.max_depth
+        && budget >= overhead + least
+        && (self.terminating || !readable.is_empty());
+      if !can_loop || !self.rng.chance(1, 4) {
+        let statement = self.assignment(readable);
+        if let Statement::DeclareOperation(v0, ..) | Statement::DeclareConst(v0, _) = &statement {
+          if !readable.contains(v0) {
+            readable.push(v0.clone());
+          }
+        }
+        statements.push(statement);
+        budget -= 1;
+        continue;
+      }
+      let body_budget = least + self.rng.below((budget - overhead - least + 1) as u64) as usize;
+      budget -= overhead + body_budget;
+      // Assignments in the body might never run, so they aren't readable after it
+      let mut inner = readable.clone();
+      if !self.terminating {
+        let cv = self.rng.pick(readable).clone();
+        let body = self.sequence(body_budget, depth + 1, &mut inner);
+        let body = Statement::from_statements(body).expect("Bodies aren't empty");
+        statements.push(Statement::While(cv, Box::new(body)));
+        continue;
+      }
+      let counter = self.counter(depth);
+      // Counters are 0 before their loop, and Schöning can only assign them with `c := c + k`
+      let init = match self.config.dialect {
+        Dialect::Schoening => {
+          let source = Value::Variable(counter.clone());
+          Statement::DeclareOperation(counter.clone(), source, Operator::Add, self.constant())
+        }
+        _ => Statement::DeclareConst(counter.clone(), self.constant()),
+      };
+      let decrement = match self.config.constants_everywhere() {
+        true => Value::Constant(1),
+        false => {
+          self.uses_one = true;
+          Value::Variable(self.one())
+        }
+      };
+      statements.push(init);
+      inner.push(counter.clone());
+      let mut body = self.sequence(body_budget, depth + 1, &mut inner);
+      body.push(Statement::DeclareOperation(
+        counter.clone(),
+        Value::Variable(counter.clone()),
+        Operator::Subtract,
+        decrement,
+      ));
+      let body = Statement::from_statements(body).expect("Bodies aren't empty");
+      statements.push(Statement::While(counter, Box::new(body)));
+    }
+    statements
+  }
+
+  /// The variables the program assigns to, besides loop counters.
+  pub fn variables(&self) -> &[String] {
+    &self.variables
+  }
+
+  /// A random program with `size` statements, counting every assignment and loop.
+  pub fn program(&mut self, size: usize) -> Statement {
+    self.program_with_inputs(size, &[])
+  }
+
+  /// Like `program`, but the program may also read `inputs` before assigning them.
+  pub fn program_with_inputs(&mut self, size: usize, inputs: &[String]) -> Statement {
+    let mut readable = match self.config.dialect.is_textbook() {
+      true => self.variables.clone(),
+      false => inputs.to_vec(),
+    };
+    let mut statements = self.sequence(size.max(1), 0, &mut readable);
+    if self.uses_one {
+      statements.insert(0, Statement::DeclareConst(self.one(), Value::Constant(1)));
+    }
+    Statement::from_statements(statements).expect("Programs aren't empty")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{compile, run::run, testing::random_config};
+
+  #[test]
+  fn programs_parse_with_their_config() {
+    let mut rng = Rng::new(47);
+    for _ in 0..300 {
+      let config = random_config(&mut rng, true);
+      let seed = rng.next_u64();
+      let mut generator = Generator::new(
+        &config,
+        seed,
+        1 + rng.below(4) as usize,
+        2,
+        rng.chance(1, 2),
+      );
+      let prog = generator.program(1 + rng.below(20) as usize);
+      // Parsing with the same options rejects anything they don't allow
+      let code = prog.to_string();
+      match compile(&mut config.clone(), &code) {
+        Ok(parsed) => assert!(parsed == prog, "Parsed differently:\n{code}"),
+        Err(_) => panic!("Seed {seed} generated invalid code for its config:\n{code}"),
+      }
+    }
+  }
+
+  #[test]
+  fn terminating_programs_only_fail_on_values() {
+    let mut rng = Rng::new(470);
+    for _ in 0..300 {
+      let config = Config {
+        max_steps: Some(1_000_000),
+        ..random_config(&mut rng, true)
+      };
+      let seed = rng.next_u64();
+      let prog = Generator::new(&config, seed, 1 + rng.below(4) as usize, 2, true)
+        .program(1 + rng.below(20) as usize);
+      if let Err(e) = run(&config, &prog) {
+        assert!(
+          matches!(e.kind(), "VariableOverflow" | "VariableUnderflow"),
+          "Seed {seed} stopped with {e:?}:\n{prog}"
+        );
+      }
+    }
+  }
+}
@@ -0,0 +1,373 @@
+use crate::{
+  parser::{Statement, Value},
+  symbolizer::Operator,
+};
+use num_bigint::BigUint;
+use num_traits::ToPrimitive;
+use std::collections::HashMap;
+
+// Gödel numbering of WHILE programs.
+//
+// Everything is built on the Cantor pairing function
+//   <a, b> = (a + b)(a + b + 1) / 2 + b
+// which is a bijection between pairs of natural numbers and the natural numbers.
+//
+// Values:     xi -> 2i              c -> 2c + 1
+// Operators:  +  -> 0    -  -> 1    *  -> 2    pair -> 3    fst  -> 4    snd  -> 5
+//             cons -> 6  head -> 7  tail -> 8
+// Statements, told apart by their remainder modulo 4:
+//   xi := a op b           -> 4 <i, <a, <op, b>>>
+//   xi := a                -> 4 <i, a> + 1
+//   while xi != 0 do P od  -> 4 <i, P> + 2
+//   P1; P2                 -> 4 <P1, P2> + 3
+// The unary operators fst, snd, head and tail always have the constant 0 as b, so numbers with
+// another b don't encode a program.
+//
+// Only programs using x-indexed variables can be encoded, so named variables are renamed
+// by `canonicalise` first.
+
+const OPERATORS: [Operator; 9] = [
+  Operator::Add,
+  Operator::Subtract,
+  Operator::Multiply,
+  Operator::Pair,
+  Operator::Fst,
+  Operator::Snd,
+  Operator::Cons,
+  Operator::Head,
+  Operator::Tail,
+];
+
+pub fn pair(a: &BigUint, b: &BigUint) -> BigUint {
+  let sum = a + b;
+  (&sum * (&sum + 1u32)) / 2u32 + b
+}
+
+pub fn unpair(z: &BigUint) -> (BigUint, BigUint) {
+  // w is the largest number with w(w + 1) / 2 <= z
+  let w = ((z * 8u32 + 1u32).sqrt() - 1u32) / 2u32;
+  let t = (&w * (&w + 1u32)) / 2u32;
+  let b = z - t;
+  let a = w - &b;
+  (a, b)
+}
+
+/// `pair` on 64 bit numbers, returning `None` if the result doesn't fit.
+pub fn pair_u64(a: u64, b: u64) -> Option<u64> {
+  let sum = a as u128 + b as u128;
+  let pair = sum.checked_mul(sum + 1)? / 2 + b as u128;
+  pair.try_into().ok()
+}
+
+pub fn unpair_u64(z: u64) -> (u64, u64) {
+  let z = z as u128;
+  let w = ((z * 8 + 1).isqrt() - 1) / 2;
+  let b = z - w * (w + 1) / 2;
+  let a = w - b;
+  (a as u64, b as u64)
+}
+
+/// Evaluates one of the function operators (see `Operator::is_function`), returning `None` if the
+/// result doesn't fit in 64 bits.
+pub fn evaluate_function(operator: &Operator, v1: u64, v2: u64) -> Option<u64> {
+  let list = |l: u64| if l == 0 { (0, 0) } else { unpair_u64(l - 1) };
+  match operator {
+    Operator::Pair => pair_u64(v1, v2),
+    Operator::Fst => Some(unpair_u64(v1).0),
+    Operator::Snd => Some(unpair_u64(v1).1),
+    Operator::Cons => pair_u64(v1, v2)?.checked_add(1),
+    Operator::Head => Some(list(v1).0),
+    Operator::Tail => Some(list(v1).1),
+    Operator::Add | Operator::Subtract | Operator::Multiply => {
+      unreachable!("{operator} is not a function")
+    }
+  }
+}
+
+/// Encodes a list of numbers, with the empty list as 0 and `h :: t` as `<h, t> + 1`.
+pub fn encode_list(values: &[BigUint]) -> BigUint {
+  values
+    .iter()
+    .rev()
+    .fold(BigUint::from(0u32), |tail, head| pair(head, &tail) + 1u32)
+}
+
+pub fn var_index(var: &str) -> Option<u64> {
+  var.strip_prefix('x')?.parse().ok()
+}
+
+/// Renames every variable that isn't of the form `xi` to the next unused `xi`, in order of first
+/// appearance. Returns the renamed program along with the renames that were made.
+pub fn canonicalise(prog: &Statement) -> (Statement, Vec<(String, String)>) {
+  fn collect<'a>(prog: &'a Statement, vars: &mut Vec<&'a String>) {
+    let mut add = |var: &'a String| {
+      if !vars.contains(&var) {
+        vars.push(var);
+      }
+    };
+    match prog {
+      Statement::S(left, right) => {
+        collect(left, vars);
+        collect(right, vars);
+      }
+      Statement::DeclareOperation(v0, v1, _, v2) => {
+        add(v0);
+        for v in [v1, v2] {
+          if let Value::Variable(var) = v {
+            add(var);
+          }
+        }
+      }
+      Statement::DeclareConst(v0, v) => {
+        add(v0);
+        if let Value::Variable(var) = v {
+          add(var);
+        }
+      }
+      Statement::While(cv, body) => {
+        add(cv);
+        collect(body, vars);
+      }
+    }
+  }
+
+  fn rename(prog: &Statement, renames: &HashMap<&String, String>) -> Statement {
+    let var = |v: &String| renames.get(v).unwrap_or(v).to_owned();
+    let value = |v: &Value| match v {
+      Value::Variable(v) => Value::Variable(var(v)),
+      Value::Constant(c) => Value::Constant(*c),
+    };
+    match prog {
+      Statement::S(left, right) => Statement::S(
+        Box::new(rename(left, renames)),
+        Box::new(rename(right, renames)),
+      ),
+      Statement::DeclareOperation(v0, v1, operator, v2) => {
+        Statement::DeclareOperation(var(v0), value(v1), operator.clone(), value(v2))
+      }
+      Statement::DeclareConst(v0, v) => Statement::DeclareConst(var(v0), value(v)),
+      Statement::While(cv, body) => Statement::While(var(cv), Box::new(rename(body, renames))),
+    }
+  }
+
+  let mut vars = vec![];
+  collect(prog, &mut vars);
+  let mut next = vars
+    .iter()
+    .filter_map(|v| var_index(v))
+    .max()
+    .map_or(0, |i| i + 1);
+  let mut renames = HashMap::new();
+  let mut renamed = vec![];
+  for var in vars {
+    if var_index(var).is_none() {
+      let new = format!("x{next}");
+      next += 1;
+      renamed.push((var.to_owned(), new.clone()));
+      renames.insert(var, new);
+    }
+  }
+  (rename(prog, &renames), renamed)
+}
+
+fn encode_value(value: &Value) -> Result<BigUint, String> {
+  match value {
+    Value::Variable(var) => Ok(BigUint::from(encode_var(var)?) * 2u32),
+    Value::Constant(c) => Ok(BigUint::from(*c) * 2u32 + 1u32),
+  }
+}
+
+fn encode_var(var: &str) -> Result<u64, String> {
+  var_index(var).ok_or_else(|| {
+    format!("Variable '{var}' is not of the form xi, canonicalise the program first.")
+  })
+}
+
+/// Computes the Gödel number of a program that only uses `xi` variables.
+pub fn encode(prog: &Statement) -> Result<BigUint, String> {
+  let (tag, code) = match prog {
+    Statement::DeclareOperation(v0, v1, operator, v2) => {
+      let operator = BigUint::from(OPERATORS.iter().position(|o| o == operator).unwrap());
+      let operation = pair(&encode_value(v1)?, &pair(&operator, &encode_value(v2)?));
+      (0u32, pair(&BigUint::from(encode_var(v0)?), &operation))
+    }
+    Statement::DeclareConst(v0, v) => (1, pair(&BigUint::from(encode_var(v0)?), &encode_value(v)?)),
+    Statement::While(cv, body) => (2, pair(&BigUint::from(encode_var(cv)?), &encode(body)?)),
+    Statement::S(left, right) => (3, pair(&encode(left)?, &encode(right)?)),
+  };
+  Ok(code * 4u32 + tag)
+}
+
+fn decode_var(n: &BigUint) -> Result<String, String> {
+  match n.to_u64() {
+    Some(i) => Ok(format!("x{i}")),
+    None => Err(format!("Variable index {n} is too large.")),
+  }
+}
+
+fn decode_value(n: &BigUint) -> Result<Value, String> {
+  let half = n / 2u32;
+  if n.bit(0) {
+    match half.to_u64() {
+      Some(c) => Ok(Value::Constant(c)),
+      None => Err(format!("Constant {half} does not fit in 64 bits.")),
+    }
+  } else {
+    Ok(Value::Variable(decode_var(&half)?))
+  }
+}
+
+/// Turns a Gödel number back into the program it encodes.
+pub fn decode(n: &BigUint) -> Result<Statement, String> {
+  let tag = (n % 4u32).to_u32().unwrap();
+  let (a, b) = unpair(&(n / 4u32));
+  Ok(match tag {
+    0 => {
+      let (v1, rest) = unpair(&b);
+      let (operator, v2) = unpair(&rest);
+      let operator = match operator.to_usize().and_then(|o| OPERATORS.get(o)) {
+        Some(operator) => operator.clone(),
+        None => return Err(format!("{operator} is not the number of an operator.")),
+      };
+      // Unary operators ignore their second value, so only one number may encode it
+      if operator.is_unary() && v2 != BigUint::from(1u32) {
+        return Err(format!(
+          "The second value of '{operator}' has to be the constant 0."
+        ));
+      }
+      Statement::DeclareOperation(
+        decode_var(&a)?,
+        decode_value(&v1)?,
+        operator,
+        decode_value(&v2)?,
+      )
+    }
+    1 => Statement::DeclareConst(decode_var(&a)?, decode_value(&b)?),
+    2 => Statement::While(decode_var(&a)?, Box::new(decode(&b)?)),
+    _ => Statement::S(Box::new(decode(&a)?), Box::new(decode(&b)?)),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    parser::{apply, assign, constant, looped, var},
+    testing::{cases, outcome, random_config},
+  };
+
+  #[test]
+  fn pairing_round_trips() {
+    for a in 0..40u64 {
+      for b in 0..40u64 {
+        let z = pair_u64(a, b).unwrap();
+        assert_eq!(unpair_u64(z), (a, b));
+        let big = pair(&BigUint::from(a), &BigUint::from(b));
+        assert_eq!(big, BigUint::from(z));
+        assert_eq!(unpair(&big), (BigUint::from(a), BigUint::from(b)));
+      }
+    }
+    assert_eq!(pair_u64(u64::MAX, 1), None);
+    let z = pair_u64(1 << 31, 1 << 31).unwrap();
+    assert_eq!(unpair_u64(z), (1 << 31, 1 << 31));
+  }
+
+  #[test]
+  fn programs_round_trip() {
+    // Every S doubles the length of the code, so the programs are kept small
+    for case in cases(28, 300, |rng| random_config(rng, false)) {
+      let statements = case.prog.clone().into_statements();
+      let prog = Statement::from_statements(statements.into_iter().take(6).collect()).unwrap();
+      let (canonical, renames) = canonicalise(&prog);
+      // Inputs the shortened program doesn't use could clash with the new names
+      let used = prog.variables();
+      let input = case
+        .input
+        .into_iter()
+        .filter(|(var, _)| used.contains(&var.as_str()))
+        .collect::<HashMap<_, _>>();
+      let code = encode(&canonical).unwrap();
+      assert_eq!(decode(&code).unwrap(), canonical, "Decoding {code} failed");
+      // Renaming changes the names in the final state, but not the values
+      let rename = |var: &String| {
+        let renamed = renames.iter().find(|(old, _)| old == var);
+        renamed.map_or(var, |(_, new)| new).clone()
+      };
+      let renamed_input = input
+        .iter()
+        .map(|(var, value)| (rename(var), *value))
+        .collect();
+      let before = outcome(&case.config, &prog, &input).map(|state| {
+        state
+          .into_iter()
+          .map(|(var, value)| (rename(&var), value))
+          .collect()
+      });
+      let before = before.map_err(|(kind, var)| (kind, var.as_ref().map(rename)));
+      assert_eq!(before, outcome(&case.config, &canonical, &renamed_input));
+    }
+  }
+
+  #[test]
+  fn numbers_round_trip() {
+    for n in 0..5000u32 {
+      let n = BigUint::from(n);
+      match decode(&n) {
+        Ok(prog) => assert_eq!(encode(&prog).unwrap(), n),
+        // Only operations can be invalid, with an unknown operator or a unary one with a second value
+        Err(_) => assert_eq!(&n % 4u32, BigUint::from(0u32), "{n} should decode"),
+      }
+    }
+  }
+
+  #[test]
+  fn known_encodings() {
+    let copy = assign("x0", var("x1"));
+    let seven = assign("x0", constant(7));
+    let programs = [
+      // 4 <0, 2> + 1
+      (copy.clone(), 21u32),
+      // 4 <0, 15> + 1
+      (seven.clone(), 541),
+      // 4 <0, <2, <0, 4>>>
+      (apply("x0", var("x1"), Operator::Add, var("x2")), 45900),
+      // 4 <1, 21> + 2
+      (looped("x1", vec![copy.clone()]), 1098),
+      // 4 <21, 541> + 3
+      (
+        Statement::from_statements(vec![copy, seven]).unwrap(),
+        634979,
+      ),
+    ];
+    for (prog, n) in programs {
+      assert_eq!(encode(&prog).unwrap(), BigUint::from(n));
+      assert!(decode(&BigUint::from(n)).unwrap() == prog);
+    }
+    // <3, <4, 0> + 1> + 1
+    let list = encode_list(&[BigUint::from(3u32), BigUint::from(4u32)]);
+    assert_eq!(list, BigUint::from(117u32));
+  }
+
+  #[test]
+  fn unary_operators_need_a_zero_operand() {
+    let code = |v2| {
+      let operation = pair(
+        &BigUint::from(2u32),
+        &pair(&BigUint::from(4u32), &BigUint::from(v2)),
+      );
+      pair(&BigUint::from(0u32), &operation) * 4u32
+    };
+    // fst(x1) with 0 and with 5 as its second value
+    assert_eq!(decode(&code(1u32)).unwrap().to_string(), "x0 := fst(x1)");
+    assert!(decode(&code(11u32)).is_err());
+  }
+
+  #[test]
+  fn named_variables_need_canonicalising() {
+    let prog = Statement::DeclareConst("a".to_owned(), Value::Constant(1));
+    assert!(encode(&prog).is_err());
+    let (canonical, renames) = canonicalise(&prog);
+    assert_eq!(renames, [("a".to_owned(), "x0".to_owned())]);
+    assert_eq!(decode(&encode(&canonical).unwrap()).unwrap(), canonical);
+  }
+}
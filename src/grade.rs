@@ -0,0 +1,430 @@
+use crate::{
+  ast::missing_options,
+  compile,
+  equiv::{grid, parse_ranges, Program},
+  error::render_all,
+  output::{error_json, json_string},
+  parser::Statement,
+  run::{run_counted, Counters},
+  Config,
+};
+use std::{
+  collections::HashMap,
+  fmt::Write,
+  time::{Duration, Instant},
+};
+
+// Grading submissions against a reference solution. Every line of the inputs file is a test like
+// `x1=3,x2=5`, and a line with ranges like `x1=0..10` gives a test for every input in the grid.
+// A test passes when the submission ends with the same values for the output variables as the
+// reference, or stops with the same kind of error. Tests the reference doesn't finish within the
+// step limit are left out of the score.
+//
+// Submissions run with limits on their steps, on the time every test takes and on their memory,
+// which is the highest total amount of bits of their variables (`space` in `whily --output json`).
+// Like the time limit, the memory limit is checked while running, so runs stop as soon as they
+// go over it.
+// The step limit is set after compiling, so pragmas can't change it. A submission conforms when it
+// only uses what the options allow and doesn't change the dialect or `allow_underflow` with
+// pragmas. It is still run with the options, but gets a score of 0 when it doesn't conform.
+
+pub struct Limits {
+  pub steps: u64,
+  // Per test
+  pub time: Option<Duration>,
+  // In bits
+  pub memory: Option<u64>,
+}
+
+#[derive(PartialEq, Eq)]
+enum Outcome {
+  Finished(Vec<(String, Option<u64>)>),
+  Error(&'static str),
+  // Which limit was reached: steps, time or memory
+  Limit(&'static str),
+}
+
+impl std::fmt::Display for Outcome {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Outcome::Finished(values) => {
+        let values = values
+          .iter()
+          .map(|(var, value)| match value {
+            Some(value) => format!("{var} = {value}"),
+            None => format!("{var} unassigned"),
+          })
+          .collect::<Vec<_>>();
+        write!(f, "{}", values.join(", "))
+      }
+      Outcome::Error(kind) => write!(f, "{kind}"),
+      Outcome::Limit(limit) => write!(f, "{limit} limit reached"),
+    }
+  }
+}
+
+impl Outcome {
+  fn json(&self) -> String {
+    match self {
+      Outcome::Finished(values) => {
+        let values = values
+          .iter()
+          .map(|(var, value)| {
+            let value = value.map_or("null".to_owned(), |v| v.to_string());
+            format!("{}: {value}", json_string(var))
+          })
+          .collect::<Vec<_>>();
+        format!(
+          "{{\"status\": \"ok\", \"state\": {{{}}}}}",
+          values.join(", ")
+        )
+      }
+      Outcome::Error(kind) => format!("{{\"status\": \"error\", \"kind\": {}}}", json_string(kind)),
+      Outcome::Limit(limit) => format!(
+        "{{\"status\": \"limit\", \"limit\": {}}}",
+        json_string(limit)
+      ),
+    }
+  }
+}
+
+struct Test {
+  input: Vec<(String, u64)>,
+  expected: Outcome,
+  // `None` if the submission doesn't parse
+  got: Option<Outcome>,
+  steps: u64,
+  time: Duration,
+}
+
+impl Test {
+  fn input(&self) -> String {
+    let input = self
+      .input
+      .iter()
+      .map(|(var, value)| format!("{var}={value}"))
+      .collect::<Vec<_>>();
+    input.join(",")
+  }
+
+  /// Whether the test counts, which it doesn't when the reference didn't finish.
+  fn valid(&self) -> bool {
+    !matches!(self.expected, Outcome::Limit(_))
+  }
+
+  fn passed(&self) -> bool {
+    self.valid() && self.got.as_ref() == Some(&self.expected)
+  }
+
+  fn result(&self) -> &'static str {
+    match (self.valid(), self.passed()) {
+      (false, _) => "skipped",
+      (_, true) => "pass",
+      _ => "fail",
+    }
+  }
+}
+
+pub struct Report {
+  submission: String,
+  reference: String,
+  // Why the submission doesn't conform, or doesn't parse
+  violations: Vec<String>,
+  // The rendered and JSON diagnostics if the submission doesn't parse
+  diagnostics: Option<(String, Vec<String>)>,
+  tests: Vec<Test>,
+}
+
+/// Reads the tests in an inputs file, see the top of this file.
+pub fn read_inputs(text: &str) -> Result<Vec<Vec<(String, u64)>>, String> {
+  let mut inputs = vec![];
+  for (i, line) in text.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let ranges = parse_ranges(line).map_err(|e| format!("On line {}: {e}", i + 1))?;
+    let vars = ranges.iter().map(|r| r.var.clone()).collect::<Vec<_>>();
+    for values in grid(&ranges).map_err(|e| format!("On line {}: {e}", i + 1))? {
+      inputs.push(vars.iter().cloned().zip(values).collect());
+    }
+  }
+  match inputs.is_empty() {
+    true => Err("The inputs file has no tests.".to_owned()),
+    false => Ok(inputs),
+  }
+}
+
+/// Why a program compiled with `compiled` (the config after its pragmas) doesn't conform to
+/// `config`.
+fn violations(config: &Config, compiled: &Config, prog: &Statement) -> Vec<String> {
+  let mut violations = missing_options(config, prog)
+    .into_iter()
+    .map(|option| format!("It needs {option}, which is not allowed."))
+    .collect::<Vec<_>>();
+  if compiled.dialect != config.dialect {
+    violations.push(format!(
+      "It switches to the {} dialect.",
+      compiled.dialect.name()
+    ));
+  }
+  if compiled.underflow_allowed() != config.underflow_allowed() {
+    violations.push("It changes allow_underflow.".to_owned());
+  }
+  violations
+}
+
+fn run(
+  config: &Config,
+  prog: &Statement,
+  input: &[(String, u64)],
+  outputs: &[String],
+  limits: &Limits,
+) -> (Outcome, u64, Duration) {
+  let start = Instant::now();
+  let mut counters = Counters {
+    deadline: limits.time.map(|time| start + time),
+    memory_limit: limits.memory,
+    ..Default::default()
+  };
+  let result = run_counted(
+    config,
+    prog,
+    input.iter().cloned().collect::<HashMap<_, _>>(),
+    &mut counters,
+  );
+  let time = start.elapsed();
+  let outcome = match result {
+    Ok(state) => Outcome::Finished(
+      outputs
+        .iter()
+        .map(|var| (var.clone(), state.get(var).copied()))
+        .collect(),
+    ),
+    Err(e) if e.kind() == "StepLimitReached" => Outcome::Limit("step"),
+    Err(e) if e.kind() == "TimeLimitReached" => Outcome::Limit("time"),
+    Err(e) if e.kind() == "MemoryLimitReached" => Outcome::Limit("memory"),
+    Err(e) => Outcome::Error(e.kind()),
+  };
+  (outcome, counters.steps, time)
+}
+
+/// Grades the submission, which doesn't have to compile, on every input.
+pub fn grade(
+  config: &Config,
+  submission: &str,
+  submission_code: &str,
+  reference: &Program,
+  inputs: &[Vec<(String, u64)>],
+  outputs: &[String],
+  limits: &Limits,
+) -> Report {
+  let mut report = Report {
+    submission: submission.to_owned(),
+    reference: reference.name.to_owned(),
+    violations: vec![],
+    diagnostics: None,
+    tests: vec![],
+  };
+  // Only the step limit applies to the reference
+  let reference_config = Config {
+    max_steps: Some(limits.steps),
+    ..reference.config.clone()
+  };
+  let reference_limits = Limits {
+    steps: limits.steps,
+    time: None,
+    memory: None,
+  };
+  let mut compiled = config.clone();
+  let prog = match compile(&mut compiled, submission_code) {
+    Ok(prog) => {
+      report.violations = violations(config, &compiled, &prog);
+      Some(prog)
+    }
+    Err(diagnostics) => {
+      report.violations.push("It doesn't parse.".to_owned());
+      let json = diagnostics
+        .iter()
+        .map(|d| error_json(d.kind(), &d.message(), None, Some(d.span())))
+        .collect();
+      report.diagnostics = Some((render_all(&diagnostics, submission_code), json));
+      None
+    }
+  };
+  let submission_config = Config {
+    max_steps: Some(limits.steps),
+    ..config.clone()
+  };
+  for input in inputs {
+    let (expected, ..) = run(
+      &reference_config,
+      &reference.prog,
+      input,
+      outputs,
+      &reference_limits,
+    );
+    let (got, steps, time) = match &prog {
+      Some(prog) => {
+        let (got, steps, time) = run(&submission_config, prog, input, outputs, limits);
+        (Some(got), steps, time)
+      }
+      None => (None, 0, Duration::ZERO),
+    };
+    report.tests.push(Test {
+      input: input.clone(),
+      expected,
+      got,
+      steps,
+      time,
+    });
+  }
+  report
+}
+
+impl Report {
+  pub fn score(&self) -> usize {
+    match self.violations.is_empty() {
+      true => self.tests.iter().filter(|t| t.passed()).count(),
+      false => 0,
+    }
+  }
+
+  pub fn total(&self) -> usize {
+    self.tests.iter().filter(|t| t.valid()).count()
+  }
+
+  pub fn markdown(&self) -> String {
+    let mut out = String::new();
+    writeln!(out, "# Grading report for {}\n", self.submission).unwrap();
+    let percentage = match self.total() {
+      0 => 0.0,
+      total => 100.0 * self.score() as f64 / total as f64,
+    };
+    writeln!(
+      out,
+      "Score: **{}/{}** ({percentage:.0}%), compared with {}.\n",
+      self.score(),
+      self.total(),
+      self.reference
+    )
+    .unwrap();
+    if self.violations.is_empty() {
+      writeln!(out, "The submission conforms to the options.\n").unwrap();
+    } else {
+      writeln!(out, "The submission gets no points:\n").unwrap();
+      for violation in &self.violations {
+        writeln!(out, "- {violation}").unwrap();
+      }
+      writeln!(out).unwrap();
+    }
+    if let Some((rendered, _)) = &self.diagnostics {
+      writeln!(out, "```\n{}\n```\n", rendered.trim()).unwrap();
+    }
+    let skipped = self.tests.len() - self.total();
+    if skipped > 0 {
+      writeln!(
+        out,
+        "{skipped} test(s) are skipped because the reference reached the step limit.\n"
+      )
+      .unwrap();
+    }
+    writeln!(
+      out,
+      "| # | Input | Expected | Got | Result | Steps | Time |"
+    )
+    .unwrap();
+    writeln!(out, "|---|---|---|---|---|---|---|").unwrap();
+    for (i, test) in self.tests.iter().enumerate() {
+      writeln!(
+        out,
+        "| {} | `{}` | {} | {} | {} | {} | {:?} |",
+        i + 1,
+        test.input(),
+        test.expected,
+        test
+          .got
+          .as_ref()
+          .map_or("not run".to_owned(), |got| got.to_string()),
+        test.result(),
+        test.steps,
+        test.time
+      )
+      .unwrap();
+    }
+    out
+  }
+
+  pub fn json(&self) -> String {
+    let strings = |items: &[String]| {
+      let items = items.iter().map(|s| json_string(s)).collect::<Vec<_>>();
+      format!("[{}]", items.join(", "))
+    };
+    let tests = self
+      .tests
+      .iter()
+      .map(|test| {
+        let input = test
+          .input
+          .iter()
+          .map(|(var, value)| format!("{}: {value}", json_string(var)))
+          .collect::<Vec<_>>();
+        format!(
+          "{{\"input\": {{{}}}, \"expected\": {}, \"got\": {}, \"result\": \"{}\", \"steps\": {}, \"seconds\": {}}}",
+          input.join(", "),
+          test.expected.json(),
+          test.got.as_ref().map_or("null".to_owned(), Outcome::json),
+          test.result(),
+          test.steps,
+          test.time.as_secs_f64()
+        )
+      })
+      .collect::<Vec<_>>();
+    let errors = self
+      .diagnostics
+      .as_ref()
+      .map_or("[]".to_owned(), |(_, json)| {
+        format!("[{}]", json.join(", "))
+      });
+    format!(
+      "{{\"submission\": {}, \"reference\": {}, \"score\": {}, \"total\": {}, \"conforms\": {}, \"violations\": {}, \"errors\": {errors}, \"tests\": [{}]}}\n",
+      json_string(&self.submission),
+      json_string(&self.reference),
+      self.score(),
+      self.total(),
+      self.violations.is_empty(),
+      strings(&self.violations),
+      tests.join(", ")
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn memory_limit_stops_the_run() {
+    let limits = Limits {
+      steps: 1_000_000,
+      time: None,
+      memory: Some(40),
+    };
+    let code = "x1 := 1; x2 := 1; while x1 != 0 do x2 := x2 + x2 od";
+    for accelerate in [true, false] {
+      let mut config = Config {
+        accelerate,
+        max_steps: Some(limits.steps),
+        allow_constants_everywhere: true,
+        ..Config::default()
+      };
+      let prog = compile(&mut config, code).unwrap();
+      let (outcome, steps, _) = run(&config, &prog, &[], &["x0".to_owned()], &limits);
+      assert!(outcome == Outcome::Limit("memory"), "Got {outcome}");
+      if !accelerate {
+        // x2 doubles every loop, so it takes 40 bits after about 80 steps
+        assert!(steps < 100, "Took {steps} steps");
+      }
+    }
+  }
+}
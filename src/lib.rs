@@ -0,0 +1,53 @@
+use crate::{
+  config::Config,
+  error::Diagnostic,
+  parser::{parse_program, statement_spans, Statement},
+  symbolizer::{symbolize_spanned, Span},
+};
+
+// The interpreter as a library, for the command line in main.rs and for the fuzz targets in
+// fuzz/.
+
+pub mod accelerate;
+pub mod analyse;
+pub mod ast;
+pub mod cfg;
+pub mod complexity;
+pub mod config;
+pub mod debugger;
+pub mod desugar;
+pub mod enumerate;
+pub mod equiv;
+pub mod error;
+pub mod gen;
+pub mod godel;
+pub mod grade;
+pub mod optimise;
+pub mod output;
+pub mod parser;
+pub mod printer;
+pub mod profile;
+pub mod run;
+pub mod symbolizer;
+pub mod symex;
+pub mod tester;
+#[cfg(test)]
+mod testing;
+pub mod universal;
+
+/// Symbolizes and parses some code, enabling the options it sets in `config`.
+pub fn compile(config: &mut Config, code: &str) -> Result<Statement, Vec<Diagnostic>> {
+  compile_spanned(config, code).map(|(parsed, _)| parsed)
+}
+
+/// Like `compile`, but also returns the spans of the statements.
+pub fn compile_spanned(
+  config: &mut Config,
+  code: &str,
+) -> Result<(Statement, Vec<Span>), Vec<Diagnostic>> {
+  let (symbols, spans) = symbolize_spanned(config, code)
+    .map_err(|errors| errors.into_iter().map(Diagnostic::from).collect::<Vec<_>>())?;
+  let parsed = parse_program(config, &symbols, &spans)
+    .map_err(|errors| errors.into_iter().map(Diagnostic::from).collect::<Vec<_>>())?;
+  Ok((parsed, statement_spans(&symbols, &spans)))
+}
@@ -1,103 +1,679 @@
+use std::collections::HashMap;
 use std::io::Read;
-use std::time::Instant;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use crate::config::cli;
-use crate::parser::parse;
-use crate::run::run;
-use crate::symbolizer::symbolize;
+use clap::ArgMatches;
 use clio::*;
-use config::Config;
-
-mod config;
-mod parser;
-mod run;
-mod symbolizer;
+use num_bigint::BigUint;
+use whily::analyse::Analyser;
+use whily::ast::{from_json, to_json};
+use whily::cfg::{Graph, GraphFormat};
+use whily::complexity::{measure, report, sample_values};
+use whily::config::Config;
+use whily::config::{cli, options_table};
+use whily::debugger::{Debugger, Machine};
+use whily::desugar::{desugar, lower};
+use whily::enumerate::{render, Enumerator};
+use whily::equiv::{check, parse_ranges, Program};
+use whily::error::render_all;
+use whily::gen::Generator;
+use whily::godel::{canonicalise, decode, encode};
+use whily::grade::{grade, read_inputs, Limits};
+use whily::optimise::optimise;
+use whily::output::{error_json, render_state, selected, state_json, Format};
+use whily::parser::Statement;
+use whily::printer::pragmas;
+use whily::profile::Profile;
+use whily::run::{run_counted, CostModel, Counters};
+use whily::symbolizer::Span;
+use whily::symex::{render_paths, Explorer};
+use whily::tester::run_tests;
+use whily::universal::{generate, validate};
+use whily::{compile, compile_spanned};
 
 // TODO: multiplication / IF f=0 then Q else R end
 
 fn main() {
   // Getting command line args and setting the config
   let mut args = cli().get_matches();
+  match args.remove_subcommand() {
+    Some((name, _)) if name == "options" => print!("{}", options_table()),
+    Some((name, mut sub_args)) if name == "run" => run_program(&mut sub_args),
+    Some((name, mut sub_args)) if name == "ast" => ast_program(&mut sub_args),
+    Some((name, mut sub_args)) if name == "opt" => optimise_program(&mut sub_args),
+    Some((name, mut sub_args)) if name == "lower" => lower_program(&mut sub_args),
+    Some((name, mut sub_args)) if name == "encode" => encode_program(&mut sub_args),
+    Some((name, sub_args)) if name == "decode" => decode_program(&sub_args),
+    Some((name, sub_args)) if name == "universal" => universal_program(&sub_args),
+    Some((name, sub_args)) if name == "gen" => gen_program(&sub_args),
+    Some((name, sub_args)) if name == "enumerate" => enumerate_programs(&sub_args),
+    Some((name, sub_args)) if name == "grade" => grade_program(&sub_args),
+    Some((name, sub_args)) if name == "test" => test_programs(&sub_args),
+    Some((name, sub_args)) if name == "equiv" => equiv_programs(&sub_args),
+    Some((name, mut sub_args)) if name == "complexity" => complexity_program(&mut sub_args),
+    Some((name, mut sub_args)) if name == "symex" => symex_program(&mut sub_args),
+    Some((name, mut sub_args)) if name == "analyse" => analyse_program(&mut sub_args),
+    Some((name, mut sub_args)) if name == "debug" => debug_program(&mut sub_args),
+    Some((name, mut sub_args)) if name == "cfg" => cfg_program(&mut sub_args),
+    _ => run_program(&mut args),
+  }
+}
+
+/// Reads, symbolizes and parses the program given as FILE, reporting any errors and exiting
+/// with code 2 if there are some. Also returns the spans of the statements, see `statement_spans`.
+fn load(args: &mut ArgMatches) -> (Config, Statement, Vec<Span>) {
+  let (code, file) = read_source(args);
+  let mut config = Config::from(args, file.as_deref()).unwrap_or_else(|e| {
+    eprintln!("{e}");
+    std::process::exit(2);
+  });
+  if reads_ast(args) {
+    return match from_json(&mut config, &code) {
+      Ok((parsed, spans)) => (config, parsed, spans),
+      Err(e) => {
+        eprintln!("{e}");
+        std::process::exit(2);
+      }
+    };
+  }
+  match compile_spanned(&mut config, &code) {
+    Ok((parsed, spans)) => (config, parsed, spans),
+    Err(e) => {
+      eprintln!("\n{}", render_all(&e, &code));
+      std::process::exit(2);
+    }
+  }
+}
+
+/// Whether FILE is a JSON syntax tree instead of WHILE code.
+fn reads_ast(args: &ArgMatches) -> bool {
+  matches!(args.try_get_one::<bool>("ast"), Ok(Some(true)))
+}
+
+/// Reads FILE, returning its code and its path, which is `None` for stdin.
+fn read_source(args: &mut ArgMatches) -> (String, Option<PathBuf>) {
   let mut path = args.remove_one::<Input>("FILE").expect("No file path");
   let mut code = String::new();
-  let read_results = path.read_to_string(&mut code);
-  match read_results {
+  if let Err(e) = path.read_to_string(&mut code) {
+    eprintln!("Could not read {path}: {e}");
+    std::process::exit(2);
+  }
+  let file = path
+    .path()
+    .is_local()
+    .then(|| path.path().path().to_owned());
+  (code, file)
+}
+
+fn ast_program(args: &mut ArgMatches) {
+  let (config, parsed, _) = load(args);
+  print!("{}", to_json(&config, &parsed));
+}
+
+fn optimise_program(args: &mut ArgMatches) {
+  let (config, parsed, _) = load(args);
+  let (optimised, statistics) = optimise(&config, parsed);
+  print!("{}", pragmas(&config));
+  println!("{optimised}");
+  eprint!("{statistics}");
+}
+
+fn encode_program(args: &mut ArgMatches) {
+  let (_, parsed, _) = load(args);
+  let (canonical, renames) = canonicalise(&parsed);
+  for (from, to) in renames {
+    eprintln!("Renamed {from} to {to}");
+  }
+  match encode(&canonical) {
+    Ok(n) => println!("{n}"),
     Err(e) => {
-      panic!("Error occurred while reading file:\n{}", e)
+      eprintln!("Could not encode the program.\n{e}");
+      std::process::exit(1);
     }
-    _ => {}
   }
+}
 
-  let mut config = Config::from(&args);
+fn decode_program(args: &ArgMatches) {
+  let number = args.get_one::<String>("NUMBER").expect("No number");
+  let Ok(n) = number.parse::<BigUint>() else {
+    eprintln!("'{number}' is not a natural number.");
+    std::process::exit(2);
+  };
+  match decode(&n) {
+    Ok(prog) => {
+      for option in prog.required_options() {
+        println!("#{option}");
+      }
+      println!("{prog}");
+    }
+    Err(e) => {
+      eprintln!("{n} does not encode a program.\n{e}");
+      std::process::exit(1);
+    }
+  }
+}
 
-  // Parsing the code
+fn universal_program(args: &ArgMatches) {
+  let vars = *args.get_one::<u64>("vars").expect("Missing arg vars");
+  let stack = *args.get_one::<u64>("stack").expect("Missing arg stack");
+  if args.get_flag("validate") {
+    if !validate(vars, stack) {
+      std::process::exit(1);
+    }
+    return;
+  }
+  print!("{}", generate(vars, stack, args.get_flag("bounded")));
+}
 
-  println!("Symbolizing and parsing program...");
-  let res = symbolize(&mut config, &code);
-  let (_, parsed) = match res {
-    Ok(k) => match parse(&config, &k, 0) {
-      Ok(k) => k,
+fn test_programs(args: &ArgMatches) {
+  let path = args.get_one::<PathBuf>("PATH").expect("No path");
+  let junit = args.get_one::<PathBuf>("junit");
+  // Invalid flags are reported once, every file then uses the whily.toml next to it
+  if let Err(e) = Config::from(args, Some(path)) {
+    eprintln!("{e}");
+    std::process::exit(2);
+  }
+  let configure = |file: &Path| Config::from(args, Some(file));
+  if !run_tests(path, junit.map(PathBuf::as_path), configure) {
+    std::process::exit(1);
+  }
+}
+
+fn equiv_programs(args: &ArgMatches) {
+  let steps = *args.get_one::<u64>("steps").expect("Missing arg steps");
+  let mut programs = vec![];
+  for arg in ["A", "B"] {
+    let path = args.get_one::<PathBuf>(arg).expect("No file path");
+    let code = match std::fs::read_to_string(path) {
+      Ok(code) => code,
+      Err(e) => {
+        eprintln!("Could not read {}: {e}", path.display());
+        std::process::exit(2);
+      }
+    };
+    let mut config = Config::from(args, Some(path)).unwrap_or_else(|e| {
+      eprintln!("{e}");
+      std::process::exit(2);
+    });
+    config.max_steps = Some(steps);
+    match compile(&mut config, &code) {
+      Ok(prog) => programs.push(Program {
+        name: path.to_str().unwrap_or(arg),
+        config,
+        prog,
+      }),
+      Err(e) => {
+        eprintln!("\nIn {}: {}", path.display(), render_all(&e, &code));
+        std::process::exit(2);
+      }
+    }
+  }
+  let inputs = args
+    .get_one::<String>("inputs")
+    .expect("Missing arg inputs");
+  let outputs = args
+    .get_one::<String>("outputs")
+    .expect("Missing arg outputs");
+  let outputs = outputs
+    .split(',')
+    .map(|v| v.trim().to_owned())
+    .filter(|v| !v.is_empty())
+    .collect::<Vec<_>>();
+  let result =
+    parse_ranges(inputs).and_then(|ranges| check(&programs[0], &programs[1], &ranges, &outputs));
+  match result {
+    Ok(true) => {}
+    Ok(false) => std::process::exit(1),
+    Err(e) => {
+      eprintln!("{e}");
+      std::process::exit(2);
+    }
+  }
+}
+
+fn grade_program(args: &ArgMatches) {
+  let read = |arg| {
+    let path = args.get_one::<PathBuf>(arg).expect("No file path");
+    match std::fs::read_to_string(path) {
+      Ok(text) => (path.display().to_string(), text),
       Err(e) => {
-        eprintln!("\nA parser error ocurred.\n{e}");
-        return;
+        eprintln!("Could not read {}: {e}", path.display());
+        std::process::exit(2);
       }
+    }
+  };
+  let submission = args.get_one::<PathBuf>("SUBMISSION").expect("No file path");
+  let config = Config::from(args, Some(submission)).unwrap_or_else(|e| {
+    eprintln!("{e}");
+    std::process::exit(2);
+  });
+  let (reference_name, reference_code) = read("reference");
+  let mut reference_config = config.clone();
+  let reference = match compile(&mut reference_config, &reference_code) {
+    Ok(prog) => Program {
+      name: &reference_name,
+      config: reference_config,
+      prog,
     },
     Err(e) => {
-      eprintln!("\nAn error ocurred.\n{e:?}");
-      return;
+      eprintln!("\nIn {reference_name}: {}", render_all(&e, &reference_code));
+      std::process::exit(2);
+    }
+  };
+  let inputs = match read_inputs(&read("inputs").1) {
+    Ok(inputs) => inputs,
+    Err(e) => {
+      eprintln!("{e}");
+      std::process::exit(2);
+    }
+  };
+  let outputs = args
+    .get_one::<String>("outputs")
+    .expect("Missing arg outputs")
+    .split(',')
+    .map(|v| v.trim().to_owned())
+    .filter(|v| !v.is_empty())
+    .collect::<Vec<_>>();
+  let limits = Limits {
+    steps: *args.get_one::<u64>("steps").expect("Missing arg steps"),
+    time: args.get_one::<Duration>("time").copied(),
+    memory: args.get_one::<u64>("memory").copied(),
+  };
+  let (submission, code) = read("SUBMISSION");
+  let report = grade(
+    &config,
+    &submission,
+    &code,
+    &reference,
+    &inputs,
+    &outputs,
+    &limits,
+  );
+  match args.get_one::<String>("format").map(String::as_str) {
+    Some("json") => print!("{}", report.json()),
+    _ => print!("{}", report.markdown()),
+  }
+  if report.score() < report.total() {
+    std::process::exit(1);
+  }
+}
+
+fn gen_program(args: &ArgMatches) {
+  let config = match Config::from(args, None) {
+    Ok(config) => config,
+    Err(e) => {
+      eprintln!("{e}");
+      std::process::exit(2);
+    }
+  };
+  let size = *args.get_one::<usize>("size").expect("Missing arg size");
+  let vars = *args.get_one::<u64>("vars").expect("Missing arg vars") as usize;
+  let max_depth = *args
+    .get_one::<usize>("max-depth")
+    .expect("Missing arg max-depth");
+  let seed = args.get_one::<u64>("seed").copied().unwrap_or_else(|| {
+    let seed = std::time::SystemTime::now()
+      .duration_since(std::time::UNIX_EPOCH)
+      .map_or(0, |time| time.as_nanos() as u64);
+    eprintln!("Seed: {seed}");
+    seed
+  });
+  let terminating = args.get_flag("terminating");
+  let prog = Generator::new(&config, seed, vars, max_depth, terminating).program(size);
+  let code = format!("{}{prog}", pragmas(&config));
+  if let Err(e) = compile(&mut Config::default(), &code) {
+    eprintln!(
+      "The generated program doesn't parse:\n{}",
+      render_all(&e, &code)
+    );
+    std::process::exit(1);
+  }
+  println!("{code}");
+}
+
+fn enumerate_programs(args: &ArgMatches) {
+  let config = match Config::from(args, None) {
+    Ok(config) => config,
+    Err(e) => {
+      eprintln!("{e}");
+      std::process::exit(2);
+    }
+  };
+  let max_size = *args
+    .get_one::<usize>("max-size")
+    .expect("Missing arg max-size");
+  let vars = *args.get_one::<u64>("vars").expect("Missing arg vars") as usize;
+  let steps = *args.get_one::<u64>("steps").expect("Missing arg steps");
+  let max_constant = *args
+    .get_one::<u64>("max-constant")
+    .expect("Missing arg max-constant");
+  let config = Config {
+    max_steps: Some(steps),
+    ..config
+  };
+  let enumerator = Enumerator::new(&config, vars, max_constant);
+  let reports = (1..=max_size)
+    .map(|size| enumerator.run_size(size))
+    .collect::<Vec<_>>();
+  print!("{}", render(&reports));
+}
+
+fn complexity_program(args: &mut ArgMatches) {
+  let vary = args.get_one::<String>("vary").expect("Missing arg vary");
+  let inputs = args
+    .get_one::<String>("inputs")
+    .expect("Missing arg inputs");
+  let samples = *args.get_one::<u64>("samples").expect("Missing arg samples");
+  let steps = *args.get_one::<u64>("steps").expect("Missing arg steps");
+  let (range, fixed) = match (parse_ranges(vary), parse_ranges(inputs)) {
+    (Ok(mut vary), Ok(inputs)) if vary.len() == 1 => {
+      let range = vary.remove(0);
+      if inputs.iter().any(|r| r.start != r.end) {
+        eprintln!("Only one input can be varied, give single values with --inputs.");
+        std::process::exit(2);
+      }
+      let fixed = inputs
+        .into_iter()
+        .map(|r| (r.var, r.start))
+        .collect::<Vec<_>>();
+      (range, fixed)
+    }
+    (Ok(_), Ok(_)) => {
+      eprintln!("--vary takes exactly one input, like x1=1..1000.");
+      std::process::exit(2);
+    }
+    (Err(e), _) | (_, Err(e)) => {
+      eprintln!("{e}");
+      std::process::exit(2);
+    }
+  };
+  let (mut config, parsed, _) = load(args);
+  config.max_steps = Some(steps);
+  let values = sample_values(&range, samples);
+  let measurements = measure(&config, &parsed, &range.var, &values, &fixed);
+  print!("{}", report(&range.var, &measurements));
+}
+
+fn symex_program(args: &mut ArgMatches) {
+  let symbolic = args
+    .get_one::<String>("symbolic")
+    .expect("Missing arg symbolic")
+    .split(',')
+    .map(|var| var.trim().to_owned())
+    .filter(|var| !var.is_empty())
+    .collect::<Vec<_>>();
+  let unroll = *args.get_one::<u64>("unroll").expect("Missing arg unroll");
+  let bound = *args.get_one::<u64>("bound").expect("Missing arg bound");
+  let (config, parsed, spans) = load(args);
+  let explorer = Explorer::new(&config, &parsed, &spans, unroll, bound);
+  let (paths, truncated) = explorer.explore(&parsed, &symbolic);
+  print!("{}", render_paths(&paths, truncated));
+}
+
+fn analyse_program(args: &mut ArgMatches) {
+  let inputs = args
+    .get_one::<String>("inputs")
+    .expect("Missing arg inputs");
+  let inputs = match parse_ranges(inputs) {
+    Ok(inputs) => inputs,
+    Err(e) => {
+      eprintln!("{e}");
+      std::process::exit(2);
+    }
+  };
+  let (config, parsed, spans) = load(args);
+  let mut analyser = Analyser::new(&config);
+  let end = analyser.analyse(&parsed, &inputs);
+  let (report, failing) = analyser.report(&parsed, &spans, &end);
+  print!("{report}");
+  if failing {
+    std::process::exit(1);
+  }
+}
+
+/// The values given with --inputs, exiting if they aren't single values.
+fn input_values(args: &ArgMatches) -> HashMap<String, u64> {
+  let inputs = args
+    .get_one::<String>("inputs")
+    .expect("Missing arg inputs");
+  match parse_ranges(inputs) {
+    Ok(inputs) if inputs.iter().all(|r| r.start == r.end) => {
+      inputs.into_iter().map(|r| (r.var, r.start)).collect()
+    }
+    Ok(_) => {
+      eprintln!("Give single values with --inputs, like x1=5.");
+      std::process::exit(2);
+    }
+    Err(e) => {
+      eprintln!("{e}");
+      std::process::exit(2);
+    }
+  }
+}
+
+fn debug_program(args: &mut ArgMatches) {
+  let input = input_values(args);
+  let (config, parsed, spans) = load(args);
+  let machine = Machine::new(&config, &parsed, input);
+  Debugger::new(machine, &parsed, &spans).session(std::io::stdin().lock());
+}
+
+fn cfg_program(args: &mut ArgMatches) {
+  let input = input_values(args);
+  let format = match args.get_one::<String>("format").map(String::as_str) {
+    Some("mermaid") => GraphFormat::Mermaid,
+    _ => GraphFormat::Dot,
+  };
+  let counts = args.get_flag("counts");
+  let (config, parsed, _) = load(args);
+  let mut counters = Counters {
+    profile: counts.then(|| Profile::new(&parsed)),
+    ..Default::default()
+  };
+  if counts {
+    if let Err(e) = run_counted(&config, &parsed, input, &mut counters) {
+      eprintln!("The run stopped early with a runtime error: {e:?}");
     }
+  }
+  print!(
+    "{}",
+    Graph::new(&parsed).render(format, counters.profile.as_ref())
+  );
+}
+
+fn desugar_program(args: &mut ArgMatches) {
+  let (config, parsed, _) = load(args);
+  let config = Config {
+    extra_operators: false,
+    ..config
   };
-  println!("Done!");
+  print!("{}", pragmas(&config));
+  println!("{}", desugar(parsed));
+}
+
+fn lower_program(args: &mut ArgMatches) {
+  let (config, parsed, _) = load(args);
+  let (canonical, renames) = canonicalise(&parsed);
+  for (from, to) in renames {
+    eprintln!("Renamed {from} to {to}");
+  }
+  let lowered = lower(canonical, config.underflow_allowed()).to_string();
+  if let Err(e) = compile(&mut Config::default(), &lowered) {
+    eprintln!(
+      "The lowered program isn't core WHILE:\n{}",
+      render_all(&e, &lowered)
+    );
+    std::process::exit(1);
+  }
+  println!("{lowered}");
+}
+
+fn run_program(args: &mut ArgMatches) {
+  if args.get_flag("desugar") {
+    desugar_program(args);
+    return;
+  }
+  let format = Format::from_name(
+    args
+      .get_one::<String>("output")
+      .expect("Missing arg output"),
+  );
+  let print = args.get_one::<String>("print").map(|vars| {
+    vars
+      .split(',')
+      .map(|v| v.trim().to_owned())
+      .collect::<Vec<_>>()
+  });
+  let verbose = args.get_flag("verbose");
+  // Everything but the final state goes to stderr unless it is meant for people
+  let report = |text: &str| match format {
+    Format::Pretty => println!("{text}"),
+    _ => eprintln!("{text}"),
+  };
+  let errors_json = |errors: Vec<String>| {
+    println!(
+      "{{\"status\": \"error\", \"errors\": [{}]}}",
+      errors.join(", ")
+    )
+  };
+
+  // Parsing the code
+
+  if verbose {
+    eprintln!("Symbolizing and parsing program...");
+  }
+  let (code, file) = read_source(args);
+  let mut config = match Config::from(args, file.as_deref()) {
+    Ok(config) => config,
+    Err(e) => {
+      match format {
+        Format::Json => errors_json(vec![error_json("InvalidConfig", &e, None, None)]),
+        _ => eprintln!("{e}"),
+      }
+      std::process::exit(2);
+    }
+  };
+  let compiled = if reads_ast(args) {
+    match from_json(&mut config, &code) {
+      Ok(compiled) => Ok(compiled),
+      Err(e) => {
+        match format {
+          Format::Json => errors_json(vec![error_json(
+            "InvalidAst",
+            &e.message,
+            None,
+            Some(e.span),
+          )]),
+          _ => eprintln!("{e}"),
+        }
+        std::process::exit(2);
+      }
+    }
+  } else {
+    compile_spanned(&mut config, &code)
+  };
+  let (parsed, spans) = match compiled {
+    Ok(compiled) => compiled,
+    Err(diagnostics) => {
+      match format {
+        Format::Json => errors_json(
+          diagnostics
+            .iter()
+            .map(|d| error_json(d.kind(), &d.message(), None, Some(d.span())))
+            .collect(),
+        ),
+        _ => eprintln!("\n{}", render_all(&diagnostics, &code)),
+      }
+      std::process::exit(2);
+    }
+  };
+  if verbose {
+    eprintln!("Done!\n\nRunning program...");
+  }
 
   // Running the code
 
-  println!("\nRunning program...");
+  let folded = args.get_one::<PathBuf>("folded");
+  let cost_model = match args.get_one::<String>("cost").map(String::as_str) {
+    Some("log") => CostModel::Log,
+    _ => CostModel::Uniform,
+  };
+  let cost_name = match cost_model {
+    CostModel::Uniform => "uniform",
+    CostModel::Log => "logarithmic",
+  };
+  let mut counters = Counters {
+    cost_model,
+    profile: (args.get_flag("profile") || folded.is_some()).then(|| Profile::new(&parsed)),
+    ..Default::default()
+  };
   let start = Instant::now();
+  let result = run_counted(&config, &parsed, HashMap::new(), &mut counters);
+  let elapsed = start.elapsed();
 
-  match run(&config, &parsed) {
+  let failed = result.is_err();
+  match result {
     Ok(state) => {
-      let elapsed = start.elapsed();
-      println!("Success! (time: {:?})\n\nFinished state:", elapsed);
-      let max_chars = match state.keys().map(|s| s.chars().count()).max() {
-        Some(max_k) => max_k,
-        None => {
-          println!("No variables used.");
-          return;
-        }
-      };
-      let mut keys = state.keys().collect::<Vec<_>>();
-
-      // This complex sorting function is just there so that x2 will be before x12
-      keys.sort_by(|a, b| {
-        let mut a_number = None;
-        let mut b_number = None;
-        if a.starts_with("x") {
-          let number = a.chars().skip(1).collect::<String>();
-          let res = u64::from_str_radix(&number, 10);
-          if res.is_ok() {
-            a_number = Some(res.unwrap());
-          }
+      let values = selected(&state, print.as_deref());
+      match format {
+        Format::Json => println!(
+          "{{\"status\": \"ok\", \"state\": {}, \"steps\": {}, \"cost_model\": \"{cost_name}\", \"cost\": {}, \"space\": {}, \"seconds\": {}}}",
+          state_json(&values),
+          counters.steps,
+          counters.cost,
+          counters.max_space,
+          elapsed.as_secs_f64()
+        ),
+        Format::Pretty => {
+          println!(
+            "Success! (time: {:?}, steps: {}, {cost_name} cost: {}, space: {} bits)\n\nFinished state:",
+            elapsed, counters.steps, counters.cost, counters.max_space
+          );
+          print!("{}", render_state(format, &values));
         }
-        if b.starts_with("x") {
-          let number = b.chars().skip(1).collect::<String>();
-          let res = u64::from_str_radix(&number, 10);
-          if res.is_ok() {
-            b_number = Some(res.unwrap());
+        _ => {
+          if verbose {
+            eprintln!(
+              "Success! (time: {:?}, steps: {}, {cost_name} cost: {}, space: {} bits)",
+              elapsed, counters.steps, counters.cost, counters.max_space
+            );
           }
+          print!("{}", render_state(format, &values));
         }
-        match (a_number, b_number) {
-          (None, None) => a.partial_cmp(b).unwrap(),
-          (None, Some(_)) => std::cmp::Ordering::Less,
-          (Some(_), None) => std::cmp::Ordering::Greater,
-          (Some(xa), Some(xb)) => xa.partial_cmp(&xb).unwrap(),
+      }
+    }
+    Err(e) => {
+      let span = counters.failed_at.and_then(|id| spans.get(id).copied());
+      match format {
+        Format::Json => errors_json(vec![error_json(
+          e.kind(),
+          &format!("{e:?}"),
+          e.variable(),
+          span,
+        )]),
+        _ => {
+          let location = span.map_or(String::new(), |span| format!(" (at {span})"));
+          report(&format!("A runtime error occurred: {e:?}{location}"));
         }
-      });
-
-      for key in keys {
-        let pad = " ".repeat(max_chars - key.len());
-        println!("{key}{pad} = {}", state.get(key).unwrap());
       }
     }
-    Err(e) => println!("A runtime error occurred: {e:?}"),
   };
+
+  if let Some(profile) = counters.profile {
+    report(&format!("\nProfile ({} steps):", counters.steps));
+    report(
+      profile
+        .hot_spots(&parsed, &spans, counters.steps)
+        .trim_end(),
+    );
+    if let Some(path) = folded {
+      if let Err(e) = std::fs::write(path, profile.folded_stacks(&parsed, &spans)) {
+        eprintln!("Could not write {}: {e}", path.display());
+      }
+    }
+  }
+  if failed {
+    std::process::exit(1);
+  }
 }
@@ -0,0 +1,636 @@
+use crate::{
+  config::Config,
+  godel::evaluate_function,
+  parser::{Statement, Value},
+  symbolizer::Operator,
+};
+use std::collections::{HashMap, HashSet};
+
+// The optimiser runs a fixed list of passes over the program until none of them change anything.
+// Every pass preserves the observable behaviour of the program: the final state when it succeeds,
+// and the kind of error (and the variable it is about) when it fails. Since the final state
+// contains every variable that was ever assigned, assignments can only be removed when the same
+// variable is assigned again later.
+
+const MAX_ROUNDS: usize = 16;
+
+type Block = Vec<Statement>;
+// Variables whose value is known at a certain point in the program
+type Constants = HashMap<String, u64>;
+
+pub struct Pass {
+  pub name: &'static str,
+  run: fn(&Config, Block) -> (Block, usize),
+}
+
+pub const PASSES: [Pass; 5] = [
+  Pass {
+    name: "constant propagation and folding",
+    run: fold_constants,
+  },
+  Pass {
+    name: "copy propagation",
+    run: propagate_copies,
+  },
+  Pass {
+    name: "dead loop removal",
+    run: remove_dead_loops,
+  },
+  Pass {
+    name: "loop-invariant code motion",
+    run: hoist_invariants,
+  },
+  Pass {
+    name: "dead store elimination",
+    run: eliminate_dead_stores,
+  },
+];
+
+pub struct Statistics {
+  pub rounds: usize,
+  pub statements_before: usize,
+  pub statements_after: usize,
+  /// The amount of changes made by each pass, in the order of `PASSES`
+  pub changes: Vec<(&'static str, usize)>,
+}
+
+impl std::fmt::Display for Statistics {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    writeln!(
+      f,
+      "Optimised in {} round(s), {} -> {} statements",
+      self.rounds, self.statements_before, self.statements_after
+    )?;
+    let max_chars = self.changes.iter().map(|(n, _)| n.len()).max().unwrap_or(0);
+    for (name, changes) in &self.changes {
+      let pad = " ".repeat(max_chars - name.len());
+      writeln!(f, "  {name}{pad} : {changes}")?;
+    }
+    Ok(())
+  }
+}
+
+pub fn optimise(config: &Config, prog: Statement) -> (Statement, Statistics) {
+  let statements_before = count_statements(&prog);
+  let mut block = prog.into_statements();
+  let mut changes = PASSES.iter().map(|p| (p.name, 0)).collect::<Vec<_>>();
+  let mut rounds = 0;
+  while rounds < MAX_ROUNDS {
+    rounds += 1;
+    let mut changed = false;
+    for (i, pass) in PASSES.iter().enumerate() {
+      let (new_block, n) = (pass.run)(config, block);
+      block = new_block;
+      changes[i].1 += n;
+      changed |= n > 0;
+    }
+    if !changed {
+      break;
+    }
+  }
+  let prog = Statement::from_statements(block).expect("Optimising removed every statement");
+  let statistics = Statistics {
+    rounds,
+    statements_before,
+    statements_after: count_statements(&prog),
+    changes,
+  };
+  (prog, statistics)
+}
+
+fn count_statements(prog: &Statement) -> usize {
+  match prog {
+    Statement::S(left, right) => count_statements(left) + count_statements(right),
+    Statement::While(_, body) => 1 + count_statements(body),
+    _ => 1,
+  }
+}
+
+fn body_of(statements: Block) -> Box<Statement> {
+  Box::new(Statement::from_statements(statements).expect("Loop body has no statements"))
+}
+
+/// The variable assigned by a single assignment.
+fn target(statement: &Statement) -> Option<&String> {
+  match statement {
+    Statement::DeclareOperation(v0, ..) | Statement::DeclareConst(v0, _) => Some(v0),
+    _ => None,
+  }
+}
+
+fn assigned_vars<'a>(statement: &'a Statement, out: &mut Vec<&'a String>) {
+  match statement {
+    Statement::S(left, right) => {
+      assigned_vars(left, out);
+      assigned_vars(right, out);
+    }
+    Statement::While(_, body) => assigned_vars(body, out),
+    s => out.extend(target(s)),
+  }
+}
+
+fn read_vars<'a>(statement: &'a Statement, out: &mut HashSet<&'a String>) {
+  let mut read_value = |v: &'a Value| {
+    if let Value::Variable(var) = v {
+      out.insert(var);
+    }
+  };
+  match statement {
+    Statement::S(left, right) => {
+      read_vars(left, out);
+      read_vars(right, out);
+    }
+    Statement::DeclareOperation(_, v1, _, v2) => {
+      read_value(v1);
+      read_value(v2);
+    }
+    Statement::DeclareConst(_, v) => read_value(v),
+    Statement::While(cv, body) => {
+      out.insert(cv);
+      read_vars(body, out);
+    }
+  }
+}
+
+fn reads(statement: &Statement, var: &String) -> bool {
+  let mut read = HashSet::new();
+  read_vars(statement, &mut read);
+  read.contains(var)
+}
+
+/// Computes `v1 operator v2` the way `run` does, returning `None` if that would be an error.
+fn evaluate(config: &Config, v1: u64, operator: &Operator, v2: u64) -> Option<u64> {
+  match operator {
+    Operator::Add => v1.checked_add(v2),
+    Operator::Subtract if config.underflow_allowed() => Some(v1.saturating_sub(v2)),
+    Operator::Subtract => v1.checked_sub(v2),
+    Operator::Multiply => v1.checked_mul(v2),
+    _ => evaluate_function(operator, v1, v2),
+  }
+}
+
+fn known(constants: &Constants, value: &Value) -> Option<u64> {
+  match value {
+    Value::Constant(c) => Some(*c),
+    Value::Variable(var) => constants.get(var).copied(),
+  }
+}
+
+/// Whether running the assignment can never produce a runtime error.
+fn cannot_error(config: &Config, statement: &Statement) -> bool {
+  let none = Constants::new();
+  match statement {
+    Statement::DeclareConst(_, Value::Constant(_)) => true,
+    Statement::DeclareOperation(_, v1, operator, v2) => {
+      matches!((known(&none, v1), known(&none, v2)), (Some(a), Some(b)) if evaluate(config, a, operator, b).is_some())
+    }
+    _ => false,
+  }
+}
+
+/// Updates the known constants after running a single assignment.
+fn transfer(config: &Config, statement: &Statement, constants: &mut Constants) {
+  let (var, value) = match statement {
+    Statement::DeclareConst(v0, v) => (v0, known(constants, v)),
+    Statement::DeclareOperation(v0, v1, operator, v2) => {
+      let value = match (known(constants, v1), known(constants, v2)) {
+        (Some(a), Some(b)) => evaluate(config, a, operator, b),
+        _ => None,
+      };
+      (v0, value)
+    }
+    _ => return,
+  };
+  match value {
+    Some(value) => constants.insert(var.to_owned(), value),
+    None => constants.remove(var),
+  };
+}
+
+/// Forgets everything the loop body might change, which gives the constants known at the start of
+/// every iteration. After the loop the same holds, and the loop variable is known to be 0.
+fn enter_loop(body: &Statement, constants: &mut Constants) {
+  let mut assigned = vec![];
+  assigned_vars(body, &mut assigned);
+  for var in assigned {
+    constants.remove(var);
+  }
+}
+
+fn exit_loop(cv: &str, body: &Statement, constants: &mut Constants) {
+  enter_loop(body, constants);
+  constants.insert(cv.to_owned(), 0);
+}
+
+// Constant propagation and folding: operations on known values are replaced by their result, and
+// known variables are replaced by constants where `allow_constants_everywhere` permits it.
+
+fn fold_constants(config: &Config, block: Block) -> (Block, usize) {
+  let mut changes = 0;
+  let block = fold_block(config, block, &mut Constants::new(), &mut changes);
+  (block, changes)
+}
+
+fn fold_block(
+  config: &Config,
+  block: Block,
+  constants: &mut Constants,
+  changes: &mut usize,
+) -> Block {
+  block
+    .into_iter()
+    .map(|s| fold_statement(config, s, constants, changes))
+    .collect()
+}
+
+fn fold_statement(
+  config: &Config,
+  statement: Statement,
+  constants: &mut Constants,
+  changes: &mut usize,
+) -> Statement {
+  let statement = match statement {
+    Statement::While(cv, body) => {
+      let mut inner = constants.clone();
+      enter_loop(&body, &mut inner);
+      let body = fold_block(config, body.into_statements(), &mut inner, changes);
+      let body = body_of(body);
+      exit_loop(&cv, &body, constants);
+      return Statement::While(cv, body);
+    }
+    Statement::DeclareConst(v0, Value::Variable(var)) if constants.contains_key(&var) => {
+      *changes += 1;
+      Statement::DeclareConst(v0, Value::Constant(constants[&var]))
+    }
+    Statement::DeclareOperation(v0, v1, operator, v2) => {
+      let result = match (known(constants, &v1), known(constants, &v2)) {
+        (Some(a), Some(b)) => evaluate(config, a, &operator, b),
+        _ => None,
+      };
+      let substitute = |v: Value, changes: &mut usize| match v {
+        Value::Variable(var) if config.constants_everywhere() && constants.contains_key(&var) => {
+          *changes += 1;
+          Value::Constant(constants[&var])
+        }
+        v => v,
+      };
+      match result {
+        Some(result) => {
+          *changes += 1;
+          Statement::DeclareConst(v0, Value::Constant(result))
+        }
+        None => {
+          let v1 = substitute(v1, changes);
+          let v2 = substitute(v2, changes);
+          Statement::DeclareOperation(v0, v1, operator, v2)
+        }
+      }
+    }
+    s => s,
+  };
+  transfer(config, &statement, constants);
+  statement
+}
+
+// Copy propagation: after `y := x`, reads of `y` are replaced by reads of `x` for as long as
+// neither of them is reassigned.
+
+// Maps a variable to the variable it is a copy of
+type Copies = HashMap<String, String>;
+
+fn propagate_copies(_config: &Config, block: Block) -> (Block, usize) {
+  let mut changes = 0;
+  let block = copy_block(block, &mut Copies::new(), &mut changes);
+  (block, changes)
+}
+
+fn kill_copies(var: &String, copies: &mut Copies) {
+  copies.retain(|copy, original| copy != var && original != var);
+}
+
+fn kill_loop_copies(body: &Statement, copies: &mut Copies) {
+  let mut assigned = vec![];
+  assigned_vars(body, &mut assigned);
+  for var in assigned {
+    kill_copies(var, copies);
+  }
+}
+
+fn copy_block(block: Block, copies: &mut Copies, changes: &mut usize) -> Block {
+  block
+    .into_iter()
+    .map(|s| copy_statement(s, copies, changes))
+    .collect()
+}
+
+fn copy_statement(statement: Statement, copies: &mut Copies, changes: &mut usize) -> Statement {
+  let substitute = |v: Value, changes: &mut usize| match v {
+    Value::Variable(var) if copies.contains_key(&var) => {
+      *changes += 1;
+      Value::Variable(copies[&var].to_owned())
+    }
+    v => v,
+  };
+  let statement = match statement {
+    Statement::While(cv, body) => {
+      let mut inner = copies.clone();
+      kill_loop_copies(&body, &mut inner);
+      let body = body_of(copy_block(body.into_statements(), &mut inner, changes));
+      kill_loop_copies(&body, copies);
+      return Statement::While(cv, body);
+    }
+    Statement::DeclareOperation(v0, v1, operator, v2) => {
+      let v1 = substitute(v1, changes);
+      let v2 = substitute(v2, changes);
+      Statement::DeclareOperation(v0, v1, operator, v2)
+    }
+    Statement::DeclareConst(v0, v) => Statement::DeclareConst(v0, substitute(v, changes)),
+    s => s,
+  };
+  if let Some(var) = target(&statement) {
+    kill_copies(var, copies);
+  }
+  if let Statement::DeclareConst(v0, Value::Variable(original)) = &statement {
+    if v0 != original {
+      copies.insert(v0.to_owned(), original.to_owned());
+    }
+  }
+  statement
+}
+
+// Dead loop removal: loops whose variable is known to be 0 never run their body.
+
+fn remove_dead_loops(config: &Config, block: Block) -> (Block, usize) {
+  let mut changes = 0;
+  let block = dead_loop_block(config, block, &mut Constants::new(), &mut changes);
+  (block, changes)
+}
+
+fn dead_loop_block(
+  config: &Config,
+  block: Block,
+  constants: &mut Constants,
+  changes: &mut usize,
+) -> Block {
+  let mut out = vec![];
+  let mut removed = vec![];
+  for statement in block {
+    match statement {
+      Statement::While(ref cv, _) if constants.get(cv) == Some(&0) => {
+        removed.push(statement);
+        *changes += 1;
+      }
+      Statement::While(cv, body) => {
+        let mut inner = constants.clone();
+        enter_loop(&body, &mut inner);
+        let body = body_of(dead_loop_block(
+          config,
+          body.into_statements(),
+          &mut inner,
+          changes,
+        ));
+        exit_loop(&cv, &body, constants);
+        out.push(Statement::While(cv, body));
+      }
+      s => {
+        transfer(config, &s, constants);
+        out.push(s);
+      }
+    }
+  }
+  // A block can't be empty, so keep one of the (harmless) loops around
+  if out.is_empty() && !removed.is_empty() {
+    out.push(removed.remove(0));
+    *changes -= 1;
+  }
+  out
+}
+
+// Loop-invariant code motion: assignments inside a loop that compute the same value in every
+// iteration are moved in front of the loop. Because the final state would show the variable even
+// if the loop never ran, this is only done for loops known to run at least once.
+
+fn hoist_invariants(config: &Config, block: Block) -> (Block, usize) {
+  let mut changes = 0;
+  let block = hoist_block(config, block, &mut Constants::new(), &mut changes);
+  (block, changes)
+}
+
+fn hoist_block(
+  config: &Config,
+  block: Block,
+  constants: &mut Constants,
+  changes: &mut usize,
+) -> Block {
+  let mut out = vec![];
+  for statement in block {
+    match statement {
+      Statement::While(cv, body) => {
+        let runs = matches!(constants.get(&cv), Some(v) if *v != 0);
+        let mut inner = constants.clone();
+        enter_loop(&body, &mut inner);
+        let mut body = hoist_block(config, body.into_statements(), &mut inner, changes);
+        if runs {
+          for hoisted in hoist_from_body(config, &cv, &mut body) {
+            transfer(config, &hoisted, constants);
+            out.push(hoisted);
+            *changes += 1;
+          }
+        }
+        let body = body_of(body);
+        exit_loop(&cv, &body, constants);
+        out.push(Statement::While(cv, body));
+      }
+      s => {
+        transfer(config, &s, constants);
+        out.push(s);
+      }
+    }
+  }
+  out
+}
+
+fn hoist_from_body(config: &Config, cv: &str, body: &mut Block) -> Vec<Statement> {
+  let whole = Statement::from_statements(body.clone()).expect("Loop body has no statements");
+  let mut assigned = vec![];
+  assigned_vars(&whole, &mut assigned);
+
+  let mut hoisted = vec![];
+  let mut kept: Block = vec![];
+  for statement in std::mem::take(body) {
+    let invariant = match target(&statement) {
+      Some(var) => {
+        let mut read = HashSet::new();
+        read_vars(&statement, &mut read);
+        var != cv
+          && assigned.iter().filter(|v| **v == var).count() == 1
+          && !read.iter().any(|r| assigned.contains(r))
+          && !kept.iter().any(|s| reads(s, var))
+          // Errors have to happen at the same point, which is only the case if nothing before it stays
+          && (kept.is_empty() || cannot_error(config, &statement))
+      }
+      None => false,
+    };
+    if invariant {
+      hoisted.push(statement);
+    } else {
+      kept.push(statement);
+    }
+  }
+  // Don't leave an empty body behind
+  if kept.is_empty() {
+    kept.push(hoisted.pop().expect("Loop body has no statements"));
+  }
+  *body = kept;
+  hoisted
+}
+
+// Dead store elimination: an assignment is removed when the variable is assigned again later in
+// the same block without being read in between. The removed assignment must not be able to
+// produce an error itself.
+
+fn eliminate_dead_stores(config: &Config, block: Block) -> (Block, usize) {
+  let mut changes = 0;
+  let block = dead_store_block(config, block, &mut changes);
+  (block, changes)
+}
+
+fn dead_store_block(config: &Config, block: Block, changes: &mut usize) -> Block {
+  let block = block
+    .into_iter()
+    .map(|s| match s {
+      Statement::While(cv, body) => Statement::While(
+        cv,
+        body_of(dead_store_block(config, body.into_statements(), changes)),
+      ),
+      s => s,
+    })
+    .collect::<Block>();
+
+  let mut dead = vec![false; block.len()];
+  for (i, statement) in block.iter().enumerate() {
+    let Some(var) = target(statement) else {
+      continue;
+    };
+    if !cannot_error(config, statement) {
+      continue;
+    }
+    for later in &block[i + 1..] {
+      if reads(later, var) {
+        break;
+      }
+      if target(later) == Some(var) {
+        dead[i] = true;
+        *changes += 1;
+        break;
+      }
+    }
+  }
+  block
+    .into_iter()
+    .zip(dead)
+    .filter_map(|(s, dead)| if dead { None } else { Some(s) })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    compile,
+    testing::{cases, outcome, random_config},
+  };
+
+  /// The code after running one pass over it once, with how many changes it made.
+  fn run_pass(pass: fn(&Config, Block) -> (Block, usize), code: &str) -> (String, usize) {
+    let mut config = Config::default();
+    let code = format!("#allow_constants_everywhere\n{code}");
+    let prog = compile(&mut config, &code).expect("Invalid test program");
+    let (block, changes) = pass(&config, prog.into_statements());
+    let prog = Statement::from_statements(block).unwrap();
+    (prog.to_string(), changes)
+  }
+
+  #[test]
+  fn folds_constants() {
+    assert_eq!(
+      run_pass(fold_constants, "x1 := 2;\nx2 := x1 + 3;\nx0 := x2 + x3"),
+      ("x1 := 2;\nx2 := 5;\nx0 := 5 + x3".to_owned(), 2)
+    );
+    // The loop may change x1, and leaves x2 at 0
+    let code = "x1 := 2;\nwhile x2 != 0 do x1 := x1 + 1 od;\nx0 := x1 + x2";
+    assert_eq!(
+      run_pass(fold_constants, code),
+      (
+        "x1 := 2;\nwhile x2 != 0 do\n  x1 := x1 + 1\nod;\nx0 := x1 + 0".to_owned(),
+        1
+      )
+    );
+  }
+
+  #[test]
+  fn propagates_copies() {
+    let code = "x1 := x2;\nx0 := x1 + x1;\nx2 := 1;\nx3 := x1 + 1";
+    assert_eq!(
+      run_pass(propagate_copies, code),
+      (
+        "x1 := x2;\nx0 := x2 + x2;\nx2 := 1;\nx3 := x1 + 1".to_owned(),
+        2
+      )
+    );
+  }
+
+  #[test]
+  fn removes_dead_loops() {
+    let code = "x1 := 0;\nwhile x1 != 0 do x2 := 1 od;\nwhile x3 != 0 do x3 := 0 od;\nx0 := 1";
+    assert_eq!(
+      run_pass(remove_dead_loops, code),
+      (
+        "x1 := 0;\nwhile x3 != 0 do\n  x3 := 0\nod;\nx0 := 1".to_owned(),
+        1
+      )
+    );
+  }
+
+  #[test]
+  fn hoists_invariants() {
+    let body = "while x1 != 0 do\n  x2 := x3 + x4;\n  x1 := x1 - 1\nod";
+    assert_eq!(
+      run_pass(hoist_invariants, &format!("x1 := 3;\n{body}")),
+      (
+        "x1 := 3;\nx2 := x3 + x4;\nwhile x1 != 0 do\n  x1 := x1 - 1\nod".to_owned(),
+        1
+      )
+    );
+    // Without the loop running, x2 wouldn't be assigned
+    assert_eq!(run_pass(hoist_invariants, body), (body.to_owned(), 0));
+  }
+
+  #[test]
+  fn eliminates_dead_stores() {
+    assert_eq!(
+      run_pass(eliminate_dead_stores, "x0 := 1;\nx0 := 2"),
+      ("x0 := 2".to_owned(), 1)
+    );
+    // Removing the subtraction would remove its underflow, and x0 is read before the last store
+    let code = "x0 := x1 - x2;\nx0 := 2;\nx3 := x0 + x0;\nx0 := 3";
+    assert_eq!(run_pass(eliminate_dead_stores, code), (code.to_owned(), 0));
+  }
+
+  #[test]
+  fn preserves_behaviour() {
+    for case in cases(27, 500, |rng| random_config(rng, true)) {
+      let before = outcome(&case.config, &case.prog, &case.input);
+      if matches!(before, Err(("StepLimitReached", _))) {
+        continue;
+      }
+      let (optimised, _) = optimise(&case.config, case.prog.clone());
+      let after = outcome(&case.config, &optimised, &case.input);
+      assert_eq!(
+        before, after,
+        "Optimising changed what\n{}\ndoes, giving\n{optimised}",
+        case.prog
+      );
+    }
+  }
+}
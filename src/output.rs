@@ -0,0 +1,190 @@
+use crate::symbolizer::Span;
+use std::{collections::HashMap, fmt::Write};
+
+// How `whily FILE` reports the final state. Besides the padded listing meant for people, the state
+// can be printed as a JSON object, as CSV with a variable and a value column, as plain
+// `name=value` lines or not at all. `--print` selects the variables to report, in the given
+// order; selected variables that were never assigned are reported as null (or left empty).
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+  Pretty,
+  Json,
+  Csv,
+  Plain,
+  Quiet,
+}
+
+impl Format {
+  pub fn from_name(name: &str) -> Self {
+    match name {
+      "json" => Format::Json,
+      "csv" => Format::Csv,
+      "plain" => Format::Plain,
+      "quiet" => Format::Quiet,
+      _ => Format::Pretty,
+    }
+  }
+}
+
+/// The variables of the state, sorted so that x2 comes before x12 and named variables come first.
+pub fn sorted_variables<V>(state: &HashMap<String, V>) -> Vec<&String> {
+  let index = |var: &str| var.strip_prefix('x')?.parse::<u64>().ok();
+  let mut keys = state.keys().collect::<Vec<_>>();
+  keys.sort_by(|a, b| match (index(a), index(b)) {
+    (None, None) => a.cmp(b),
+    (None, Some(_)) => std::cmp::Ordering::Less,
+    (Some(_), None) => std::cmp::Ordering::Greater,
+    (Some(xa), Some(xb)) => xa.cmp(&xb),
+  });
+  keys
+}
+
+/// The variables to report with their values, all of them if `print` is `None`.
+pub fn selected(
+  state: &HashMap<String, u64>,
+  print: Option<&[String]>,
+) -> Vec<(String, Option<u64>)> {
+  match print {
+    Some(vars) => vars
+      .iter()
+      .map(|var| (var.to_owned(), state.get(var).copied()))
+      .collect(),
+    None => sorted_variables(state)
+      .into_iter()
+      .map(|var| (var.to_owned(), Some(state[var])))
+      .collect(),
+  }
+}
+
+/// The state in one of the formats that aren't JSON.
+pub fn render_state(format: Format, values: &[(String, Option<u64>)]) -> String {
+  let value = |v: &Option<u64>| v.map_or(String::new(), |v| v.to_string());
+  let mut out = String::new();
+  match format {
+    Format::Pretty => {
+      if values.is_empty() {
+        writeln!(out, "No variables used.").unwrap();
+      }
+      let width = values
+        .iter()
+        .map(|(k, _)| k.chars().count())
+        .max()
+        .unwrap_or(0);
+      for (var, v) in values {
+        let v = v.map_or("unassigned".to_owned(), |v| v.to_string());
+        writeln!(out, "{var:<width$} = {v}").unwrap();
+      }
+    }
+    Format::Csv => {
+      writeln!(out, "variable,value").unwrap();
+      for (var, v) in values {
+        writeln!(out, "{},{}", csv_field(var), value(v)).unwrap();
+      }
+    }
+    Format::Plain => {
+      for (var, v) in values {
+        writeln!(out, "{var}={}", value(v)).unwrap();
+      }
+    }
+    Format::Json | Format::Quiet => {}
+  }
+  out
+}
+
+/// A CSV field, quoted if it contains a separator, a quote or a line break. Variables can't,
+/// but the names given with `--print` can.
+fn csv_field(text: &str) -> String {
+  match text.contains([',', '"', '\n', '\r']) {
+    true => format!("\"{}\"", text.replace('"', "\"\"")),
+    false => text.to_owned(),
+  }
+}
+
+pub fn json_string(text: &str) -> String {
+  let mut escaped = String::from('"');
+  for c in text.chars() {
+    match c {
+      '"' => escaped.push_str("\\\""),
+      '\\' => escaped.push_str("\\\\"),
+      '\n' => escaped.push_str("\\n"),
+      '\t' => escaped.push_str("\\t"),
+      c if (c as u32) < 0x20 => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+      c => escaped.push(c),
+    }
+  }
+  escaped.push('"');
+  escaped
+}
+
+pub fn span_json(span: Span) -> String {
+  format!(
+    "{{\"line\": {}, \"col\": {}, \"end_line\": {}, \"end_col\": {}}}",
+    span.line, span.col, span.end_line, span.end_col
+  )
+}
+
+pub fn state_json(values: &[(String, Option<u64>)]) -> String {
+  let fields = values
+    .iter()
+    .map(|(var, v)| {
+      let v = v.map_or("null".to_owned(), |v| v.to_string());
+      format!("{}: {v}", json_string(var))
+    })
+    .collect::<Vec<_>>();
+  format!("{{{}}}", fields.join(", "))
+}
+
+/// An error as a JSON object, with `null` for a missing variable or span.
+pub fn error_json(kind: &str, message: &str, variable: Option<&str>, span: Option<Span>) -> String {
+  format!(
+    "{{\"kind\": {}, \"message\": {}, \"variable\": {}, \"span\": {}}}",
+    json_string(kind),
+    json_string(message),
+    variable.map_or("null".to_owned(), json_string),
+    span.map_or("null".to_owned(), span_json)
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn json_is_escaped() {
+    assert_eq!(json_string("x0"), "\"x0\"");
+    assert_eq!(
+      json_string("a \"b\" \\ c\nd\te\u{1}f\u{7f}ö"),
+      "\"a \\\"b\\\" \\\\ c\\nd\\te\\u0001f\u{7f}ö\""
+    );
+    let values = [("x0".to_owned(), Some(3)), ("a\"b".to_owned(), None)];
+    assert_eq!(state_json(&values), "{\"x0\": 3, \"a\\\"b\": null}");
+    let span = Span {
+      line: 1,
+      col: 2,
+      end_line: 3,
+      end_col: 4,
+    };
+    assert_eq!(
+      error_json("VariableUnderflow", "\"x0\"", Some("x0"), Some(span)),
+      "{\"kind\": \"VariableUnderflow\", \"message\": \"\\\"x0\\\"\", \"variable\": \"x0\", \"span\": {\"line\": 1, \"col\": 2, \"end_line\": 3, \"end_col\": 4}}"
+    );
+    assert_eq!(
+      error_json("MaxLoopsReached", "", None, None),
+      "{\"kind\": \"MaxLoopsReached\", \"message\": \"\", \"variable\": null, \"span\": null}"
+    );
+  }
+
+  #[test]
+  fn csv_is_escaped() {
+    let values = [
+      ("x0".to_owned(), Some(3)),
+      ("a\"b".to_owned(), None),
+      ("c\nd".to_owned(), Some(1)),
+    ];
+    assert_eq!(
+      render_state(Format::Csv, &values),
+      "variable,value\nx0,3\n\"a\"\"b\",\n\"c\nd\",1\n"
+    );
+  }
+}
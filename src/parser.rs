@@ -1,9 +1,10 @@
 use crate::{
-  config::Config,
-  symbolizer::{Operator, Symbol},
+  config::{Config, Dialect},
+  error::ParseError,
+  symbolizer::{Operator, Span, Symbol},
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Statement {
   S(Box<Statement>, Box<Statement>),
   DeclareOperation(String, Value, Operator, Value),
@@ -11,219 +12,610 @@ pub enum Statement {
   While(String, Box<Statement>),
 }
 
+#[derive(Clone, PartialEq, Eq)]
 pub enum Value {
   Variable(String),
   Constant(u64),
 }
 
-pub fn parse(
+impl Value {
+  pub fn variable(&self) -> Option<&str> {
+    match self {
+      Value::Variable(var) => Some(var),
+      Value::Constant(_) => None,
+    }
+  }
+}
+
+// Parsing recovers from errors so that all of them can be reported at once. After an error in an
+// assignment, the rest of it is skipped up to the next `;` or the `od` of the loop it is in, and
+// after an error in the head of a loop, up to its `do`. Statements with errors are left out of
+// the partially parsed program, as are loops with an error in their head. An `od` without a loop
+// is reported once, and parsing carries on after it. The symbolizer also carries on after an
+// error, but programs with symbol errors aren't parsed, so those get reported on their own.
+
+/// Parses a whole program, returning every error found if there are any.
+pub fn parse_program(
   config: &Config,
-  symbols: &Vec<Symbol>,
-  mut index: usize,
-) -> Result<(usize, Statement), String> {
-  let mut left: Option<Statement> = None;
+  symbols: &[Symbol],
+  spans: &[Span],
+) -> Result<Statement, Vec<ParseError>> {
+  let (statement, errors) = parse_partial(config, symbols, spans);
+  match statement {
+    Some(statement) if errors.is_empty() => Ok(statement),
+    _ => Err(errors),
+  }
+}
 
-  let mut statement: Option<Statement> = None;
-  index = index.wrapping_sub(1);
+/// Parses as much of a program as possible, returning the statements without errors along with
+/// all errors.
+pub fn parse_partial(
+  config: &Config,
+  symbols: &[Symbol],
+  spans: &[Span],
+) -> (Option<Statement>, Vec<ParseError>) {
+  let mut errors = vec![];
+  let mut statements = vec![];
+  let mut index = 0;
   loop {
-    (left, statement) = match (left, statement) {
-      (Some(l), Some(s)) => (None, Some(Statement::S(Box::new(l), Box::new(s)))),
-      v => v,
-    };
-    index = index.wrapping_add(1);
-    let first = symbols.get(index);
-    if matches!(first, Some(Symbol::EOS)) {
-      if statement.is_none() {
-        return Err("Did not find left side of ; symbol.".to_owned());
-      }
-      left = statement;
-      statement = None;
-      continue;
+    let (end, sequence) = parse_sequence(config, symbols, spans, index, false, &mut errors);
+    statements.extend(sequence);
+    if end >= symbols.len() {
+      break;
     }
+    // The sequence stopped at an od
+    errors.push(ParseError::UnmatchedOd { span: spans[end] });
+    index = end + 1;
+    if matches!(symbols.get(index), Some(Symbol::Eos)) {
+      index += 1;
+    }
+    if index >= symbols.len() {
+      break;
+    }
+  }
+  (Statement::from_statements(statements), errors)
+}
 
-    match first {
-      None => {
-        if statement.is_none() {
-          return Err("Unexpected end of program, expected a statement first.".to_owned());
+/// The span of the symbol at `index`, or the position right after the last symbol.
+fn span_at(spans: &[Span], index: usize) -> Span {
+  match (spans.get(index), spans.last()) {
+    (Some(span), _) => *span,
+    (None, Some(last)) => Span {
+      line: last.end_line,
+      col: last.end_col + 1,
+      end_line: last.end_line,
+      end_col: last.end_col + 1,
+    },
+    (None, None) => Span {
+      line: 1,
+      col: 1,
+      end_line: 1,
+      end_col: 1,
+    },
+  }
+}
+
+fn unexpected(
+  symbols: &[Symbol],
+  spans: &[Span],
+  index: usize,
+  expected: &'static str,
+) -> ParseError {
+  ParseError::Unexpected {
+    expected,
+    found: symbols.get(index).cloned(),
+    span: span_at(spans, index),
+  }
+}
+
+fn is_keyword(symbol: Option<&Symbol>, keyword: &str) -> bool {
+  matches!(symbol, Some(Symbol::Keyword(k)) if k == keyword)
+}
+
+/// Whether a statement starts at `index`.
+fn starts_statement(symbols: &[Symbol], index: usize) -> bool {
+  match symbols.get(index) {
+    Some(Symbol::Variable(_)) => matches!(symbols.get(index + 1), Some(Symbol::Declare)),
+    symbol => is_keyword(symbol, "while"),
+  }
+}
+
+/// The index of the first `;` or `od` from `index` on that isn't inside a loop starting after
+/// `index`, or the end of the program.
+fn skip_statement(symbols: &[Symbol], mut index: usize) -> usize {
+  let mut depth = 0;
+  while let Some(symbol) = symbols.get(index) {
+    match symbol {
+      Symbol::Eos if depth == 0 => break,
+      Symbol::Keyword(kw) if kw == "while" => depth += 1,
+      Symbol::Keyword(kw) if kw == "od" => {
+        if depth == 0 {
+          break;
         }
-        return Ok((index - 1, statement.unwrap()));
+        depth -= 1;
       }
+      _ => {}
+    }
+    index += 1;
+  }
+  index
+}
 
+/// Parses statements separated by ; starting at `index`, up to the end of the program or an `od`.
+/// Returns the index it stopped at along with the statements. An `od` where a statement should
+/// be is only reported in loops, as elsewhere it is reported as an unmatched `od`.
+fn parse_sequence(
+  config: &Config,
+  symbols: &[Symbol],
+  spans: &[Span],
+  mut index: usize,
+  in_loop: bool,
+  errors: &mut Vec<ParseError>,
+) -> (usize, Vec<Statement>) {
+  let mut statements = vec![];
+  loop {
+    match symbols.get(index) {
+      Some(Symbol::Eos) => {
+        errors.push(unexpected(symbols, spans, index, "a statement before ';'"));
+        index += 1;
+        continue;
+      }
+      None => {
+        errors.push(unexpected(symbols, spans, index, "a statement"));
+        return (index, statements);
+      }
       Some(Symbol::Keyword(kw)) if kw == "od" => {
-        if statement.is_none() {
-          return Err("Unexpected 'od', expected a statement first.".to_owned());
+        if in_loop {
+          errors.push(unexpected(symbols, spans, index, "a statement"));
         }
-        return Ok((index - 1, statement.unwrap()));
+        return (index, statements);
       }
-      // 1  2  3  4  5
-      // xi := xj + xk
-      // xi := xj − xk
-      // xi := c
-      Some(Symbol::Variable(v0)) => {
-        if statement.is_some() {
-          return Err(format!("Found two statements in a row, the second starting with '{v0}', did you miss a ; symbol?"));
+      Some(Symbol::Keyword(kw)) if kw == "while" => {
+        let (next, statement) = parse_while(config, symbols, spans, index, errors);
+        statements.extend(statement);
+        index = next;
+      }
+      _ => match parse_assignment(config, symbols, spans, index).and_then(|(next, statement)| {
+        let span = spans[index].to(spans[next - 1]);
+        Ok((next, check_dialect(config, statement, span)?))
+      }) {
+        Ok((next, statement)) => {
+          statements.push(statement);
+          index = next;
         }
-        index += 1;
-        let second = symbols.get(index);
-        if !matches!(second, Some(Symbol::Declare)) {
-          return Err(format!(
-            "Invalid second symbol '{:?}' after variable '{v0}'",
-            second
-          ));
+        Err(e) => {
+          errors.push(e);
+          index = skip_statement(symbols, index);
         }
+      },
+    }
 
-        index += 1;
-        let left = match symbols.get(index) {
-          Some(Symbol::Variable(v1)) => Value::Variable(v1.to_owned()),
-          Some(Symbol::Constant(c)) => Value::Constant(*c),
-          Some(s) => {
-            return Err(format!(
-              "Unexpected symbol '{s:?}' in  '{v0} := {s:?}', expected either a variable or constant."
-            ))
-          }
-          None => {
-            return Err(format!(
-              "Unexpected end of program after '{v0} :='."
-            ))
-          }
-        };
-
-        index += 1;
+    match symbols.get(index) {
+      Some(Symbol::Eos) => index += 1,
+      None => return (index, statements),
+      Some(Symbol::Keyword(kw)) if kw == "od" => return (index, statements),
+      Some(_) if starts_statement(symbols, index) => {
+        errors.push(ParseError::MissingSemicolon { span: spans[index] });
+      }
+      Some(_) => {
+        errors.push(unexpected(symbols, spans, index, "';'"));
+        index = skip_statement(symbols, index);
         match symbols.get(index) {
-          None | Some(Symbol::EOS) | Some(Symbol::Keyword(_)) => {
-            index -= 1;
-            if config.allow_constants_everywhere || matches!(left, Value::Constant(_)) {
-              statement = Some(Statement::DeclareConst(v0.to_owned(), left));
-            } else {
-              return Err(format!(
-                "Assigning variables to other variables is not allowed without 'allow_constants_everywhere' enabled."
-              ));
-            }
-          }
-          Some(Symbol::Operator(operator)) => {
-            if !matches!(operator, Operator::Subtract | Operator::Add) && !config.extra_operators {
-              return Err(format!(
-                "Using operators other than + or - is not allowed without 'extra_operations' enabled."
-              ));
-            }
-            index += 1;
-            let right = symbols.get(index);
-            let right = match right {
-              Some(Symbol::Variable(v2)) => Value::Variable(v2.to_owned()),
-              Some(Symbol::Constant(c)) => Value::Constant(*c),
-              Some(s) => {
-                return Err(format!(
-                  "Invalid symbol '{s:?}' in '{v0} := {s:?}'. Expected variable or constant."
-                ))
-              }
-              None => return Err(format!("Unexpected end of program after '{v0} :='.")),
-            };
-            match (&left, &right) {
-              (Value::Constant(_), _) | (_, Value::Constant(_)) => {
-                if !config.allow_constants_everywhere {
-                  return Err(format!(
-                  "Using constants in + or - operations is not allowed without 'allow_constants_everywhere' enabled."
-                ));
-                }
-              }
-              _ => {}
-            }
-            statement = Some(Statement::DeclareOperation(
-              v0.to_owned(),
-              left,
-              operator.clone(),
-              right,
-            ))
-          }
-          Some(s) => {
-            return Err(format!(
-              "Invalid symbol '{s:?}' in '{v0} := {left:?} {s:?}'. Expected an operator."
-            ))
-          }
+          Some(Symbol::Eos) => index += 1,
+          _ => return (index, statements),
         }
       }
+    }
+  }
+}
 
-      // }
-      // while xi != 0 do P1 od
-      Some(Symbol::Keyword(kw)) if kw == "while" => {
-        if statement.is_some() {
-          return Err("Found two statements in a row, the second starting with 'while'. Did you miss a ; symbol?".to_owned());
+/// Rejects assignments that aren't part of the dialect, see `Dialect`.
+fn check_dialect(
+  config: &Config,
+  statement: Statement,
+  span: Span,
+) -> Result<Statement, ParseError> {
+  match (&statement, config.dialect) {
+    (
+      Statement::DeclareOperation(
+        _,
+        Value::Variable(_),
+        Operator::Add | Operator::Subtract,
+        Value::Constant(_),
+      ),
+      Dialect::Schoening,
+    ) => Ok(statement),
+    (_, Dialect::Schoening) => Err(ParseError::NotInDialect {
+      feature: "assignments other than xi := xj + c and xi := xj - c",
+      dialect: config.dialect.name(),
+      span,
+    }),
+    _ => Ok(statement),
+  }
+}
+
+/// Parses `while xi != 0 do P1 od` starting at `while`, returning the index after it. The loop is
+/// left out when its head has an error or it isn't closed.
+fn parse_while(
+  config: &Config,
+  symbols: &[Symbol],
+  spans: &[Span],
+  index: usize,
+  errors: &mut Vec<ParseError>,
+) -> (usize, Option<Statement>) {
+  let while_span = spans[index];
+  let (cv, do_index) = match parse_while_head(symbols, spans, index + 1) {
+    Ok(cv) => (Some(cv), index + 4),
+    Err((at, e)) => {
+      errors.push(e);
+      match find_do(symbols, at) {
+        Some(do_index) => (None, do_index),
+        None => return (skip_statement(symbols, at), None),
+      }
+    }
+  };
+  let (end, body) = parse_sequence(config, symbols, spans, do_index + 1, true, errors);
+  if !is_keyword(symbols.get(end), "od") {
+    errors.push(ParseError::MissingOd {
+      while_span,
+      span: span_at(spans, end),
+    });
+    return (end, None);
+  }
+  let statement = match (cv, Statement::from_statements(body)) {
+    (Some(cv), Some(body)) => Some(Statement::While(cv, Box::new(body))),
+    _ => None,
+  };
+  (end + 1, statement)
+}
+
+/// The index of the first `do` from `index` on, if it comes before the end of the statement.
+fn find_do(symbols: &[Symbol], mut index: usize) -> Option<usize> {
+  loop {
+    match symbols.get(index) {
+      Some(Symbol::Keyword(kw)) if kw == "do" => return Some(index),
+      None | Some(Symbol::Eos | Symbol::Keyword(_)) => return None,
+      _ => index += 1,
+    }
+  }
+}
+
+/// Parses `xi != 0 do` starting at `index`, returning the variable. Errors come with the index
+/// they are at.
+fn parse_while_head(
+  symbols: &[Symbol],
+  spans: &[Span],
+  index: usize,
+) -> Result<String, (usize, ParseError)> {
+  let cv = match symbols.get(index) {
+    Some(Symbol::Variable(cv)) => cv,
+    _ => {
+      return Err((
+        index,
+        unexpected(symbols, spans, index, "a variable after 'while'"),
+      ))
+    }
+  };
+  if !matches!(symbols.get(index + 1), Some(Symbol::NotEquals)) {
+    return Err((index + 1, unexpected(symbols, spans, index + 1, "'!='")));
+  }
+  if !matches!(symbols.get(index + 2), Some(Symbol::Constant(0))) {
+    return Err((index + 2, unexpected(symbols, spans, index + 2, "'0'")));
+  }
+  if !is_keyword(symbols.get(index + 3), "do") {
+    return Err((index + 3, unexpected(symbols, spans, index + 3, "'do'")));
+  }
+  Ok(cv.to_owned())
+}
+
+/// Parses an assignment starting at `index`, returning the index after it.
+// 1  2  3  4  5
+// xi := xj + xk
+// xi := xj − xk
+// xi := c
+// xi := f(xj, xk)
+fn parse_assignment(
+  config: &Config,
+  symbols: &[Symbol],
+  spans: &[Span],
+  mut index: usize,
+) -> Result<(usize, Statement), ParseError> {
+  let v0 = match symbols.get(index) {
+    Some(Symbol::Variable(v0)) => v0.to_owned(),
+    _ => return Err(unexpected(symbols, spans, index, "a statement")),
+  };
+  index += 1;
+  if !matches!(symbols.get(index), Some(Symbol::Declare)) {
+    return Err(unexpected(symbols, spans, index, "':='"));
+  }
+
+  index += 1;
+  if let Some(Symbol::Operator(function)) = symbols.get(index) {
+    let function_span = spans[index];
+    if !function.is_function() {
+      return Err(unexpected(
+        symbols,
+        spans,
+        index,
+        "a variable, constant or function",
+      ));
+    }
+    let (end, arguments) = parse_arguments(symbols, spans, index + 1)?;
+    let span = function_span.to(spans[end]);
+    let expected = if function.is_unary() { 1 } else { 2 };
+    if arguments.len() != expected {
+      return Err(ParseError::WrongArgumentCount {
+        function: function.clone(),
+        expected,
+        found: arguments.len(),
+        span,
+      });
+    }
+    if !config.extra_operators {
+      return Err(ParseError::FeatureDisabled {
+        feature: format!("'{function}'"),
+        option: "extra_operators",
+        span,
+      });
+    }
+    if !config.constants_everywhere() && arguments.iter().any(|a| matches!(a, Value::Constant(_))) {
+      return Err(ParseError::FeatureDisabled {
+        feature: format!("constants as arguments of '{function}'"),
+        option: "allow_constants_everywhere",
+        span,
+      });
+    }
+    let mut arguments = arguments.into_iter();
+    let v1 = arguments.next().unwrap();
+    let v2 = arguments.next().unwrap_or(Value::Constant(0));
+    return Ok((
+      end + 1,
+      Statement::DeclareOperation(v0, v1, function.clone(), v2),
+    ));
+  }
+  let left_span = span_at(spans, index);
+  let left = match symbols.get(index) {
+    Some(Symbol::Variable(v1)) => Value::Variable(v1.to_owned()),
+    Some(Symbol::Constant(c)) => Value::Constant(*c),
+    _ => {
+      return Err(unexpected(
+        symbols,
+        spans,
+        index,
+        "a variable, constant or function",
+      ))
+    }
+  };
+
+  index += 1;
+  match symbols.get(index) {
+    // Functions can only be called, which keeps the operand of unary ones at 0
+    Some(Symbol::Operator(operator)) if operator.is_function() => Err(unexpected(
+      symbols,
+      spans,
+      index,
+      "an infix operator or ';'",
+    )),
+    Some(Symbol::Operator(operator)) => {
+      if !matches!(operator, Operator::Subtract | Operator::Add) && !config.extra_operators {
+        return Err(ParseError::FeatureDisabled {
+          feature: format!("the operator '{operator}'"),
+          option: "extra_operators",
+          span: spans[index],
+        });
+      }
+      index += 1;
+      let right = match symbols.get(index) {
+        Some(Symbol::Variable(v2)) => Value::Variable(v2.to_owned()),
+        Some(Symbol::Constant(c)) => Value::Constant(*c),
+        _ => return Err(unexpected(symbols, spans, index, "a variable or constant")),
+      };
+      match (&left, &right) {
+        (Value::Constant(_), _) | (_, Value::Constant(_)) if !config.constants_everywhere() => {
+          return Err(ParseError::FeatureDisabled {
+            feature: format!("constants in {operator} operations"),
+            option: "allow_constants_everywhere",
+            span: left_span.to(spans[index]),
+          });
         }
-        index += 1;
-        let cv = match symbols.get(index) {
-          Some(Symbol::Variable(cv)) => cv,
-          Some(s) => {
-            return Err(format!(
-              "Invalid symbol '{s:?}' in 'while {s:?}'. 'while' must be followed by 'xi != 0'."
-            ))
-          }
-          None => return Err(format!("Unexpected end of program after 'while'.")),
-        };
-        // !=
-        index += 1;
-        match symbols.get(index) {
-          Some(Symbol::NotEquals) => {}
-          Some(s) => {
-            return Err(format!(
-              "Invalid symbol '{s:?}' in 'while {cv} {s:?}'. Only != is allowed."
-            ))
+        _ => {}
+      }
+      Ok((
+        index + 1,
+        Statement::DeclareOperation(v0, left, operator.clone(), right),
+      ))
+    }
+    Some(Symbol::Variable(_)) if !starts_statement(symbols, index) => {
+      Err(unexpected(symbols, spans, index, "an operator or ';'"))
+    }
+    // The end of the statement, a missing ; is reported by `parse_sequence`
+    None | Some(Symbol::Eos | Symbol::Keyword(_) | Symbol::Variable(_)) => {
+      if config.constants_everywhere() || matches!(left, Value::Constant(_)) {
+        Ok((index, Statement::DeclareConst(v0, left)))
+      } else {
+        Err(ParseError::FeatureDisabled {
+          feature: "assignments of variables to other variables".to_owned(),
+          option: "allow_constants_everywhere",
+          span: left_span,
+        })
+      }
+    }
+    _ => Err(unexpected(symbols, spans, index, "an operator or ';'")),
+  }
+}
+
+// Shorthands for building programs in code
+
+pub fn var(name: &str) -> Value {
+  Value::Variable(name.to_owned())
+}
+
+pub fn constant(c: u64) -> Value {
+  Value::Constant(c)
+}
+
+pub fn assign(dst: &str, value: Value) -> Statement {
+  Statement::DeclareConst(dst.to_owned(), value)
+}
+
+pub fn apply(dst: &str, v1: Value, operator: Operator, v2: Value) -> Statement {
+  Statement::DeclareOperation(dst.to_owned(), v1, operator, v2)
+}
+
+pub fn looped(cv: &str, body: Vec<Statement>) -> Statement {
+  Statement::While(
+    cv.to_owned(),
+    Box::new(Statement::from_statements(body).expect("Empty loop body")),
+  )
+}
+
+/// Parses `(a, b, ..)`, starting at the opening parenthesis. Returns the index of the closing
+/// parenthesis along with the arguments.
+fn parse_arguments(
+  symbols: &[Symbol],
+  spans: &[Span],
+  mut index: usize,
+) -> Result<(usize, Vec<Value>), ParseError> {
+  if !matches!(symbols.get(index), Some(Symbol::OpenParen)) {
+    return Err(unexpected(symbols, spans, index, "'('"));
+  }
+  let mut arguments = vec![];
+  loop {
+    index += 1;
+    match symbols.get(index) {
+      Some(Symbol::Variable(v)) => arguments.push(Value::Variable(v.to_owned())),
+      Some(Symbol::Constant(c)) => arguments.push(Value::Constant(*c)),
+      _ => return Err(unexpected(symbols, spans, index, "a variable or constant")),
+    }
+    index += 1;
+    match symbols.get(index) {
+      Some(Symbol::Comma) => {}
+      Some(Symbol::CloseParen) => return Ok((index, arguments)),
+      _ => return Err(unexpected(symbols, spans, index, "',' or ')'")),
+    }
+  }
+}
+
+impl Statement {
+  /// Splits a chain of `S` statements into the statements it is made of, in order.
+  pub fn into_statements(self) -> Vec<Statement> {
+    match self {
+      Statement::S(left, right) => {
+        let mut statements = left.into_statements();
+        statements.extend(right.into_statements());
+        statements
+      }
+      s => vec![s],
+    }
+  }
+
+  /// The inverse of `into_statements`, chaining the statements together the same way `parse` does.
+  /// Returns `None` if there are no statements.
+  pub fn from_statements(statements: Vec<Statement>) -> Option<Statement> {
+    statements
+      .into_iter()
+      .reduce(|left, right| Statement::S(Box::new(left), Box::new(right)))
+  }
+
+  /// Every statement in the program except for `S`, in the order they appear in the source code.
+  pub fn nodes(&self) -> Vec<&Statement> {
+    fn walk<'a>(statement: &'a Statement, out: &mut Vec<&'a Statement>) {
+      match statement {
+        Statement::S(left, right) => {
+          walk(left, out);
+          walk(right, out);
+        }
+        Statement::While(_, body) => {
+          out.push(statement);
+          walk(body, out);
+        }
+        s => out.push(s),
+      }
+    }
+    let mut out = vec![];
+    walk(self, &mut out);
+    out
+  }
+
+  /// Every variable the statement uses, in order of first appearance.
+  pub fn variables(&self) -> Vec<&str> {
+    let mut out: Vec<&str> = vec![];
+    for statement in self.nodes() {
+      let used = match statement {
+        Statement::DeclareOperation(v0, v1, _, v2) => {
+          vec![Some(v0.as_str()), v1.variable(), v2.variable()]
+        }
+        Statement::DeclareConst(v0, v) => vec![Some(v0.as_str()), v.variable()],
+        Statement::While(cv, _) => vec![Some(cv.as_str())],
+        Statement::S(..) => vec![],
+      };
+      for var in used.into_iter().flatten() {
+        if !out.contains(&var) {
+          out.push(var);
+        }
+      }
+    }
+    out
+  }
+
+  /// The names of the config options that `parse` requires to be enabled for this statement.
+  pub fn required_options(&self) -> Vec<&'static str> {
+    fn is_named(var: &str) -> bool {
+      !matches!(var.strip_prefix('x'), Some(i) if i.parse::<u64>().is_ok())
+    }
+    fn walk(statement: &Statement, options: &mut Vec<&'static str>) {
+      let mut require = |option| {
+        if !options.contains(&option) {
+          options.push(option);
+        }
+      };
+      match statement {
+        Statement::S(left, right) => {
+          walk(left, options);
+          walk(right, options);
+        }
+        Statement::DeclareOperation(v0, v1, operator, v2) => {
+          if is_named(v0) {
+            require("allow_named_vars");
           }
-          None => return Err(format!("Unexpected end of program after 'while x{cv}'.")),
-        };
-        // 0
-        index += 1;
-        match symbols.get(index) {
-          Some(Symbol::Constant(0)) => {}
-          Some(s) => {
-            return Err(format!(
-              "Invalid symbol '{s:?}' in 'while {cv} != {s:?}'. Only 0 is allowed."
-            ))
+          if !matches!(operator, Operator::Add | Operator::Subtract) {
+            require("extra_operators");
           }
-          None => return Err(format!("Unexpected end of program after 'while {cv} !='.")),
-        };
-        // do
-        index += 1;
-        match symbols.get(index) {
-          Some(Symbol::Keyword(kw)) if kw == "do" => {}
-          Some(s) => {
-            return Err(format!(
-              "Invalid symbol '{s:?}' in 'while {cv} != 0 {s:?}'. Only 'do' is allowed."
-            ))
+          let arguments = if operator.is_unary() {
+            vec![v1]
+          } else {
+            vec![v1, v2]
+          };
+          for v in arguments {
+            match v {
+              Value::Constant(_) => require("allow_constants_everywhere"),
+              Value::Variable(var) if is_named(var) => require("allow_named_vars"),
+              _ => {}
+            }
           }
-          None => {
-            return Err(format!(
-              "Unexpected end of program after 'while {cv} != 0'."
-            ))
+        }
+        Statement::DeclareConst(v0, v) => {
+          if is_named(v0) {
+            require("allow_named_vars");
           }
-        };
-        // P1
-        let p1 = match parse(config, symbols, index + 1) {
-          Err(e) => return Err(e),
-          Ok(p1) => p1,
-        };
-        index = p1.0;
-        // od
-        index += 1;
-        match symbols.get(index) {
-          Some(Symbol::Keyword(kw)) if kw == "od" => {}
-          Some(s) => {
-            return Err(format!(
-              "Invalid symbol '{s:?}' in 'while {cv} != 0 do .. {s:?}'. Only 'od' is allowed."
-            ))
+          if let Value::Variable(var) = v {
+            require("allow_constants_everywhere");
+            if is_named(var) {
+              require("allow_named_vars");
+            }
           }
-          None => {
-            return Err(format!(
-              "Unexpected end of program after 'while {cv} != 0 do .. '. Expected 'od' instead."
-            ))
+        }
+        Statement::While(cv, body) => {
+          if is_named(cv) {
+            require("allow_named_vars");
           }
-        };
-        statement = Some(Statement::While(cv.to_owned(), Box::new(p1.1)));
-      }
-      // ;
-      _ => {
-        return Err(format!("Invalid start of statement: {:?}", first));
+          walk(body, options);
+        }
       }
     }
+    let mut options = vec![];
+    walk(self, &mut options);
+    options
   }
 }
 
@@ -235,3 +627,116 @@ impl std::fmt::Debug for Value {
     }
   }
 }
+
+/// The spans of the statements of a program that parsed successfully, in the same order as
+/// `Statement::nodes`, given the symbols of the program and their spans.
+pub fn statement_spans(symbols: &[Symbol], spans: &[Span]) -> Vec<Span> {
+  let is_keyword =
+    |i: usize, keyword: &str| matches!(&symbols[i], Symbol::Keyword(k) if k == keyword);
+  let mut statement_spans = vec![];
+  for (i, symbol) in symbols.iter().enumerate() {
+    let starts_statement =
+      i == 0 || matches!(symbols[i - 1], Symbol::Eos) || is_keyword(i - 1, "do");
+    if !starts_statement {
+      continue;
+    }
+    if is_keyword(i, "while") {
+      // Up to the matching od
+      let mut depth = 0;
+      let end = (i..symbols.len())
+        .find(|&j| {
+          if is_keyword(j, "while") {
+            depth += 1;
+          } else if is_keyword(j, "od") {
+            depth -= 1;
+          }
+          depth == 0 && is_keyword(j, "od")
+        })
+        .unwrap_or(symbols.len() - 1);
+      statement_spans.push(spans[i].to(spans[end]));
+    } else if matches!(symbol, Symbol::Variable(_)) {
+      let end = (i..symbols.len())
+        .find(|&j| matches!(symbols[j], Symbol::Eos | Symbol::Keyword(_)))
+        .unwrap_or(symbols.len());
+      statement_spans.push(spans[i].to(spans[end - 1]));
+    }
+  }
+  statement_spans
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{compile, config::Config};
+
+  /// The kinds of the errors in `code`, with the lines they are on.
+  fn errors(code: &str) -> Vec<(&'static str, usize)> {
+    match compile(&mut Config::default(), code) {
+      Ok(_) => vec![],
+      Err(errors) => errors.iter().map(|e| (e.kind(), e.span().line)).collect(),
+    }
+  }
+
+  #[test]
+  fn reports_every_parse_error() {
+    let code = "x1 := ;\nx2 := x1 +;\nwhile x1 != 1 do x3 := 1 od;\nx4 := 2 x5 := 3";
+    assert_eq!(
+      errors(code),
+      [
+        ("Unexpected", 1),
+        ("Unexpected", 2),
+        ("Unexpected", 3),
+        ("MissingSemicolon", 4)
+      ]
+    );
+  }
+
+  #[test]
+  fn unmatched_od_is_reported_once() {
+    assert_eq!(errors("x1 := 1;\nod;\nx2 := 2"), [("UnmatchedOd", 2)]);
+    assert_eq!(errors("od"), [("UnmatchedOd", 1)]);
+    assert_eq!(
+      errors("while x1 != 0 do x1 := 0 od od;\nx2 := ;\nx3 := 1"),
+      [("UnmatchedOd", 1), ("Unexpected", 2)]
+    );
+  }
+
+  #[test]
+  fn errors_in_loops() {
+    assert_eq!(errors("while x1 != 0 do od"), [("Unexpected", 1)]);
+    assert_eq!(
+      errors("while x1 != 0 do\nx1 := 0;\n"),
+      [("Unexpected", 2), ("MissingOd", 2)]
+    );
+    assert_eq!(errors("while x1 != 0 do\nx1 := 0"), [("MissingOd", 2)]);
+    assert_eq!(
+      errors("while x1 do x2 := 1 + od;\nx3 := 1 +"),
+      [("Unexpected", 1), ("Unexpected", 1), ("Unexpected", 2)]
+    );
+  }
+
+  #[test]
+  fn reports_every_symbol_error() {
+    let code = "#bogus\nx1 := 99999999999999999999999;\ny := pair(x1, 2);\nx2 := x1 @ 3";
+    assert_eq!(
+      errors(code),
+      [
+        ("InvalidOption", 1),
+        ("NumberTooLarge", 2),
+        ("FeatureDisabled", 3),
+        ("FeatureDisabled", 3),
+        ("InvalidCharacter", 4)
+      ]
+    );
+  }
+
+  #[test]
+  fn functions_are_not_infix() {
+    let code = "#extra_operators\n#allow_constants_everywhere\nx1 := x0 fst 5;\nx2 := x1 pair x2;\nx3 := x1 * x2";
+    assert_eq!(errors(code), [("Unexpected", 3), ("Unexpected", 4)]);
+  }
+
+  #[test]
+  fn valid_programs_parse() {
+    assert_eq!(errors("x1 := 1; while x1 != 0 do x1 := x1 - x1 od"), []);
+  }
+}
@@ -0,0 +1,62 @@
+use crate::{
+  config::{Config, OPTIONS},
+  parser::{Statement, Value},
+};
+use std::fmt::Write;
+
+const INDENT: &str = "  ";
+
+/// The `#option` lines needed to run a printed program with the same config.
+pub fn pragmas(config: &Config) -> String {
+  let default = Config::default();
+  let mut out = String::new();
+  for option in &OPTIONS {
+    if option.get(config) != option.get(&default) {
+      writeln!(out, "{}", option.pragma(config)).unwrap();
+    }
+  }
+  out
+}
+
+fn write_statement(
+  f: &mut std::fmt::Formatter<'_>,
+  statement: &Statement,
+  depth: usize,
+) -> std::fmt::Result {
+  let indent = INDENT.repeat(depth);
+  match statement {
+    Statement::S(left, right) => {
+      write_statement(f, left, depth)?;
+      writeln!(f, ";")?;
+      write_statement(f, right, depth)
+    }
+    Statement::DeclareOperation(v0, v1, operator, _) if operator.is_unary() => {
+      write!(f, "{indent}{v0} := {operator}({v1})")
+    }
+    Statement::DeclareOperation(v0, v1, operator, v2) if operator.is_function() => {
+      write!(f, "{indent}{v0} := {operator}({v1}, {v2})")
+    }
+    Statement::DeclareOperation(v0, v1, operator, v2) => {
+      write!(f, "{indent}{v0} := {v1} {operator} {v2}")
+    }
+    Statement::DeclareConst(v0, v) => write!(f, "{indent}{v0} := {v}"),
+    Statement::While(cv, body) => {
+      writeln!(f, "{indent}while {cv} != 0 do")?;
+      write_statement(f, body, depth + 1)?;
+      write!(f, "\n{indent}od")
+    }
+  }
+}
+
+/// Prints the statement as WHILE source code, which parses back into an equivalent statement.
+impl std::fmt::Display for Statement {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write_statement(f, self, 0)
+  }
+}
+
+impl std::fmt::Display for Value {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{self:?}")
+  }
+}
@@ -0,0 +1,201 @@
+use crate::{parser::Statement, symbolizer::Span};
+use std::{collections::HashMap, fmt::Write};
+
+// Counts how often every statement runs and how many steps (see `run`) it takes, including the
+// steps of the statements inside it for loops. Statements are numbered in the order of
+// `Statement::nodes`, which is also the order of `parser::statement_spans`.
+//
+// Steps are also collected per stack of loops for folded stack output, which is one line per
+// stack, like `while x1 != 0 (2:1-5:2);x0 := x0 + x2 (3:3-14) 100`, where the last frame is
+// the statement that took the steps (loop frames take the steps of checking their condition).
+
+const HOT_SPOTS: usize = 20;
+
+pub struct Profile {
+  ids: HashMap<*const Statement, usize>,
+  executions: Vec<u64>,
+  steps: Vec<u64>,
+  // The loops that are currently running, outermost first
+  stack: Vec<usize>,
+  folded: HashMap<Vec<usize>, u64>,
+}
+
+impl Profile {
+  /// Starts a profile of `prog`, which has to be the exact statement that then gets run.
+  pub fn new(prog: &Statement) -> Self {
+    let nodes = prog.nodes();
+    Self {
+      ids: nodes
+        .iter()
+        .enumerate()
+        .map(|(id, s)| (*s as *const Statement, id))
+        .collect(),
+      executions: vec![0; nodes.len()],
+      steps: vec![0; nodes.len()],
+      stack: vec![],
+      folded: HashMap::new(),
+    }
+  }
+
+  fn id(&self, statement: &Statement) -> usize {
+    *self
+      .ids
+      .get(&(statement as *const Statement))
+      .expect("Profiling a statement outside of the profiled program")
+  }
+
+  fn add_folded(&mut self, leaf: Option<usize>, steps: u64) {
+    let mut stack = self.stack.clone();
+    stack.extend(leaf);
+    *self.folded.entry(stack).or_default() += steps;
+  }
+
+  pub fn assignment(&mut self, statement: &Statement) {
+    let id = self.id(statement);
+    self.executions[id] += 1;
+    self.steps[id] += 1;
+    self.add_folded(Some(id), 1);
+  }
+
+  pub fn enter_loop(&mut self, statement: &Statement) {
+    let id = self.id(statement);
+    self.executions[id] += 1;
+    self.stack.push(id);
+  }
+
+  pub fn loop_check(&mut self) {
+    self.add_folded(None, 1);
+  }
+
+  /// Adds a loop that ran in closed form, checking its condition `checks` times and running the
+  /// statements of its body as often as `executions` says.
+  pub fn accelerated_loop(&mut self, body: &Statement, checks: u64, executions: &[u64]) {
+    // Accelerated loop bodies are flat, see `try_accelerate`
+    self.add_folded(None, checks);
+    for (statement, &count) in body.nodes().into_iter().zip(executions) {
+      let id = self.id(statement);
+      self.executions[id] += count;
+      self.steps[id] += count;
+      if count > 0 {
+        self.add_folded(Some(id), count);
+      }
+    }
+  }
+
+  pub fn exit_loop(&mut self, statement: &Statement, steps: u64) {
+    let id = self.id(statement);
+    self.steps[id] += steps;
+    self.stack.pop();
+  }
+
+  /// How often the statement ran, or for loops how often the loop was started.
+  pub fn executions(&self, statement: &Statement) -> u64 {
+    self.executions[self.id(statement)]
+  }
+
+  pub fn name(statement: &Statement) -> String {
+    match statement {
+      Statement::While(cv, _) => format!("while {cv} != 0"),
+      s => s.to_string(),
+    }
+  }
+
+  /// A table of the statements that took the most steps.
+  pub fn hot_spots(&self, prog: &Statement, spans: &[Span], total_steps: u64) -> String {
+    let nodes = prog.nodes();
+    let mut ids = (0..nodes.len())
+      .filter(|&id| self.executions[id] > 0)
+      .collect::<Vec<_>>();
+    ids.sort_by_key(|&id| std::cmp::Reverse(self.steps[id]));
+
+    let spans = ids
+      .iter()
+      .map(|&id| spans[id].to_string())
+      .collect::<Vec<_>>();
+    let span_width = spans.iter().map(String::len).max().unwrap_or(0).max(4);
+    let mut table = String::new();
+    writeln!(
+      table,
+      "{:<span_width$} {:>12} {:>14} {:>6}  Statement",
+      "Span", "Executions", "Steps", "Share"
+    )
+    .unwrap();
+    for (&id, span) in ids.iter().zip(&spans).take(HOT_SPOTS) {
+      let share = 100.0 * self.steps[id] as f64 / total_steps.max(1) as f64;
+      writeln!(
+        table,
+        "{span:<span_width$} {:>12} {:>14} {share:>5.1}%  {}",
+        self.executions[id],
+        self.steps[id],
+        Self::name(nodes[id])
+      )
+      .unwrap();
+    }
+    if ids.len() > HOT_SPOTS {
+      writeln!(table, "({} more statements)", ids.len() - HOT_SPOTS).unwrap();
+    }
+    table
+  }
+
+  /// The steps per stack of loops, in the folded format flame graph tools use.
+  pub fn folded_stacks(&self, prog: &Statement, spans: &[Span]) -> String {
+    let nodes = prog.nodes();
+    let frame = |id: usize| format!("{} ({})", Self::name(nodes[id]), spans[id]);
+    let mut lines = self
+      .folded
+      .iter()
+      .map(|(stack, steps)| {
+        let frames = stack.iter().map(|&id| frame(id)).collect::<Vec<_>>();
+        (stack, format!("{} {steps}", frames.join(";")))
+      })
+      .collect::<Vec<_>>();
+    lines.sort();
+    lines.into_iter().map(|(_, line)| line + "\n").collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    compile_spanned,
+    config::Config,
+    run::{run_counted, Counters},
+  };
+
+  #[test]
+  fn folded_stacks_of_nested_loops() {
+    let code = "x0 := x1 + x4;
+while x1 != 0 do
+  x2 := x0 + x4;
+  while x2 != 0 do
+    x2 := x2 - x3
+  od;
+  x1 := x1 - x3
+od";
+    let (prog, spans) = compile_spanned(&mut Config::default(), code).unwrap();
+    let input = HashMap::from([("x1".into(), 2), ("x3".into(), 1), ("x4".into(), 0)]);
+    // Both loops check their condition once more than their body runs
+    let expected = "x0 := x1 + x4 (1:1-13) 1
+while x1 != 0 (2:1-8:2) 3
+while x1 != 0 (2:1-8:2);x2 := x0 + x4 (3:3-15) 2
+while x1 != 0 (2:1-8:2);while x2 != 0 (4:3-6:4) 6
+while x1 != 0 (2:1-8:2);while x2 != 0 (4:3-6:4);x2 := x2 - x3 (5:5-17) 4
+while x1 != 0 (2:1-8:2);x1 := x1 - x3 (7:3-15) 2
+";
+    // The inner loop runs in closed form when accelerated, which has to count the same
+    for accelerate in [false, true] {
+      let config = Config {
+        accelerate,
+        ..Config::default()
+      };
+      let mut counters = Counters {
+        profile: Some(Profile::new(&prog)),
+        ..Default::default()
+      };
+      run_counted(&config, &prog, input.clone(), &mut counters).unwrap();
+      let profile = counters.profile.unwrap();
+      assert_eq!(profile.folded_stacks(&prog, &spans), expected);
+    }
+  }
+}
@@ -1,96 +1,297 @@
 use crate::{
+  accelerate::try_accelerate,
+  godel::evaluate_function,
   parser::{Statement, Value},
+  profile::Profile,
   symbolizer::Operator,
   Config,
 };
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Instant};
 
 const MAX_ITERATIONS: usize = 1024 * 128;
+// How often the deadline is checked, in steps
+const DEADLINE_INTERVAL: u64 = 4096;
+
+// A step is an executed assignment or a check of a loop condition. When the config sets
+// `max_steps`, running out of steps replaces the cap of MAX_ITERATIONS on every loop.
+//
+// The cost of running a program is either its amount of steps (the uniform cost model), or the
+// sum of the lengths of the values every step uses (the logarithmic cost model): the operands
+// of an assignment and the loop variable of a check. The space it takes is the highest total
+// length of all values in the state. The length of a value is its amount of bits, but at least 1.
 
 pub fn run(config: &Config, prog: &Statement) -> Result<HashMap<String, u64>, RuntimeError> {
-  let mut state: HashMap<String, u64> = HashMap::new();
-  run_with_state(config, prog, &mut state)?;
-  Ok(state)
+  run_with_input(config, prog, HashMap::new())
+}
+
+/// Runs the program starting from the given state instead of an empty one.
+pub fn run_with_input(
+  config: &Config,
+  prog: &Statement,
+  state: HashMap<String, u64>,
+) -> Result<HashMap<String, u64>, RuntimeError> {
+  run_counted(config, prog, state, &mut Counters::default())
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum CostModel {
+  #[default]
+  Uniform,
+  Log,
+}
+
+pub fn length(value: u64) -> u64 {
+  (64 - value.leading_zeros() as u64).max(1)
+}
+
+/// Everything that gets counted while running a program.
+#[derive(Default)]
+pub struct Counters {
+  pub steps: u64,
+  pub cost_model: CostModel,
+  pub cost: u64,
+  // The current and the highest total length of the values in the state
+  pub space: u64,
+  pub max_space: u64,
+  // The largest value any variable had, including the input
+  pub max_value: u64,
+  // Only kept track of when set, as it slows running down
+  pub profile: Option<Profile>,
+  // The index in `Statement::nodes` of the innermost statement that caused an error, if one
+  // occurred
+  pub failed_at: Option<usize>,
+  // When set, running stops with TimeLimitReached after this
+  pub deadline: Option<Instant>,
+  // When set, running stops with MemoryLimitReached as soon as the space is larger than this
+  pub memory_limit: Option<u64>,
 }
 
-fn run_with_state(
+/// Like `run_with_input`, adding what happened to `counters`. These are also updated when an
+/// error occurs.
+pub fn run_counted(
   config: &Config,
   prog: &Statement,
+  mut state: HashMap<String, u64>,
+  counters: &mut Counters,
+) -> Result<HashMap<String, u64>, RuntimeError> {
+  if config.dialect.is_textbook() {
+    // Variables start at 0 instead of being unassigned, see `Dialect`
+    for var in prog.variables() {
+      state.entry(var.to_owned()).or_insert(0);
+    }
+  }
+  for &value in state.values() {
+    counters.space += length(value);
+    counters.max_value = counters.max_value.max(value);
+  }
+  counters.max_space = counters.max_space.max(counters.space);
+  check_memory(counters)?;
+  let mut failed = None;
+  let result = run_with_state(config, prog, &mut state, counters, &mut failed);
+  if let Some(failed) = failed {
+    counters.failed_at = prog.nodes().iter().position(|s| std::ptr::eq(*s, failed));
+  }
+  result.map(|()| state)
+}
+
+/// Counts a step using values with a total length of `log_cost`.
+fn step(config: &Config, counters: &mut Counters, log_cost: u64) -> Result<(), RuntimeError> {
+  counters.steps = counters.steps.saturating_add(1);
+  counters.cost = counters.cost.saturating_add(match counters.cost_model {
+    CostModel::Uniform => 1,
+    CostModel::Log => log_cost,
+  });
+  if counters.steps.is_multiple_of(DEADLINE_INTERVAL)
+    && counters
+      .deadline
+      .is_some_and(|deadline| Instant::now() > deadline)
+  {
+    return Err(RuntimeError::TimeLimitReached);
+  }
+  match config.max_steps {
+    Some(max_steps) if counters.steps > max_steps => Err(RuntimeError::StepLimitReached),
+    _ => Ok(()),
+  }
+}
+
+fn assign(
   state: &mut HashMap<String, u64>,
+  var: &str,
+  value: u64,
+  counters: &mut Counters,
+) -> Result<(), RuntimeError> {
+  let old = state.insert(var.to_owned(), value);
+  counters.space = counters.space + length(value) - old.map_or(0, length);
+  counters.max_space = counters.max_space.max(counters.space);
+  counters.max_value = counters.max_value.max(value);
+  check_memory(counters)
+}
+
+/// Checks the highest space so far against the memory limit.
+fn check_memory(counters: &Counters) -> Result<(), RuntimeError> {
+  match counters.memory_limit {
+    Some(limit) if counters.max_space > limit => Err(RuntimeError::MemoryLimitReached),
+    _ => Ok(()),
+  }
+}
+
+/// The value of an operand in the state.
+pub fn read(state: &HashMap<String, u64>, value: &Value) -> Result<u64, RuntimeError> {
+  match value {
+    Value::Variable(var) => state
+      .get(var)
+      .copied()
+      .ok_or_else(|| RuntimeError::UnassignedVariable(var.to_owned())),
+    Value::Constant(c) => Ok(*c),
+  }
+}
+
+/// The value `v0 := v1 operator v2` assigns.
+pub fn apply(
+  config: &Config,
+  v0: &str,
+  operator: &Operator,
+  v1: u64,
+  v2: u64,
+) -> Result<u64, RuntimeError> {
+  match operator {
+    Operator::Subtract => match v1.checked_sub(v2) {
+      Some(val) => Ok(val),
+      None if config.underflow_allowed() => Ok(0),
+      None => Err(RuntimeError::VariableUnderflow(v0.to_owned())),
+    },
+    Operator::Add => v1
+      .checked_add(v2)
+      .ok_or(RuntimeError::VariableOverflow(v0.to_owned())),
+    Operator::Multiply => v1
+      .checked_mul(v2)
+      .ok_or(RuntimeError::VariableOverflow(v0.to_owned())),
+    _ => evaluate_function(operator, v1, v2).ok_or(RuntimeError::VariableOverflow(v0.to_owned())),
+  }
+}
+
+/// Runs `prog`, setting `failed` to the innermost statement that caused an error.
+fn run_with_state<'a>(
+  config: &Config,
+  prog: &'a Statement,
+  state: &mut HashMap<String, u64>,
+  counters: &mut Counters,
+  failed: &mut Option<&'a Statement>,
+) -> Result<(), RuntimeError> {
+  let result = run_statement(config, prog, state, counters, failed);
+  if result.is_err() && failed.is_none() && !matches!(prog, Statement::S(..)) {
+    *failed = Some(prog);
+  }
+  result
+}
+
+fn run_statement<'a>(
+  config: &Config,
+  prog: &'a Statement,
+  state: &mut HashMap<String, u64>,
+  counters: &mut Counters,
+  failed: &mut Option<&'a Statement>,
 ) -> Result<(), RuntimeError> {
   match prog {
     Statement::S(left, right) => {
-      run_with_state(config, left, state)?;
-      run_with_state(config, right, state)?;
+      run_with_state(config, left, state, counters, failed)?;
+      run_with_state(config, right, state, counters, failed)?;
     }
     Statement::DeclareOperation(v0, v1, operator, v2) => {
-      let v1 = match v1 {
-        Value::Variable(var) if state.contains_key(var) => {
-          state.get(var).expect("Variable not accessed?! 1")
-        }
-        Value::Variable(var) => return Err(RuntimeError::UnassignedVariable(var.to_owned())),
-        Value::Constant(c) => c,
+      let (v1, v2) = (read(state, v1)?, read(state, v2)?);
+      let operands = if operator.is_unary() {
+        length(v1)
+      } else {
+        length(v1) + length(v2)
       };
-      let v2 = match v2 {
-        Value::Variable(var) if state.contains_key(var) => {
-          state.get(var).expect("Variable not accessed?! 2")
-        }
-        Value::Variable(var) => return Err(RuntimeError::UnassignedVariable(var.to_owned())),
-        Value::Constant(c) => c,
-      };
-      match operator {
-        Operator::Subtract => {
-          match v1.checked_sub(v2.to_owned()) {
-            Some(val) => state.insert(v0.to_owned(), val),
-            None => {
-              if !config.allow_underflow {
-                return Err(RuntimeError::VariableUnderflow(v0.to_owned()));
-              } else {
-                state.insert(v0.to_owned(), 0)
-              }
-            }
-          };
-        }
-        Operator::Add => {
-          match v1.checked_add(v2.to_owned()) {
-            Some(val) => state.insert(v0.to_owned(), val),
-            None => return Err(RuntimeError::VariableOverflow(v0.to_owned())),
-          };
-        }
-
-        Operator::Multiply => {
-          match v1.checked_mul(v2.to_owned()) {
-            Some(val) => state.insert(v0.to_owned(), val),
-            None => return Err(RuntimeError::VariableOverflow(v0.to_owned())),
-          };
-        }
+      step(config, counters, operands)?;
+      if let Some(profile) = &mut counters.profile {
+        profile.assignment(prog);
       }
+      let value = apply(config, v0, operator, v1, v2)?;
+      assign(state, v0, value, counters)?;
     }
     Statement::DeclareConst(v0, v) => {
-      let v = match v {
-        Value::Variable(var) if state.contains_key(var) => {
-          state.get(var).expect("Variable not accessed?! 2")
-        }
-        Value::Variable(var) => return Err(RuntimeError::UnassignedVariable(var.to_owned())),
-        Value::Constant(c) => c,
-      };
-      state.insert(v0.to_owned(), *v);
+      let v = read(state, v)?;
+      step(config, counters, length(v))?;
+      if let Some(profile) = &mut counters.profile {
+        profile.assignment(prog);
+      }
+      assign(state, v0, v, counters)?;
     }
     Statement::While(cv, s) => {
       if !state.contains_key(cv) {
         return Err(RuntimeError::UnassignedVariable(cv.to_owned()));
       }
-      let mut i = 0;
-      while *state.get(cv).unwrap() != 0 {
-        i += 1;
-        if i > MAX_ITERATIONS {
-          println!("CV: {cv}");
-          println!("{:?}", state);
-          return Err(RuntimeError::MaxLoopsReached);
+      if let Some(profile) = &mut counters.profile {
+        profile.enter_loop(prog);
+      }
+      let before = counters.steps;
+      let result = run_loop(config, cv, s, state, counters, failed);
+      let steps = counters.steps - before;
+      if let Some(profile) = &mut counters.profile {
+        profile.exit_loop(prog, steps);
+      }
+      result?;
+    }
+  }
+  Ok(())
+}
+
+fn run_loop<'a>(
+  config: &Config,
+  cv: &str,
+  s: &'a Statement,
+  state: &mut HashMap<String, u64>,
+  counters: &mut Counters,
+  failed: &mut Option<&'a Statement>,
+) -> Result<(), RuntimeError> {
+  if config.accelerate {
+    let budget = config
+      .max_steps
+      .map(|max| max.saturating_sub(counters.steps));
+    if let Some((cost, result)) = try_accelerate(config, cv, s, state, budget) {
+      counters.steps = counters.steps.saturating_add(cost.steps);
+      counters.cost = counters.cost.saturating_add(match counters.cost_model {
+        CostModel::Uniform => cost.steps,
+        CostModel::Log => cost.log_cost,
+      });
+      counters.max_space = counters
+        .max_space
+        .max(counters.space + cost.peak_space_change);
+      counters.space = counters.space.saturating_add_signed(cost.space_change);
+      // Every variable only goes up or down, so it was largest either before or after the loop
+      for statement in s.nodes() {
+        if let Statement::DeclareOperation(v0, ..) = statement {
+          counters.max_value = counters.max_value.max(state[v0]);
         }
-        run_with_state(config, s, state)?;
       }
+      if let Some(profile) = &mut counters.profile {
+        profile.accelerated_loop(s, cost.checks, &cost.executions);
+      }
+      // The loop already ran, but its peak shows whether it went over the limit on the way
+      check_memory(counters)?;
+      if let Err((statement, error)) = result {
+        *failed = Some(statement);
+        return Err(error);
+      }
+      return Ok(());
+    }
+  }
+  let mut i = 0;
+  loop {
+    step(config, counters, length(state[cv]))?;
+    if let Some(profile) = &mut counters.profile {
+      profile.loop_check();
     }
+    if *state.get(cv).unwrap() == 0 {
+      break;
+    }
+    i += 1;
+    if i > MAX_ITERATIONS && config.max_steps.is_none() {
+      return Err(RuntimeError::MaxLoopsReached(cv.to_owned()));
+    }
+    run_with_state(config, s, state, counters, failed)?;
   }
   Ok(())
 }
@@ -100,7 +301,36 @@ pub enum RuntimeError {
   VariableOverflow(String),
   VariableUnderflow(String),
   // TODO: Detect loops by checking state
-  MaxLoopsReached,
+  // With the variable of the loop that ran too often
+  MaxLoopsReached(String),
+  StepLimitReached,
+  TimeLimitReached,
+  MemoryLimitReached,
+}
+
+impl RuntimeError {
+  /// The name of the variant, as used in test annotations.
+  pub fn kind(&self) -> &'static str {
+    match self {
+      Self::UnassignedVariable(_) => "UnassignedVariable",
+      Self::VariableOverflow(_) => "VariableOverflow",
+      Self::VariableUnderflow(_) => "VariableUnderflow",
+      Self::MaxLoopsReached(_) => "MaxLoopsReached",
+      Self::StepLimitReached => "StepLimitReached",
+      Self::TimeLimitReached => "TimeLimitReached",
+      Self::MemoryLimitReached => "MemoryLimitReached",
+    }
+  }
+
+  pub fn variable(&self) -> Option<&str> {
+    match self {
+      Self::UnassignedVariable(v)
+      | Self::VariableOverflow(v)
+      | Self::VariableUnderflow(v)
+      | Self::MaxLoopsReached(v) => Some(v),
+      Self::StepLimitReached | Self::TimeLimitReached | Self::MemoryLimitReached => None,
+    }
+  }
 }
 
 impl std::fmt::Debug for RuntimeError {
@@ -112,7 +342,35 @@ impl std::fmt::Debug for RuntimeError {
         f,
         "VariableUnderflow {v} (you can try running it with 'allow_underflow' enabled)"
       ),
-      Self::MaxLoopsReached => write!(f, "MaxLoopsReached"),
+      Self::MaxLoopsReached(v) => write!(f, "MaxLoopsReached {v}"),
+      Self::StepLimitReached => write!(f, "StepLimitReached"),
+      Self::TimeLimitReached => write!(f, "TimeLimitReached"),
+      Self::MemoryLimitReached => write!(f, "MemoryLimitReached"),
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::compile;
+
+  #[test]
+  fn endless_loops_name_their_variable() {
+    let code = "x1 := 1;\nwhile x1 != 0 do x2 := x1 + x1 od";
+    let prog = compile(&mut Config::default(), code).unwrap();
+    let config = Config {
+      accelerate: false,
+      ..Config::default()
+    };
+    let mut counters = Counters::default();
+    let error = run_counted(&config, &prog, HashMap::new(), &mut counters).unwrap_err();
+    assert_eq!(
+      (error.kind(), error.variable()),
+      ("MaxLoopsReached", Some("x1"))
+    );
+    // The loop is the statement that failed
+    let failed = prog.nodes()[counters.failed_at.unwrap()];
+    assert!(matches!(failed, Statement::While(..)));
+  }
+}
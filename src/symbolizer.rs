@@ -1,7 +1,8 @@
-use crate::Config;
+use crate::{config::ConfigOption, error::LexError, Config};
 
 const KEYWORDS: [&str; 3] = ["while", "do", "od"];
 
+#[derive(Clone, PartialEq, Eq)]
 pub enum Symbol {
   Variable(String),
   Constant(u64),
@@ -9,14 +10,67 @@ pub enum Symbol {
   Operator(Operator),
   Declare,
   NotEquals,
-  EOS,
+  OpenParen,
+  CloseParen,
+  Comma,
+  Eos,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Operator {
   Subtract,
   Add,
   Multiply,
+  // Cantor pairing: pair(a, b) = (a + b)(a + b + 1) / 2 + b, with fst and snd as its inverse
+  Pair,
+  Fst,
+  Snd,
+  // Lists: the empty list is 0, cons(h, t) = pair(h, t) + 1, and head and tail of 0 are 0
+  Cons,
+  Head,
+  Tail,
+}
+
+const FUNCTIONS: [Operator; 6] = [
+  Operator::Pair,
+  Operator::Fst,
+  Operator::Snd,
+  Operator::Cons,
+  Operator::Head,
+  Operator::Tail,
+];
+
+impl Operator {
+  /// Whether the operator is written as a function, like `pair(a, b)`, instead of `a + b`.
+  pub fn is_function(&self) -> bool {
+    FUNCTIONS.contains(self)
+  }
+
+  /// Whether the operator only takes one argument. The second value of its `DeclareOperation` is
+  /// always the constant 0 and should be ignored.
+  pub fn is_unary(&self) -> bool {
+    matches!(self, Self::Fst | Self::Snd | Self::Head | Self::Tail)
+  }
+
+  fn from_function_name(name: &str) -> Option<Self> {
+    FUNCTIONS.into_iter().find(|f| f.to_string() == name)
+  }
+}
+
+impl std::fmt::Display for Operator {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Add => write!(f, "+"),
+      Self::Subtract => write!(f, "-"),
+      Self::Multiply => write!(f, "*"),
+      Self::Pair => write!(f, "pair"),
+      Self::Fst => write!(f, "fst"),
+      Self::Snd => write!(f, "snd"),
+      Self::Cons => write!(f, "cons"),
+      Self::Head => write!(f, "head"),
+      Self::Tail => write!(f, "tail"),
+    }
+  }
 }
 impl std::fmt::Debug for Symbol {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -26,20 +80,63 @@ impl std::fmt::Debug for Symbol {
       Self::Keyword(k) => write!(f, "{k}"),
       Self::Declare => write!(f, ":="),
       Self::NotEquals => write!(f, "!="),
-      Self::Operator(Operator::Add) => write!(f, "+"),
-      Self::Operator(Operator::Subtract) => write!(f, "-"),
-      Self::Operator(Operator::Multiply) => write!(f, "*"),
-      Self::EOS => write!(f, ";"),
+      Self::OpenParen => write!(f, "("),
+      Self::CloseParen => write!(f, ")"),
+      Self::Comma => write!(f, ","),
+      Self::Operator(o) => write!(f, "{o}"),
+      Self::Eos => write!(f, ";"),
     }
   }
 }
 
-pub fn symbolize(config: &mut Config, input: &str) -> Result<Vec<Symbol>, SymbolError> {
-  let mut chars = input.chars().peekable().into_iter();
+/// Where something is in the source code, with lines and columns starting at 1 and the end
+/// being inclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Span {
+  pub line: usize,
+  pub col: usize,
+  pub end_line: usize,
+  pub end_col: usize,
+}
+
+impl Span {
+  /// The span from the start of this one to the end of `other`.
+  pub fn to(self, other: Span) -> Span {
+    Span {
+      end_line: other.end_line,
+      end_col: other.end_col,
+      ..self
+    }
+  }
+}
+
+impl std::fmt::Display for Span {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    if self.line == self.end_line {
+      write!(f, "{}:{}-{}", self.line, self.col, self.end_col)
+    } else {
+      write!(
+        f,
+        "{}:{}-{}:{}",
+        self.line, self.col, self.end_line, self.end_col
+      )
+    }
+  }
+}
+
+/// Splits the input into symbols, along with the span of every symbol. After an error it carries
+/// on with the next symbol, so that every error gets reported.
+pub fn symbolize_spanned(
+  config: &mut Config,
+  input: &str,
+) -> Result<(Vec<Symbol>, Vec<Span>), Vec<LexError>> {
+  let mut errors = vec![];
+  let mut chars = input.chars().peekable();
   let mut line = 1;
   let mut col = 0;
   let mut skip = 0;
   let mut symbols = vec![];
+  let mut spans = vec![];
 
   let mut in_comment = false;
   loop {
@@ -48,6 +145,7 @@ pub fn symbolize(config: &mut Config, input: &str) -> Result<Vec<Symbol>, Symbol
     if c.is_none() {
       break;
     }
+    let start = col;
     if in_comment {
       match c.unwrap() {
         ']' => in_comment = false,
@@ -68,13 +166,30 @@ pub fn symbolize(config: &mut Config, input: &str) -> Result<Vec<Symbol>, Symbol
           flag.push(c.unwrap());
           col += 1;
         }
-        let res = config.enable(&flag);
-        if res.is_err() {
-          return Err(SymbolError::new(
-            line,
-            col,
-            &format!("Invalid configuration flag: #{}. Run whily with --help to see the different possible options.",flag)
-          ));
+        // Valued options like #max_steps 1000
+        let mut value = None;
+        if ConfigOption::find(&flag).is_some_and(ConfigOption::takes_value) {
+          while matches!(&chars.peek(), Some(' ' | '\t')) {
+            chars.next();
+            col += 1;
+          }
+          let mut text = String::new();
+          while matches!(&chars.peek(), Some(c) if !c.is_whitespace() && !matches!(c, ';' | '[')) {
+            text.push(chars.next().unwrap());
+            col += 1;
+          }
+          value = Some(text).filter(|t| !t.is_empty());
+        }
+        if let Err(error) = config.set(&flag, value.as_deref()) {
+          errors.push(LexError::InvalidOption {
+            error,
+            span: Span {
+              line,
+              col: start,
+              end_line: line,
+              end_col: col,
+            },
+          });
         }
         continue;
       }
@@ -90,11 +205,27 @@ pub fn symbolize(config: &mut Config, input: &str) -> Result<Vec<Symbol>, Symbol
         skip = 1;
         Some(Symbol::NotEquals)
       }
+      Some('≠') if config.dialect.is_textbook() => Some(Symbol::NotEquals),
       Some('+') => Some(Symbol::Operator(Operator::Add)),
       Some('-') => Some(Symbol::Operator(Operator::Subtract)),
+      Some('−') if config.dialect.is_textbook() => Some(Symbol::Operator(Operator::Subtract)),
       Some('*') => Some(Symbol::Operator(Operator::Multiply)),
-      Some(';') => Some(Symbol::EOS),
-      Some('x') => Some(Symbol::Variable("".to_owned())),
+      Some(';') => Some(Symbol::Eos),
+      Some('(') => Some(Symbol::OpenParen),
+      Some(')') => Some(Symbol::CloseParen),
+      Some(',') => Some(Symbol::Comma),
+      // Variables like x1, other words starting with x are named variables
+      Some('x')
+        if {
+          let mut next = chars.clone();
+          if config.dialect.is_textbook() && next.peek() == Some(&'_') {
+            next.next();
+          }
+          matches!(next.peek(), Some('0'..='9'))
+        } =>
+      {
+        Some(Symbol::Variable("".to_owned()))
+      }
       Some('\n') => {
         col = 0;
         line += 1;
@@ -114,14 +245,18 @@ pub fn symbolize(config: &mut Config, input: &str) -> Result<Vec<Symbol>, Symbol
     } else if is_var || matches!(&c, Some('0'..='9')) {
       // 0..=9
       if is_var {
-        assert!(matches!(&chars.peek(), Some('0'..='9')));
+        // Subscripts like x_1
+        if config.dialect.is_textbook() && matches!(&chars.peek(), Some('_')) {
+          chars.next();
+          col += 1;
+        }
         c = chars.next();
         col += 1;
       }
-      let mut val = 0u64;
+      let mut val = Some(0u64);
       loop {
-        val *= 10;
-        val += (c.unwrap() as u8).wrapping_sub('0' as u8) as u64;
+        let digit = (c.unwrap() as u8).wrapping_sub(b'0') as u64;
+        val = val.and_then(|val| val.checked_mul(10)?.checked_add(digit));
         if matches!(&chars.peek(), Some('0'..='9')) {
           c = chars.next();
           col += 1;
@@ -129,23 +264,36 @@ pub fn symbolize(config: &mut Config, input: &str) -> Result<Vec<Symbol>, Symbol
           break;
         }
       }
+      let Some(val) = val else {
+        errors.push(LexError::NumberTooLarge {
+          span: Span {
+            line,
+            col: start,
+            end_line: line,
+            end_col: col,
+          },
+        });
+        continue;
+      };
       if is_var {
         symbol = Some(Symbol::Variable(format!("x{val}")));
       } else {
         symbol = Some(Symbol::Constant(val));
       }
     } else {
-      // KEYWORDS
-      for k in KEYWORDS {
-        let mut keyword_chars = k.chars().into_iter();
+      // KEYWORDS, which the textbook dialects accept in any case and with end for od
+      let textbook = config.dialect.is_textbook();
+      let same = |k: char, c: &char| k == *c || (textbook && k == c.to_ascii_lowercase());
+      for k in KEYWORDS.into_iter().chain(textbook.then_some("end")) {
+        let mut keyword_chars = k.chars();
         let mut cur_keyword_char = keyword_chars.next();
         let mut chars_clone = chars.clone();
-        let mut col_clone = col.clone();
+        let mut col_clone = col;
         let mut c_clone = c.as_ref();
 
         if !(cur_keyword_char.is_some()
           && c_clone.is_some()
-          && &cur_keyword_char.unwrap() == c_clone.unwrap())
+          && same(cur_keyword_char.unwrap(), c_clone.unwrap()))
         {
           continue;
         }
@@ -156,7 +304,7 @@ pub fn symbolize(config: &mut Config, input: &str) -> Result<Vec<Symbol>, Symbol
 
         while cur_keyword_char.is_some()
           && c_clone.is_some()
-          && &cur_keyword_char.unwrap() == c_clone.unwrap()
+          && same(cur_keyword_char.unwrap(), c_clone.unwrap())
         {
           chars_clone.next();
           c_clone = chars_clone.peek();
@@ -164,32 +312,76 @@ pub fn symbolize(config: &mut Config, input: &str) -> Result<Vec<Symbol>, Symbol
           cur_keyword_char = keyword_chars.next();
         }
         if cur_keyword_char.is_none() {
-          col = col_clone;
+          col = col_clone - 1;
           chars = chars_clone;
-          symbol = Some(Symbol::Keyword(k.to_string()));
+          symbol = Some(Symbol::Keyword(
+            if k == "end" { "od" } else { k }.to_string(),
+          ));
           break;
         }
       }
       if symbol.is_none() {
-        if !config.allow_named_vars {
-          return Err(SymbolError::new(
-            line,
-            col,
-            "Unknown keyword. Are you using named variables without 'allow_named_vars' enabled?",
-          ));
+        // Functions like pair(a, b)
+        let mut name = c.map(String::from).unwrap_or_default();
+        let mut chars_clone = chars.clone();
+        while let Some(next @ ('A'..='Z' | 'a'..='z' | '0'..='9' | '_')) = chars_clone.peek() {
+          name.push(*next);
+          chars_clone.next();
+        }
+        let name_span = Span {
+          line,
+          col: start,
+          end_line: line,
+          end_col: col + name.chars().count() - 1,
+        };
+        if let Some(function) = Operator::from_function_name(&name) {
+          if config.extra_operators || !config.allow_named_vars {
+            col += name.chars().count() - 1;
+            chars = chars_clone.clone();
+          }
+          if config.extra_operators {
+            symbols.push(Symbol::Operator(function));
+            spans.push(Span {
+              line,
+              col: start,
+              end_line: line,
+              end_col: col,
+            });
+            continue;
+          } else if !config.allow_named_vars {
+            errors.push(LexError::FeatureDisabled {
+              feature: format!("'{name}' as a function"),
+              option: "extra_operators",
+              span: name_span,
+            });
+            continue;
+          }
         }
 
         // make it a variable
         match c {
           Some('A'..='Z') | Some('a'..='z') | Some('0'..='9') | Some('_') => {}
           _ => {
-            return Err(SymbolError::new(
-              line,
-              col,
-              "Unknown keyword or invalid variable name",
-            ))
+            errors.push(LexError::InvalidCharacter {
+              character: c.unwrap(),
+              span: Span {
+                end_col: start,
+                ..name_span
+              },
+            });
+            continue;
           }
         }
+        if !config.allow_named_vars {
+          col += name.chars().count() - 1;
+          chars = chars_clone;
+          errors.push(LexError::FeatureDisabled {
+            feature: format!("the named variable '{name}'"),
+            option: "allow_named_vars",
+            span: name_span,
+          });
+          continue;
+        }
         let mut variable_name = format!("{}", c.unwrap());
         loop {
           match chars.peek() {
@@ -198,6 +390,7 @@ pub fn symbolize(config: &mut Config, input: &str) -> Result<Vec<Symbol>, Symbol
             _ => break,
           }
           c = chars.next();
+          col += 1;
           variable_name = format!("{variable_name}{}", c.unwrap());
         }
         symbol = Some(Symbol::Variable(variable_name));
@@ -205,32 +398,16 @@ pub fn symbolize(config: &mut Config, input: &str) -> Result<Vec<Symbol>, Symbol
     }
     assert!(symbol.is_some());
     symbols.push(symbol.unwrap());
-  }
-
-  Ok(symbols)
-}
-
-pub struct SymbolError {
-  msg: String,
-  line: usize,
-  pos: usize,
-}
-impl SymbolError {
-  fn new(line: usize, pos: usize, msg: &str) -> Self {
-    Self {
+    spans.push(Span {
       line,
-      pos,
-      msg: msg.to_string(),
-    }
+      col: start,
+      end_line: line,
+      end_col: col,
+    });
   }
-}
 
-impl std::fmt::Debug for SymbolError {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    write!(
-      f,
-      "Syntax error on line {}:{}: {}",
-      self.line, self.pos, self.msg
-    )
+  match errors.is_empty() {
+    true => Ok((symbols, spans)),
+    false => Err(errors),
   }
 }
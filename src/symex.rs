@@ -0,0 +1,793 @@
+use crate::{
+  config::Config,
+  godel::evaluate_function,
+  output::sorted_variables,
+  parser::{Statement, Value},
+  symbolizer::{Operator, Span},
+};
+use num_bigint::BigInt;
+use num_traits::{One, Signed, Zero};
+use std::{
+  collections::{BTreeMap, HashMap},
+  fmt::Write,
+};
+
+// Runs a program on symbolic inputs. Every value is a polynomial over the inputs, and every
+// `while` test forks the path into one where the loop variable is 0 and one where it isn't, up
+// to a given amount of iterations per loop. Subtraction forks as well when the result may be
+// negative, into a path where it isn't and one that underflows (or gets 0 with `allow_underflow`).
+//
+// Every path keeps the conditions it was taken under. Conditions of the form `xi = c`, and
+// `p = 0` for polynomials without negative coefficients, are solved by substituting the inputs,
+// and `xi != 0` is kept as a lower bound of 1. A condition is decided without forking when the
+// polynomial is provably nonnegative (or negative) after shifting every input by its lower bound,
+// so contradicting paths are mostly, but not always, pruned.
+//
+// The result of a function like `pair(a, b)` that isn't constant becomes a new opaque input.
+// Overflow is flagged when a value can exceed 64 bits with every input at most `bound`.
+
+const MAX_PATHS: usize = 1000;
+
+// The atoms with their exponents, sorted by atom
+type Monomial = Vec<(String, u32)>;
+
+#[derive(Clone, PartialEq, Eq)]
+struct Poly(BTreeMap<Monomial, BigInt>);
+
+impl Poly {
+  fn constant(c: impl Into<BigInt>) -> Self {
+    let mut poly = Poly(BTreeMap::new());
+    poly.add_term(vec![], c.into());
+    poly
+  }
+
+  fn atom(name: &str) -> Self {
+    Poly(BTreeMap::from([(
+      vec![(name.to_owned(), 1)],
+      BigInt::one(),
+    )]))
+  }
+
+  fn add_term(&mut self, monomial: Monomial, c: BigInt) {
+    let total = self.0.remove(&monomial).unwrap_or_default() + c;
+    if !total.is_zero() {
+      self.0.insert(monomial, total);
+    }
+  }
+
+  fn add(&self, other: &Poly) -> Poly {
+    let mut out = self.clone();
+    for (m, c) in &other.0 {
+      out.add_term(m.clone(), c.clone());
+    }
+    out
+  }
+
+  fn neg(&self) -> Poly {
+    Poly(self.0.iter().map(|(m, c)| (m.clone(), -c)).collect())
+  }
+
+  fn sub(&self, other: &Poly) -> Poly {
+    self.add(&other.neg())
+  }
+
+  fn mul(&self, other: &Poly) -> Poly {
+    let mut out = Poly::constant(0);
+    for (m1, c1) in &self.0 {
+      for (m2, c2) in &other.0 {
+        let mut m = m1.clone();
+        for (atom, e) in m2 {
+          match m.iter_mut().find(|(a, _)| a == atom) {
+            Some((_, existing)) => *existing += e,
+            None => m.push((atom.clone(), *e)),
+          }
+        }
+        m.sort();
+        out.add_term(m, c1 * c2);
+      }
+    }
+    out
+  }
+
+  fn as_constant(&self) -> Option<BigInt> {
+    match self.0.iter().next() {
+      None => Some(BigInt::zero()),
+      Some((m, c)) if m.is_empty() && self.0.len() == 1 => Some(c.clone()),
+      _ => None,
+    }
+  }
+
+  /// `atom + c` as the atom and c.
+  fn as_shifted_atom(&self) -> Option<(&str, BigInt)> {
+    let c = self.0.get(&vec![]).cloned().unwrap_or_default();
+    let mut terms = self.0.iter().filter(|(m, _)| !m.is_empty());
+    match (terms.next(), terms.next()) {
+      (Some((m, coefficient)), None) if m.len() == 1 && m[0].1 == 1 && coefficient.is_one() => {
+        Some((&m[0].0, c))
+      }
+      _ => None,
+    }
+  }
+
+  /// Replaces the atom by a polynomial.
+  fn substitute(&self, atom: &str, value: &Poly) -> Poly {
+    let mut out = Poly::constant(0);
+    for (m, c) in &self.0 {
+      let mut term = Poly::constant(c.clone());
+      for (a, e) in m {
+        let factor = if a == atom {
+          value.clone()
+        } else {
+          Poly::atom(a)
+        };
+        for _ in 0..*e {
+          term = term.mul(&factor);
+        }
+      }
+      out = out.add(&term);
+    }
+    out
+  }
+
+  fn atoms(&self) -> Vec<&str> {
+    let mut atoms = self
+      .0
+      .keys()
+      .flatten()
+      .map(|(a, _)| a.as_str())
+      .collect::<Vec<_>>();
+    atoms.sort();
+    atoms.dedup();
+    atoms
+  }
+}
+
+impl std::fmt::Display for Poly {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    if self.0.is_empty() {
+      return write!(f, "0");
+    }
+    let degree = |m: &Monomial| m.iter().map(|(_, e)| e).sum::<u32>();
+    let mut terms = self.0.iter().collect::<Vec<_>>();
+    // Highest degree first, then the highest powers of the first atoms
+    let exponents = |m: &Monomial| {
+      m.iter()
+        .map(|(a, e)| (a.clone(), u32::MAX - e))
+        .collect::<Vec<_>>()
+    };
+    terms.sort_by(|(a, _), (b, _)| {
+      degree(b)
+        .cmp(&degree(a))
+        .then_with(|| exponents(a).cmp(&exponents(b)))
+    });
+    for (i, (m, c)) in terms.into_iter().enumerate() {
+      match (i, c.is_negative()) {
+        (0, true) => write!(f, "-")?,
+        (0, false) => {}
+        (_, true) => write!(f, " - ")?,
+        (_, false) => write!(f, " + ")?,
+      }
+      let factors = m
+        .iter()
+        .map(|(a, e)| match e {
+          1 => a.to_owned(),
+          e => format!("{a}^{e}"),
+        })
+        .collect::<Vec<_>>();
+      match (c.abs().is_one(), factors.is_empty()) {
+        (_, true) => write!(f, "{}", c.abs())?,
+        (true, false) => write!(f, "{}", factors.join("*"))?,
+        (false, false) => write!(f, "{}*{}", c.abs(), factors.join("*"))?,
+      }
+    }
+    Ok(())
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Relation {
+  Zero,
+  NonZero,
+  NonNegative,
+  Negative,
+}
+
+impl Relation {
+  fn holds(self, value: &BigInt) -> bool {
+    match self {
+      Relation::Zero => value.is_zero(),
+      Relation::NonZero => !value.is_zero(),
+      Relation::NonNegative => !value.is_negative(),
+      Relation::Negative => value.is_negative(),
+    }
+  }
+
+  /// Whether `other` holds for a polynomial that this relation holds for, if that follows.
+  fn implies(self, other: Relation) -> Option<bool> {
+    use Relation::*;
+    match (self, other) {
+      (a, b) if a == b => Some(true),
+      (Zero, NonNegative) | (Negative, NonZero) => Some(true),
+      (Zero, _) | (_, Zero) if self != NonNegative => Some(false),
+      (NonNegative, Negative) | (Negative, NonNegative) => Some(false),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Clone)]
+struct Condition {
+  poly: Poly,
+  relation: Relation,
+}
+
+impl std::fmt::Display for Condition {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let relation = match self.relation {
+      Relation::Zero => "= 0",
+      Relation::NonZero => "!= 0",
+      Relation::NonNegative => ">= 0",
+      Relation::Negative => "< 0",
+    };
+    write!(f, "{} {relation}", self.poly)
+  }
+}
+
+#[derive(Clone, Copy)]
+enum Task<'a> {
+  Run(&'a Statement),
+  // A loop with the amount of iterations it already did
+  Loop(&'a Statement, u64),
+}
+
+#[derive(Clone)]
+struct Path<'a> {
+  state: HashMap<String, Poly>,
+  conditions: Vec<Condition>,
+  // Inputs that the conditions fixed to a value
+  fixed: Vec<(String, BigInt)>,
+  // The lower and upper bounds the conditions put on inputs
+  ranges: BTreeMap<String, (BigInt, Option<BigInt>)>,
+  // Upper bounds of the opaque inputs, the others are at most `bound`
+  opaque: HashMap<String, BigInt>,
+  // Upper bounds of the variables, following the operations that computed them
+  limits: HashMap<String, BigInt>,
+  warnings: Vec<String>,
+  tasks: Vec<Task<'a>>,
+}
+
+/// One path through the program, as text.
+pub struct SymbolicPath {
+  pub ending: String,
+  pub conditions: Vec<String>,
+  pub state: Vec<(String, String)>,
+  pub warnings: Vec<String>,
+}
+
+enum Next<'a> {
+  Continue(Path<'a>),
+  Done(SymbolicPath),
+}
+
+impl Path<'_> {
+  fn range(&self, atom: &str) -> (BigInt, Option<BigInt>) {
+    self.ranges.get(atom).cloned().unwrap_or_default()
+  }
+
+  /// Whether the polynomial is at least 0 for all inputs above their lower bounds.
+  fn nonnegative(&self, poly: &Poly) -> bool {
+    let mut shifted = poly.clone();
+    for atom in poly.atoms() {
+      let (lower, _) = self.range(atom);
+      if !lower.is_zero() {
+        shifted = shifted.substitute(atom, &Poly::atom(atom).add(&Poly::constant(lower)));
+      }
+    }
+    shifted.0.values().all(|c| !c.is_negative())
+  }
+
+  /// Whether the relation always or never holds, if that can be shown.
+  fn decide(&self, poly: &Poly, relation: Relation) -> Option<bool> {
+    if let Some(c) = poly.as_constant() {
+      return Some(relation.holds(&c));
+    }
+    if let Some((atom, c)) = poly.as_shifted_atom() {
+      let (lower, upper) = self.range(atom);
+      // The polynomial is positive or negative on the whole range of the input
+      let positive = (lower + &c).is_positive();
+      let negative = upper.is_some_and(|u| (u + &c).is_negative());
+      if positive || negative {
+        return Some(match relation {
+          Relation::Zero => false,
+          Relation::NonZero => true,
+          Relation::NonNegative => positive,
+          Relation::Negative => negative,
+        });
+      }
+    }
+    for known in self.conditions.iter().filter(|c| c.poly == *poly) {
+      if let Some(holds) = known.relation.implies(relation) {
+        return Some(holds);
+      }
+    }
+    let one = Poly::constant(1);
+    if self.nonnegative(&poly.sub(&one)) {
+      return Some(matches!(
+        relation,
+        Relation::NonZero | Relation::NonNegative
+      ));
+    }
+    if self.nonnegative(&poly.neg().sub(&one)) {
+      return Some(matches!(relation, Relation::NonZero | Relation::Negative));
+    }
+    if self.nonnegative(poly) {
+      match relation {
+        Relation::NonNegative => return Some(true),
+        Relation::Negative => return Some(false),
+        _ => {}
+      }
+    }
+    None
+  }
+
+  /// Adds a condition, returning false if the path became impossible.
+  fn assume(&mut self, poly: Poly, relation: Relation) -> bool {
+    if let Some(holds) = self.decide(&poly, relation) {
+      return holds;
+    }
+    if let Some((atom, c)) = poly.as_shifted_atom() {
+      let atom = atom.to_owned();
+      let (lower, upper) = self.range(&atom);
+      let value = -c;
+      match relation {
+        Relation::Zero => return self.narrow(&atom, value.clone(), Some(value)),
+        Relation::NonNegative => return self.narrow(&atom, value, None),
+        Relation::Negative => return self.narrow(&atom, BigInt::zero(), Some(value - 1)),
+        Relation::NonZero if value == lower => return self.narrow(&atom, value + 1, None),
+        Relation::NonZero if Some(&value) == upper.as_ref() => {
+          return self.narrow(&atom, BigInt::zero(), Some(value - 1))
+        }
+        Relation::NonZero => {}
+      }
+    } else if relation == Relation::Zero && poly.0.values().all(|c| !c.is_negative()) {
+      // Every term has to be 0, so the inputs of the linear terms are
+      let linear = poly
+        .0
+        .keys()
+        .filter(|m| m.len() == 1 && m[0].1 == 1)
+        .map(|m| m[0].0.clone())
+        .collect::<Vec<_>>();
+      if !linear.is_empty() {
+        let mut rest = poly.clone();
+        for atom in linear {
+          rest = rest.substitute(&atom, &Poly::constant(0));
+          if !self.narrow(&atom, BigInt::zero(), Some(BigInt::zero())) {
+            return false;
+          }
+        }
+        return self.assume(rest, relation);
+      }
+    }
+    self.conditions.push(Condition { poly, relation });
+    true
+  }
+
+  /// Narrows the range of an input, returning false if it became empty.
+  fn narrow(&mut self, atom: &str, lower: BigInt, upper: Option<BigInt>) -> bool {
+    let (old_lower, old_upper) = self.range(atom);
+    let lower = lower.max(old_lower);
+    let upper = match (upper, old_upper) {
+      (Some(a), Some(b)) => Some(a.min(b)),
+      (a, b) => a.or(b),
+    };
+    match upper {
+      Some(upper) if upper < lower => false,
+      Some(upper) if upper == lower => self.fix(atom, lower),
+      upper => {
+        self.ranges.insert(atom.to_owned(), (lower, upper));
+        // Conditions on the input may follow from its range now
+        let conditions = std::mem::take(&mut self.conditions);
+        conditions
+          .into_iter()
+          .all(|c| self.assume(c.poly, c.relation))
+      }
+    }
+  }
+
+  /// Substitutes a value for an input everywhere.
+  fn fix(&mut self, atom: &str, value: BigInt) -> bool {
+    self.ranges.remove(atom);
+    let constant = Poly::constant(value.clone());
+    for poly in self.state.values_mut() {
+      *poly = poly.substitute(atom, &constant);
+    }
+    self.fixed.push((atom.to_owned(), value));
+    let conditions = std::mem::take(&mut self.conditions);
+    conditions
+      .into_iter()
+      .all(|c| self.assume(c.poly.substitute(atom, &constant), c.relation))
+  }
+
+  /// The largest value the polynomial can have, ignoring its negative terms.
+  fn upper(&self, poly: &Poly, bound: &BigInt) -> BigInt {
+    let mut total = BigInt::zero();
+    for (m, c) in poly.0.iter().filter(|(_, c)| c.is_positive()) {
+      let mut term = c.clone();
+      for (atom, e) in m {
+        let upper = match (self.opaque.get(atom), self.range(atom).1) {
+          (Some(upper), _) => upper.clone(),
+          (None, Some(upper)) => upper.min(bound.clone()),
+          (None, None) => bound.clone(),
+        };
+        term *= upper.pow(*e);
+      }
+      total += term;
+    }
+    total
+  }
+
+  /// The conditions of the path, starting with the fixed inputs and the ranges of inputs.
+  fn describe_conditions(&self) -> Vec<String> {
+    let mut conditions = self
+      .fixed
+      .iter()
+      .map(|(atom, value)| format!("{atom} = {value}"))
+      .collect::<Vec<_>>();
+    for (atom, (lower, upper)) in &self.ranges {
+      conditions.push(match upper {
+        None => format!("{atom} >= {lower}"),
+        Some(upper) if lower.is_zero() => format!("{atom} <= {upper}"),
+        Some(upper) => format!("{lower} <= {atom} <= {upper}"),
+      });
+    }
+    conditions.extend(self.conditions.iter().map(Condition::to_string));
+    conditions
+  }
+}
+
+pub struct Explorer<'a> {
+  config: &'a Config,
+  ids: HashMap<*const Statement, usize>,
+  spans: &'a [Span],
+  unroll: u64,
+  bound: BigInt,
+}
+
+impl<'a> Explorer<'a> {
+  pub fn new(
+    config: &'a Config,
+    prog: &'a Statement,
+    spans: &'a [Span],
+    unroll: u64,
+    bound: u64,
+  ) -> Self {
+    Self {
+      config,
+      ids: prog
+        .nodes()
+        .into_iter()
+        .enumerate()
+        .map(|(id, s)| (s as *const Statement, id))
+        .collect(),
+      spans,
+      unroll,
+      bound: BigInt::from(bound),
+    }
+  }
+
+  fn span(&self, statement: &Statement) -> String {
+    self
+      .ids
+      .get(&(statement as *const Statement))
+      .and_then(|&id| self.spans.get(id))
+      .map_or("?".to_owned(), Span::to_string)
+  }
+
+  fn done(&self, path: Path, ending: String) -> Next<'a> {
+    let conditions = path.describe_conditions();
+    let state = sorted_variables(&path.state)
+      .into_iter()
+      .map(|var| (var.to_owned(), path.state[var].to_string()))
+      .collect();
+    Next::Done(SymbolicPath {
+      ending,
+      conditions,
+      state,
+      warnings: path.warnings,
+    })
+  }
+
+  fn read(&self, path: &Path, value: &Value) -> Result<Poly, String> {
+    match value {
+      Value::Constant(c) => Ok(Poly::constant(*c)),
+      Value::Variable(var) => match path.state.get(var) {
+        Some(poly) => Ok(poly.clone()),
+        None if self.config.dialect.is_textbook() => Ok(Poly::constant(0)),
+        None => Err(var.to_owned()),
+      },
+    }
+  }
+
+  /// The largest value of an operand, which is at most the largest value of its polynomial.
+  fn limit(&self, path: &Path, value: &Value, poly: &Poly) -> BigInt {
+    let upper = path.upper(poly, &self.bound);
+    match value {
+      Value::Variable(var) => path
+        .limits
+        .get(var)
+        .map_or(upper.clone(), |limit| limit.min(&upper).clone()),
+      Value::Constant(_) => upper,
+    }
+  }
+
+  /// Assigns a value that was just computed, checking it for overflow.
+  fn store(
+    &self,
+    mut path: Path<'a>,
+    statement: &Statement,
+    v0: &str,
+    value: Poly,
+    limit: BigInt,
+  ) -> Vec<Next<'a>> {
+    let max = BigInt::from(u64::MAX);
+    if value.as_constant().is_some_and(|c| c > max) {
+      let ending = format!("VariableOverflow {v0} at {}", self.span(statement));
+      return vec![self.done(path, ending)];
+    }
+    if limit > max {
+      let warning = format!("overflow possible at {}", self.span(statement));
+      if !path.warnings.contains(&warning) {
+        path.warnings.push(warning);
+      }
+    }
+    path.limits.insert(v0.to_owned(), limit.min(max));
+    path.state.insert(v0.to_owned(), value);
+    vec![Next::Continue(path)]
+  }
+
+  fn assignment(&self, mut path: Path<'a>, statement: &'a Statement) -> Vec<Next<'a>> {
+    let (v0, v1, operator, v2) = match statement {
+      Statement::DeclareOperation(v0, v1, operator, v2) => (v0, v1, operator, v2),
+      Statement::DeclareConst(v0, v) => (v0, v, &Operator::Add, &Value::Constant(0)),
+      _ => unreachable!("Not an assignment"),
+    };
+    let (p1, p2) = match (self.read(&path, v1), self.read(&path, v2)) {
+      (Ok(p1), Ok(p2)) => (p1, p2),
+      (Err(var), _) | (_, Err(var)) => {
+        let ending = format!("UnassignedVariable {var} at {}", self.span(statement));
+        return vec![self.done(path, ending)];
+      }
+    };
+    let (u1, u2) = (self.limit(&path, v1, &p1), self.limit(&path, v2, &p2));
+    match operator {
+      Operator::Add => self.store(path, statement, v0, p1.add(&p2), u1 + u2),
+      Operator::Multiply => self.store(path, statement, v0, p1.mul(&p2), u1 * u2),
+      Operator::Subtract => {
+        let difference = p1.sub(&p2);
+        let mut out = vec![];
+        let mut negative = path.clone();
+        if negative.assume(difference.clone(), Relation::Negative) {
+          if self.config.underflow_allowed() {
+            out.extend(self.store(negative, statement, v0, Poly::constant(0), BigInt::zero()));
+          } else {
+            let ending = format!("VariableUnderflow {v0} at {}", self.span(statement));
+            out.push(self.done(negative, ending));
+          }
+        }
+        if path.assume(difference.clone(), Relation::NonNegative) {
+          out.extend(self.store(path, statement, v0, difference, u1));
+        }
+        out
+      }
+      function => {
+        if let (Some(c1), Some(c2)) = (p1.as_constant(), p2.as_constant()) {
+          let value = u64::try_from(c1)
+            .ok()
+            .zip(u64::try_from(c2).ok())
+            .and_then(|(c1, c2)| evaluate_function(function, c1, c2));
+          return match value {
+            Some(value) => self.store(path, statement, v0, Poly::constant(value), value.into()),
+            None => {
+              let ending = format!("VariableOverflow {v0} at {}", self.span(statement));
+              vec![self.done(path, ending)]
+            }
+          };
+        }
+        let name = if function.is_unary() {
+          format!("{function}({p1})")
+        } else {
+          format!("{function}({p1}, {p2})")
+        };
+        let upper = match function {
+          Operator::Pair | Operator::Cons => {
+            let sum = &u1 + &u2;
+            (&sum * (&sum + 1)) / 2 + &u2 + BigInt::from(u8::from(*function == Operator::Cons))
+          }
+          _ => u1,
+        };
+        // Beyond 64 bits the exact bound doesn't matter anymore
+        let upper = upper.min(BigInt::from(u64::MAX) + 1);
+        path.opaque.insert(name.clone(), upper.clone());
+        self.store(path, statement, v0, Poly::atom(&name), upper)
+      }
+    }
+  }
+
+  fn advance(&self, mut path: Path<'a>) -> Vec<Next<'a>> {
+    let Some(task) = path.tasks.pop() else {
+      return vec![self.done(path, "finished".to_owned())];
+    };
+    match task {
+      Task::Run(Statement::S(left, right)) => {
+        path.tasks.push(Task::Run(right));
+        path.tasks.push(Task::Run(left));
+        vec![Next::Continue(path)]
+      }
+      Task::Run(statement @ Statement::While(..)) => {
+        path.tasks.push(Task::Loop(statement, 0));
+        vec![Next::Continue(path)]
+      }
+      Task::Run(statement) => self.assignment(path, statement),
+      Task::Loop(statement, iterations) => {
+        let Statement::While(cv, body) = statement else {
+          unreachable!("Not a loop")
+        };
+        let value = match self.read(&path, &Value::Variable(cv.to_owned())) {
+          Ok(value) => value,
+          Err(var) => {
+            let ending = format!("UnassignedVariable {var} at {}", self.span(statement));
+            return vec![self.done(path, ending)];
+          }
+        };
+        let mut out = vec![];
+        let mut exit = path.clone();
+        if path.assume(value.clone(), Relation::NonZero) {
+          if iterations == self.unroll {
+            let ending = format!(
+              "unrolled the loop at {} {iterations} times",
+              self.span(statement)
+            );
+            out.push(self.done(path, ending));
+          } else {
+            path.tasks.push(Task::Loop(statement, iterations + 1));
+            path.tasks.push(Task::Run(body));
+            out.push(Next::Continue(path));
+          }
+        }
+        if exit.assume(value, Relation::Zero) {
+          out.push(Next::Continue(exit));
+        }
+        out
+      }
+    }
+  }
+
+  /// Explores the paths through the program with the given variables as symbolic inputs. Also
+  /// returns whether there were too many paths to explore all of them.
+  pub fn explore(&self, prog: &'a Statement, symbolic: &[String]) -> (Vec<SymbolicPath>, bool) {
+    let start = Path {
+      state: symbolic
+        .iter()
+        .map(|var| (var.to_owned(), Poly::atom(var)))
+        .collect(),
+      conditions: vec![],
+      fixed: vec![],
+      ranges: BTreeMap::new(),
+      opaque: HashMap::new(),
+      limits: HashMap::new(),
+      warnings: vec![],
+      tasks: vec![Task::Run(prog)],
+    };
+    let mut paths = vec![start];
+    let mut done = vec![];
+    while let Some(path) = paths.pop() {
+      if done.len() + paths.len() >= MAX_PATHS {
+        return (done, true);
+      }
+      for next in self.advance(path) {
+        match next {
+          Next::Continue(path) => paths.push(path),
+          Next::Done(path) => done.push(path),
+        }
+      }
+    }
+    (done, false)
+  }
+}
+
+/// The paths with their conditions, warnings and final states.
+pub fn render_paths(paths: &[SymbolicPath], truncated: bool) -> String {
+  let mut out = String::new();
+  for (i, path) in paths.iter().enumerate() {
+    writeln!(out, "Path {} ({}):", i + 1, path.ending).unwrap();
+    let conditions = match path.conditions.is_empty() {
+      true => "always".to_owned(),
+      false => path.conditions.join(", "),
+    };
+    writeln!(out, "  when {conditions}").unwrap();
+    for warning in &path.warnings {
+      writeln!(out, "  {warning}").unwrap();
+    }
+    let width = path.state.iter().map(|(v, _)| v.len()).max().unwrap_or(0);
+    for (var, value) in &path.state {
+      writeln!(out, "  {var:<width$} = {value}").unwrap();
+    }
+  }
+  if truncated {
+    writeln!(out, "Stopped after {MAX_PATHS} paths.").unwrap();
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::compile_spanned;
+
+  /// The paths through the code, with every loop unrolled at most `unroll` times.
+  fn explore(code: &str, symbolic: &[&str], unroll: u64) -> Vec<SymbolicPath> {
+    let mut config = Config::default();
+    let (prog, spans) = compile_spanned(&mut config, code).expect("Invalid test program");
+    let symbolic = symbolic
+      .iter()
+      .map(|&var| var.to_owned())
+      .collect::<Vec<_>>();
+    let (paths, truncated) =
+      Explorer::new(&config, &prog, &spans, unroll, 1000).explore(&prog, &symbolic);
+    assert!(!truncated);
+    paths
+  }
+
+  fn endings(paths: &[SymbolicPath]) -> Vec<(&str, String)> {
+    paths
+      .iter()
+      .map(|path| (path.ending.as_str(), path.conditions.join(", ")))
+      .collect()
+  }
+
+  #[test]
+  fn multiplication_gives_a_product() {
+    let paths = explore("#extra_operators\nx0 := x1 * x2", &["x1", "x2"], 5);
+    assert_eq!(endings(&paths), [("finished", String::new())]);
+    assert_eq!(paths[0].state[0], ("x0".to_owned(), "x1*x2".to_owned()));
+    assert!(paths[0].warnings.is_empty());
+  }
+
+  #[test]
+  fn loops_are_unrolled() {
+    let code = "#allow_constants_everywhere\nwhile x1 != 0 do\n  x1 := x1 - 1\nod";
+    let paths = explore(code, &["x1"], 2);
+    assert_eq!(
+      endings(&paths),
+      [
+        ("finished", "x1 = 0".to_owned()),
+        ("finished", "x1 = 1".to_owned()),
+        ("unrolled the loop at 2:1-4:2 2 times", "x1 >= 3".to_owned()),
+        ("finished", "x1 = 2".to_owned()),
+      ]
+    );
+    assert_eq!(paths[2].state, [("x1".to_owned(), "x1 - 2".to_owned())]);
+  }
+
+  #[test]
+  fn underflow_is_only_flagged_where_possible() {
+    let paths = explore("x0 := x1 - x2", &["x1", "x2"], 5);
+    assert_eq!(
+      endings(&paths),
+      [
+        ("VariableUnderflow x0 at 1:1-13", "x1 - x2 < 0".to_owned()),
+        ("finished", "x1 - x2 >= 0".to_owned()),
+      ]
+    );
+    // x2 - x1 can't underflow, and x1 - 3 only can in the loop, where x1 isn't 0
+    let code = "#allow_constants_everywhere\nx2 := x1 + 1;\nx0 := x2 - x1;\nwhile x1 != 0 do\n  x3 := x1 - 3;\n  x1 := 0\nod";
+    assert_eq!(
+      endings(&explore(code, &["x1"], 5)),
+      [
+        ("finished", "x1 = 0".to_owned()),
+        ("VariableUnderflow x3 at 5:3-14", "1 <= x1 <= 2".to_owned()),
+        ("finished", "x1 >= 3".to_owned()),
+      ]
+    );
+  }
+}
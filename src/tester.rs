@@ -0,0 +1,531 @@
+use crate::{compile, error::render_all, godel::var_index, run::run_with_input, Config};
+use std::{
+  collections::HashMap,
+  fmt::Write,
+  path::{Path, PathBuf},
+  time::{Duration, Instant},
+};
+
+// Test cases are written in comments of the program they test:
+//   [test: x1=3, x2=4 => x0=12]
+//   [test: x1=0 => error VariableUnderflow]
+//   [test: => error UnassignedVariable x3]
+// The variables before the arrow are assigned before the program runs. After it, either the
+// values some variables must have at the end, or the kind of error the program must stop with,
+// optionally followed by the variable the error is about.
+
+enum Expected {
+  State(Vec<(String, u64)>),
+  Error(String, Option<String>),
+}
+
+struct Case {
+  line: usize,
+  text: String,
+  spec: Result<(Vec<(String, u64)>, Expected), String>,
+}
+
+struct Outcome {
+  name: String,
+  time: Duration,
+  // The reason and the details of the failure, if the case failed
+  failure: Option<(String, String)>,
+}
+
+struct FileReport {
+  path: PathBuf,
+  outcomes: Vec<Outcome>,
+}
+
+fn normalise_var(var: &str) -> Result<String, String> {
+  if var.is_empty() || var.contains(char::is_whitespace) {
+    return Err(format!("'{var}' is not a variable."));
+  }
+  Ok(match var_index(var) {
+    Some(i) => format!("x{i}"),
+    None => var.to_owned(),
+  })
+}
+
+fn parse_assignments(text: &str) -> Result<Vec<(String, u64)>, String> {
+  text
+    .split(',')
+    .map(str::trim)
+    .filter(|a| !a.is_empty())
+    .map(|a| {
+      let Some((var, value)) = a.split_once('=') else {
+        return Err(format!("Expected 'variable=value', found '{a}'."));
+      };
+      let value = value.trim();
+      let value = value
+        .parse()
+        .map_err(|_| format!("'{value}' is not a natural number below 2^64."))?;
+      Ok((normalise_var(var.trim())?, value))
+    })
+    .collect()
+}
+
+fn parse_case(text: &str) -> Result<(Vec<(String, u64)>, Expected), String> {
+  let Some((inputs, expected)) = text.split_once("=>") else {
+    return Err("Missing '=>' between the inputs and the expected result.".to_owned());
+  };
+  let inputs = parse_assignments(inputs)?;
+  let expected = expected.trim();
+  let expected = match expected.strip_prefix("error") {
+    Some(error) => {
+      let mut words = error.split_whitespace();
+      let Some(kind) = words.next() else {
+        return Err("Missing the kind of error after 'error'.".to_owned());
+      };
+      let var = words.next().map(normalise_var).transpose()?;
+      if let Some(word) = words.next() {
+        return Err(format!("Unexpected '{word}' after the expected error."));
+      }
+      Expected::Error(kind.to_owned(), var)
+    }
+    None => Expected::State(parse_assignments(expected)?),
+  };
+  Ok((inputs, expected))
+}
+
+/// Finds the `[test: ...]` comments in the source code.
+fn find_cases(code: &str) -> Vec<Case> {
+  let mut cases = vec![];
+  let mut rest = code;
+  let mut line = 1;
+  while let Some(start) = rest.find('[') {
+    line += rest[..start].matches('\n').count();
+    let comment = &rest[start + 1..];
+    let end = comment.find(']').unwrap_or(comment.len());
+    let body = comment[..end].trim();
+    if let Some(text) = body.strip_prefix("test:") {
+      let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+      cases.push(Case {
+        line,
+        spec: parse_case(&text),
+        text,
+      });
+    }
+    line += comment[..end].matches('\n').count();
+    rest = &comment[(end + 1).min(comment.len())..];
+  }
+  cases
+}
+
+fn check(
+  expected: &Expected,
+  result: Result<HashMap<String, u64>, crate::run::RuntimeError>,
+) -> Option<(String, String)> {
+  match (expected, result) {
+    (Expected::State(expected), Ok(state)) => {
+      let mut diff = String::new();
+      for (var, value) in expected {
+        match state.get(var) {
+          Some(actual) if actual == value => {}
+          Some(actual) => writeln!(diff, "{var}: expected {value}, got {actual}").unwrap(),
+          None => writeln!(diff, "{var}: expected {value}, but it was never assigned").unwrap(),
+        }
+      }
+      (!diff.is_empty()).then(|| ("Wrong final state".to_owned(), diff))
+    }
+    (Expected::State(_), Err(e)) => Some((
+      "Unexpected runtime error".to_owned(),
+      format!("The program stopped with {e:?}\n"),
+    )),
+    (Expected::Error(kind, var), Ok(state)) => {
+      let mut keys = state.keys().collect::<Vec<_>>();
+      keys.sort();
+      let mut details = String::new();
+      for key in keys {
+        writeln!(details, "{key} = {}", state[key]).unwrap();
+      }
+      let var = var.as_ref().map_or(String::new(), |v| format!(" {v}"));
+      Some((
+        format!("Expected error {kind}{var}, but the program finished"),
+        details,
+      ))
+    }
+    (Expected::Error(kind, var), Err(e)) => {
+      let kind_matches = e.kind() == kind;
+      let var_matches = var.as_deref().is_none_or(|v| e.variable() == Some(v));
+      (!(kind_matches && var_matches)).then(|| {
+        let var = var.as_ref().map_or(String::new(), |v| format!(" {v}"));
+        (
+          format!("Expected error {kind}{var}"),
+          format!("The program stopped with {e:?}\n"),
+        )
+      })
+    }
+  }
+}
+
+fn test_file(base: Result<Config, String>, path: &Path) -> FileReport {
+  let failed = |name: &str, reason: &str, details: String| FileReport {
+    path: path.to_owned(),
+    outcomes: vec![Outcome {
+      name: name.to_owned(),
+      time: Duration::ZERO,
+      failure: Some((reason.to_owned(), details)),
+    }],
+  };
+  let code = match std::fs::read_to_string(path) {
+    Ok(code) => code,
+    Err(e) => return failed("read", "Could not read the file", format!("{e}\n")),
+  };
+  let cases = find_cases(&code);
+  if cases.is_empty() {
+    return FileReport {
+      path: path.to_owned(),
+      outcomes: vec![],
+    };
+  }
+  let mut config = match base {
+    Ok(config) => config,
+    Err(e) => return failed("config", "Invalid options", format!("{e}\n")),
+  };
+  let prog = match compile(&mut config, &code) {
+    Ok(prog) => prog,
+    Err(e) => {
+      return failed(
+        "compile",
+        "Could not parse the program",
+        format!("{}\n", render_all(&e, &code)),
+      )
+    }
+  };
+
+  let outcomes = cases
+    .into_iter()
+    .map(|case| {
+      let name = format!("line {}: {}", case.line, case.text);
+      let start = Instant::now();
+      let failure = match case.spec {
+        Err(e) => Some(("Invalid test annotation".to_owned(), format!("{e}\n"))),
+        Ok((inputs, expected)) => {
+          let result = run_with_input(&config, &prog, inputs.into_iter().collect());
+          check(&expected, result)
+        }
+      };
+      Outcome {
+        name,
+        time: start.elapsed(),
+        failure,
+      }
+    })
+    .collect();
+  FileReport {
+    path: path.to_owned(),
+    outcomes,
+  }
+}
+
+/// Collects the `.while` files in a directory and its subdirectories, in sorted order.
+fn discover(path: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+  if !path.is_dir() {
+    files.push(path.to_owned());
+    return Ok(());
+  }
+  let mut entries = std::fs::read_dir(path)?
+    .map(|e| e.map(|e| e.path()))
+    .collect::<Result<Vec<_>, _>>()?;
+  entries.sort();
+  for entry in entries {
+    if entry.is_dir() {
+      discover(&entry, files)?;
+    } else if entry.extension().is_some_and(|e| e == "while") {
+      files.push(entry);
+    }
+  }
+  Ok(())
+}
+
+fn escape_xml(text: &str) -> String {
+  let mut escaped = String::new();
+  for c in text.chars() {
+    match c {
+      '&' => escaped.push_str("&amp;"),
+      '<' => escaped.push_str("&lt;"),
+      '>' => escaped.push_str("&gt;"),
+      '"' => escaped.push_str("&quot;"),
+      '\'' => escaped.push_str("&apos;"),
+      c => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+fn junit(reports: &[FileReport]) -> String {
+  let count = |r: &FileReport| r.outcomes.len();
+  let failures = |r: &FileReport| r.outcomes.iter().filter(|o| o.failure.is_some()).count();
+  let time = |r: &FileReport| r.outcomes.iter().map(|o| o.time).sum::<Duration>();
+  let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+  writeln!(
+    xml,
+    "<testsuites name=\"whily\" tests=\"{}\" failures=\"{}\" time=\"{:.6}\">",
+    reports.iter().map(count).sum::<usize>(),
+    reports.iter().map(failures).sum::<usize>(),
+    reports.iter().map(time).sum::<Duration>().as_secs_f64(),
+  )
+  .unwrap();
+  for report in reports.iter().filter(|r| count(r) > 0) {
+    let suite = escape_xml(&report.path.display().to_string());
+    writeln!(
+      xml,
+      "  <testsuite name=\"{suite}\" tests=\"{}\" failures=\"{}\" time=\"{:.6}\">",
+      count(report),
+      failures(report),
+      time(report).as_secs_f64(),
+    )
+    .unwrap();
+    for outcome in &report.outcomes {
+      let name = escape_xml(&outcome.name);
+      let time = outcome.time.as_secs_f64();
+      match &outcome.failure {
+        None => writeln!(
+          xml,
+          "    <testcase name=\"{name}\" classname=\"{suite}\" time=\"{time:.6}\"/>"
+        )
+        .unwrap(),
+        Some((reason, details)) => {
+          writeln!(
+            xml,
+            "    <testcase name=\"{name}\" classname=\"{suite}\" time=\"{time:.6}\">"
+          )
+          .unwrap();
+          writeln!(
+            xml,
+            "      <failure message=\"{}\">{}</failure>",
+            escape_xml(reason),
+            escape_xml(details)
+          )
+          .unwrap();
+          xml.push_str("    </testcase>\n");
+        }
+      }
+    }
+    xml.push_str("  </testsuite>\n");
+  }
+  xml.push_str("</testsuites>\n");
+  xml
+}
+
+/// Tests every `.while` file under `path`, each with the config `configure` gives for it.
+fn test_files(
+  path: &Path,
+  configure: impl Fn(&Path) -> Result<Config, String>,
+) -> std::io::Result<Vec<FileReport>> {
+  let mut files = vec![];
+  discover(path, &mut files)?;
+  Ok(
+    files
+      .iter()
+      .map(|file| test_file(configure(file), file))
+      .collect(),
+  )
+}
+
+/// Runs the test cases of every `.while` file under `path`, printing the results and writing a
+/// JUnit report if asked to. Every file is run with the config `configure` gives for it, so that
+/// it can use the whily.toml next to it. Returns whether every case passed.
+pub fn run_tests(
+  path: &Path,
+  junit_path: Option<&Path>,
+  configure: impl Fn(&Path) -> Result<Config, String>,
+) -> bool {
+  let reports = match test_files(path, configure) {
+    Ok(reports) => reports,
+    Err(e) => {
+      eprintln!("Could not read {}: {e}", path.display());
+      return false;
+    }
+  };
+
+  let (mut passed, mut failed) = (0, 0);
+  for report in &reports {
+    for outcome in &report.outcomes {
+      let path = report.path.display();
+      match &outcome.failure {
+        None => {
+          passed += 1;
+          println!("{path} {} ... ok", outcome.name);
+        }
+        Some((reason, details)) => {
+          failed += 1;
+          println!("{path} {} ... FAILED\n  {reason}", outcome.name);
+          for line in details.lines() {
+            println!("    {line}");
+          }
+        }
+      }
+    }
+  }
+  if passed + failed == 0 {
+    println!("No test cases found in {}.", path.display());
+  } else {
+    println!("\n{passed} passed, {failed} failed");
+  }
+
+  if let Some(junit_path) = junit_path {
+    if let Err(e) = std::fs::write(junit_path, junit(&reports)) {
+      eprintln!("Could not write {}: {e}", junit_path.display());
+      return false;
+    }
+  }
+  failed == 0
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::run::RuntimeError;
+
+  fn assignments(pairs: &[(&str, u64)]) -> Vec<(String, u64)> {
+    pairs.iter().map(|&(var, v)| (var.to_owned(), v)).collect()
+  }
+
+  #[test]
+  fn cases_are_parsed() {
+    let Ok((inputs, Expected::State(state))) = parse_case("x1=3, x02 = 4 => x0=12") else {
+      panic!("Expected a final state");
+    };
+    assert_eq!(inputs, assignments(&[("x1", 3), ("x2", 4)]));
+    assert_eq!(state, assignments(&[("x0", 12)]));
+    let Ok((inputs, Expected::Error(kind, var))) = parse_case("=> error UnassignedVariable x03")
+    else {
+      panic!("Expected an error");
+    };
+    assert!(inputs.is_empty());
+    assert_eq!(
+      (kind.as_str(), var.as_deref()),
+      ("UnassignedVariable", Some("x3"))
+    );
+    let Ok((_, Expected::Error(_, None))) = parse_case("x1=0 => error VariableUnderflow") else {
+      panic!("Expected an error without a variable");
+    };
+
+    let error = |text| parse_case(text).err().expect("Expected an invalid case");
+    assert_eq!(
+      error("x1=3"),
+      "Missing '=>' between the inputs and the expected result."
+    );
+    assert_eq!(
+      error("x1 => x0=1"),
+      "Expected 'variable=value', found 'x1'."
+    );
+    assert_eq!(
+      error("x1=-1 => x0=1"),
+      "'-1' is not a natural number below 2^64."
+    );
+    assert_eq!(error("a b=1 => x0=1"), "'a b' is not a variable.");
+    assert_eq!(
+      error("=> error"),
+      "Missing the kind of error after 'error'."
+    );
+    assert_eq!(
+      error("=> error VariableOverflow x0 x1"),
+      "Unexpected 'x1' after the expected error."
+    );
+  }
+
+  #[test]
+  fn cases_are_found_in_comments() {
+    let code = "[a comment]\nx0 := x1 [test: x1=1 => x0=1];\n[test:\n  x1=2\n  => x0=2]\n[test x0]\n[test: x1]";
+    let cases = find_cases(code);
+    let found = cases
+      .iter()
+      .map(|c| (c.line, c.text.as_str(), c.spec.is_ok()))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      found,
+      [
+        (2, "x1=1 => x0=1", true),
+        (3, "x1=2 => x0=2", true),
+        (7, "x1", false)
+      ]
+    );
+  }
+
+  #[test]
+  fn results_are_compared() {
+    let state = HashMap::from([("x0".to_owned(), 3), ("x1".to_owned(), 4)]);
+    let expected = Expected::State(assignments(&[("x0", 3), ("x1", 5), ("x2", 1)]));
+    assert_eq!(
+      check(&expected, Ok(state.clone())),
+      Some((
+        "Wrong final state".to_owned(),
+        "x1: expected 5, got 4\nx2: expected 1, but it was never assigned\n".to_owned()
+      ))
+    );
+    let expected = Expected::State(assignments(&[("x0", 3)]));
+    assert_eq!(check(&expected, Ok(state.clone())), None);
+    let (reason, details) = check(
+      &Expected::Error("VariableUnderflow".to_owned(), None),
+      Ok(state),
+    )
+    .unwrap();
+    assert_eq!(
+      reason,
+      "Expected error VariableUnderflow, but the program finished"
+    );
+    assert_eq!(details, "x0 = 3\nx1 = 4\n");
+
+    let underflow = || Err(RuntimeError::VariableUnderflow("x0".to_owned()));
+    let expect =
+      |var: Option<&str>| Expected::Error("VariableUnderflow".to_owned(), var.map(str::to_owned));
+    assert_eq!(check(&expect(None), underflow()), None);
+    assert_eq!(check(&expect(Some("x0")), underflow()), None);
+    let (reason, _) = check(&expect(Some("x1")), underflow()).unwrap();
+    assert_eq!(reason, "Expected error VariableUnderflow x1");
+  }
+
+  #[test]
+  fn junit_is_escaped() {
+    assert_eq!(
+      escape_xml("a<b & \"c\" > 'd'"),
+      "a&lt;b &amp; &quot;c&quot; &gt; &apos;d&apos;"
+    );
+    let report = FileReport {
+      path: PathBuf::from("a&b.while"),
+      outcomes: vec![
+        Outcome {
+          name: "line 1: x1=1 => x0=1".to_owned(),
+          time: Duration::ZERO,
+          failure: None,
+        },
+        Outcome {
+          name: "line 2: => x0<1".to_owned(),
+          time: Duration::ZERO,
+          failure: Some(("Wrong \"state\"".to_owned(), "x0: 1 < 2\n".to_owned())),
+        },
+      ],
+    };
+    let xml = junit(&[report]);
+    assert!(xml.contains("<testsuites name=\"whily\" tests=\"2\" failures=\"1\""));
+    assert!(xml.contains("<testsuite name=\"a&amp;b.while\" tests=\"2\" failures=\"1\""));
+    assert!(xml.contains("<testcase name=\"line 1: x1=1 =&gt; x0=1\" classname=\"a&amp;b.while\""));
+    assert!(xml.contains("<failure message=\"Wrong &quot;state&quot;\">x0: 1 &lt; 2\n</failure>"));
+  }
+
+  #[test]
+  fn every_file_uses_its_own_project_file() {
+    let root = std::env::temp_dir().join(format!("whily-tester-{}", std::process::id()));
+    let (lenient, strict) = (root.join("lenient"), root.join("strict"));
+    std::fs::create_dir_all(&lenient).unwrap();
+    std::fs::create_dir_all(&strict).unwrap();
+    std::fs::write(lenient.join("whily.toml"), "allow_underflow = true\n").unwrap();
+    let program = "x0 := x1 - x2 [test: x1=1, x2=2 => {}]";
+    std::fs::write(lenient.join("a.while"), program.replace("{}", "x0=0")).unwrap();
+    let underflow = program.replace("{}", "error VariableUnderflow x0");
+    std::fs::write(strict.join("b.while"), underflow).unwrap();
+    let reports = test_files(&root, |file| Config::for_program(Some(file))).unwrap();
+    std::fs::remove_dir_all(&root).unwrap();
+    let outcomes = reports
+      .iter()
+      .map(|r| (r.path.file_name().unwrap().to_owned(), r.outcomes.len()))
+      .collect::<Vec<_>>();
+    assert_eq!(outcomes, [("a.while".into(), 1), ("b.while".into(), 1)]);
+    for outcome in reports.iter().flat_map(|r| &r.outcomes) {
+      assert_eq!(outcome.failure, None, "{} failed", outcome.name);
+    }
+  }
+}
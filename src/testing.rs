@@ -0,0 +1,83 @@
+use crate::{
+  config::{Config, Dialect},
+  gen::{Generator, Rng},
+  parser::Statement,
+  run::run_with_input,
+};
+use std::collections::HashMap;
+
+// Helpers for the tests of the passes that rewrite programs, which check them by running random
+// programs before and after the rewrite.
+
+/// The step limit programs run with, so that generated loops that never stop don't hang a test.
+pub const MAX_STEPS: u64 = 20_000;
+
+/// The final state of a run, or the kind of error it stopped with and the variable it is about.
+pub type Outcome = Result<HashMap<String, u64>, (&'static str, Option<String>)>;
+
+pub struct Case {
+  pub config: Config,
+  pub prog: Statement,
+  pub input: HashMap<String, u64>,
+}
+
+pub fn outcome(config: &Config, prog: &Statement, input: &HashMap<String, u64>) -> Outcome {
+  let config = Config {
+    max_steps: Some(MAX_STEPS),
+    ..config.clone()
+  };
+  run_with_input(&config, prog, input.clone())
+    .map_err(|e| (e.kind(), e.variable().map(str::to_owned)))
+}
+
+/// `count` random programs with random small inputs, with the config `configure` picks.
+pub fn cases(seed: u64, count: usize, configure: impl Fn(&mut Rng) -> Config) -> Vec<Case> {
+  let mut rng = Rng::new(seed);
+  (0..count)
+    .map(|_| {
+      let config = configure(&mut rng);
+      let mut generator = Generator::new(
+        &config,
+        rng.next_u64(),
+        1 + rng.below(4) as usize,
+        rng.below(3) as usize,
+        rng.chance(1, 2),
+      );
+      // In the order of the variables, so that the same seed gives the same program
+      let inputs = generator
+        .variables()
+        .iter()
+        .filter(|_| rng.chance(2, 3))
+        .cloned()
+        .collect::<Vec<_>>();
+      let mut input = inputs
+        .iter()
+        .map(|var| (var.clone(), rng.below(12)))
+        .collect::<HashMap<_, _>>();
+      let prog = generator.program_with_inputs(1 + rng.below(16) as usize, &inputs);
+      // Passes may reuse the names of variables the program doesn't mention
+      let used = prog.variables();
+      input.retain(|var, _| used.contains(&var.as_str()));
+      Case {
+        config,
+        prog,
+        input,
+      }
+    })
+    .collect()
+}
+
+/// A config with random options, in the whily dialect unless `dialects` is set.
+pub fn random_config(rng: &mut Rng, dialects: bool) -> Config {
+  Config {
+    allow_named_vars: rng.chance(1, 2),
+    allow_underflow: rng.chance(1, 2),
+    allow_constants_everywhere: rng.chance(1, 2),
+    extra_operators: rng.chance(1, 2),
+    dialect: match dialects {
+      true => *rng.pick(&Dialect::ALL),
+      false => Dialect::Whily,
+    },
+    ..Config::default()
+  }
+}
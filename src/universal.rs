@@ -0,0 +1,510 @@
+use crate::{
+  compile,
+  config::Config,
+  desugar::lower,
+  godel::{canonicalise, encode, encode_list},
+  parser::{apply, assign, constant, looped, var, Statement, Value},
+  run::run,
+  symbolizer::Operator,
+};
+use num_bigint::BigUint;
+use num_traits::ToPrimitive;
+
+// Generates a universal WHILE program: a WHILE program that runs the program with Gödel number x1
+// (see godel.rs) on the input list x2, and leaves the simulated x0 in x0.
+//
+// Since variables only hold 64 bits, neither the state nor the stack of the simulated program can
+// be encoded into a single number, so both get a fixed amount of registers. The simulated program
+// keeps a stack of statements still to run:
+//   xi := a op b            computes the result and stores it in register i
+//   xi := a                 stores a in register i
+//   while xi != 0 do P od   pushes itself and then P if register i isn't 0
+//   P1; P2                  pushes P2 and then P1
+// Taking a statement off this stack counts as a single step.
+//
+// The program is core WHILE, so it runs with every option disabled. It is written with named
+// variables and constant operands, and then lowered (see desugar.rs), which renames them to
+// unused xi. Core WHILE can't compare two numbers without risking an underflow, so numbers are
+// only ever decremented after checking that they aren't 0: decoding counts up to the Gödel
+// number one at a time, walking through the pairs <a, b> in order. That takes time linear in
+// the Gödel numbers, and since they grow quickly with the size of a program, only small programs
+// can be run. Gödel numbers and input lists also have to fit in 64 bits.
+
+/// A variable holding 1, which is cheaper than a constant operand once lowered.
+fn one() -> Value {
+  var("one")
+}
+
+struct Generator {
+  vars: u64,
+  stack: u64,
+  temps: usize,
+}
+
+impl Generator {
+  fn temp(&mut self) -> String {
+    self.temps += 1;
+    format!("t{}", self.temps)
+  }
+
+  /// dst := 1 if v is 0, and 0 otherwise
+  fn is_zero(&mut self, dst: &str, v: Value) -> Vec<Statement> {
+    let t = self.temp();
+    vec![
+      assign(dst, constant(1)),
+      assign(&t, v),
+      looped(&t, vec![assign(dst, constant(0)), assign(&t, constant(0))]),
+    ]
+  }
+
+  /// dst := 1 if v isn't 0, and 0 otherwise
+  fn is_nonzero(&mut self, dst: &str, v: Value) -> Vec<Statement> {
+    let t = self.temp();
+    vec![
+      assign(dst, constant(0)),
+      assign(&t, v),
+      looped(&t, vec![assign(dst, constant(1)), assign(&t, constant(0))]),
+    ]
+  }
+
+  fn if_nonzero(&mut self, condition: Value, mut body: Vec<Statement>) -> Vec<Statement> {
+    let t = self.temp();
+    body.push(assign(&t, constant(0)));
+    vec![assign(&t, condition), looped(&t, body)]
+  }
+
+  fn if_zero(&mut self, condition: Value, body: Vec<Statement>) -> Vec<Statement> {
+    let zero = self.temp();
+    let mut out = self.is_zero(&zero, condition);
+    out.extend(self.if_nonzero(var(&zero), body));
+    out
+  }
+
+  /// x := x - 1, unless x is 0
+  fn decrement(&mut self, x: &str) -> Vec<Statement> {
+    let decrement = vec![apply(x, var(x), Operator::Subtract, one())];
+    self.if_nonzero(var(x), decrement)
+  }
+
+  /// dst := max(0, v1 - v2), taking v2 steps
+  fn monus(&mut self, dst: &str, v1: Value, v2: Value) -> Vec<Statement> {
+    let (r, n) = (self.temp(), self.temp());
+    let mut body = self.decrement(&r);
+    body.push(apply(&n, var(&n), Operator::Subtract, one()));
+    vec![
+      assign(&r, v1),
+      assign(&n, v2),
+      looped(&n, body),
+      assign(dst, var(&r)),
+    ]
+  }
+
+  fn multiply(&mut self, dst: &str, v1: Value, v2: Value) -> Vec<Statement> {
+    let (m, n, b) = (self.temp(), self.temp(), self.temp());
+    vec![
+      assign(&m, constant(0)),
+      assign(&n, v1),
+      assign(&b, v2),
+      looped(
+        &n,
+        vec![
+          apply(&m, var(&m), Operator::Add, var(&b)),
+          apply(&n, var(&n), Operator::Subtract, one()),
+        ],
+      ),
+      assign(dst, var(&m)),
+    ]
+  }
+
+  /// Splits v into quotient and remainder by `divisor`, counting down from v. The countdown is
+  /// unrolled `divisor` times, so that only every `divisor` steps start a new iteration.
+  fn divide(&mut self, quotient: &str, remainder: &str, v: Value, divisor: u64) -> Vec<Statement> {
+    let n = self.temp();
+    let mut level = vec![
+      apply(&n, var(&n), Operator::Subtract, one()),
+      apply(quotient, var(quotient), Operator::Add, one()),
+      assign(remainder, constant(0)),
+    ];
+    for k in (1..divisor).rev() {
+      let t = self.temp();
+      level.push(assign(&t, constant(0)));
+      level = vec![
+        apply(&n, var(&n), Operator::Subtract, one()),
+        assign(remainder, constant(k)),
+        assign(&t, var(&n)),
+        looped(&t, level),
+      ];
+    }
+    vec![
+      assign(&n, v),
+      assign(quotient, constant(0)),
+      assign(remainder, constant(0)),
+      looped(&n, level),
+    ]
+  }
+
+  /// Splits z = <a, b> into a and b, walking through <0, 0>, <1, 0>, <0, 1>, <2, 0>, ... which
+  /// are the pairs with the numbers 0, 1, 2, 3, ...
+  fn unpair(&mut self, a: &str, b: &str, z: Value) -> Vec<Statement> {
+    let (n, t, diagonal) = (self.temp(), self.temp(), self.temp());
+    vec![
+      assign(&n, z),
+      assign(a, constant(0)),
+      assign(b, constant(0)),
+      looped(
+        &n,
+        vec![
+          apply(&n, var(&n), Operator::Subtract, one()),
+          assign(&diagonal, constant(1)),
+          assign(&t, var(a)),
+          looped(
+            &t,
+            vec![
+              apply(a, var(a), Operator::Subtract, one()),
+              apply(b, var(b), Operator::Add, one()),
+              assign(&diagonal, constant(0)),
+              assign(&t, constant(0)),
+            ],
+          ),
+          looped(
+            &diagonal,
+            vec![
+              apply(a, var(b), Operator::Add, one()),
+              assign(b, constant(0)),
+              assign(&diagonal, constant(0)),
+            ],
+          ),
+        ],
+      ),
+    ]
+  }
+
+  /// Sets `hits[k]` to 1 if v = k and to 0 otherwise, for k below `hits.len()`, and `found` to
+  /// whether one of them is 1.
+  fn select(&mut self, v: Value, hits: &[String], found: &str) -> Vec<Statement> {
+    let i = self.temp();
+    let mut out = vec![assign(&i, v), assign(found, constant(0))];
+    for hit in hits {
+      out.extend(self.is_zero(hit, var(&i)));
+      out.extend(self.if_nonzero(var(found), vec![assign(hit, constant(0))]));
+      out.extend(self.if_nonzero(var(hit), vec![assign(found, constant(1))]));
+      out.extend(self.decrement(&i));
+    }
+    out
+  }
+
+  fn temps(&mut self, count: u64) -> Vec<String> {
+    (0..count).map(|_| self.temp()).collect()
+  }
+
+  /// dst := the register (r or s) with the given index, or 0 if it doesn't exist
+  fn get(&mut self, prefix: &str, count: u64, dst: &str, index: Value) -> Vec<Statement> {
+    let (hits, found) = (self.temps(count), self.temp());
+    let mut out = self.select(index, &hits, &found);
+    out.push(assign(dst, constant(0)));
+    for (k, hit) in hits.iter().enumerate() {
+      out.extend(self.if_nonzero(var(hit), vec![assign(dst, var(&format!("{prefix}{k}")))]));
+    }
+    out
+  }
+
+  /// Sets the register (r or s) with the given index, aborting if it doesn't exist
+  fn set(&mut self, prefix: &str, count: u64, index: Value, value: Value) -> Vec<Statement> {
+    let (hits, found, v) = (self.temps(count), self.temp(), self.temp());
+    let mut out = vec![assign(&v, value)];
+    out.extend(self.select(index, &hits, &found));
+    for (k, hit) in hits.iter().enumerate() {
+      out.extend(self.if_nonzero(var(hit), vec![assign(&format!("{prefix}{k}"), var(&v))]));
+    }
+    out.extend(self.if_zero(var(&found), vec![assign("abort", constant(1))]));
+    out
+  }
+
+  fn get_var(&mut self, dst: &str, index: Value) -> Vec<Statement> {
+    self.get("r", self.vars, dst, index)
+  }
+
+  fn set_var(&mut self, index: Value, value: Value) -> Vec<Statement> {
+    self.set("r", self.vars, index, value)
+  }
+
+  fn push(&mut self, value: Value) -> Vec<Statement> {
+    let mut out = self.set("s", self.stack, var("sp"), value);
+    out.push(apply("sp", var("sp"), Operator::Add, one()));
+    out
+  }
+
+  /// Only used while sp isn't 0
+  fn pop(&mut self, dst: &str) -> Vec<Statement> {
+    let mut out = vec![apply("sp", var("sp"), Operator::Subtract, one())];
+    out.extend(self.get("s", self.stack, dst, var("sp")));
+    out
+  }
+
+  /// dst := the value encoded by v, reading the register for variables
+  fn value(&mut self, dst: &str, v: Value) -> Vec<Statement> {
+    let (half, bit) = (self.temp(), self.temp());
+    let mut out = self.divide(&half, &bit, v, 2);
+    out.extend(self.get_var(dst, var(&half)));
+    out.extend(self.if_nonzero(var(&bit), vec![assign(dst, var(&half))]));
+    out
+  }
+
+  /// go := whether the simulation should take another step
+  fn keep_going(&mut self, bounded: bool) -> Vec<Statement> {
+    let out_of_fuel = self.temp();
+    let mut out = self.is_nonzero("go", var("sp"));
+    out.extend(self.if_nonzero(var("abort"), vec![assign("go", constant(0))]));
+    if bounded {
+      out.extend(self.is_zero(&out_of_fuel, var("fuel")));
+      out.extend(self.if_nonzero(var(&out_of_fuel), vec![assign("go", constant(0))]));
+    }
+    out
+  }
+
+  fn step(&mut self, bounded: bool) -> Vec<Statement> {
+    let mut out = vec![];
+    if bounded {
+      out.push(apply("fuel", var("fuel"), Operator::Subtract, one()));
+    }
+    out.extend(self.pop("stmt"));
+    out.extend(self.divide("code", "tag", var("stmt"), 4));
+    out.extend(self.unpair("left", "right", var("code")));
+
+    // xi := a op b, where only +, - and * are supported
+    let mut operation = self.unpair("v1", "rest", var("right"));
+    operation.extend(self.unpair("op", "v2", var("rest")));
+    operation.extend(self.value("a", var("v1")));
+    operation.extend(self.value("b", var("v2")));
+    let (ops, known) = (self.temps(3), self.temp());
+    operation.extend(self.select(var("op"), &ops, &known));
+    operation.push(assign("res", constant(0)));
+    let add = vec![apply("res", var("a"), Operator::Add, var("b"))];
+    operation.extend(self.if_nonzero(var(&ops[0]), add));
+    let subtract = self.monus("res", var("a"), var("b"));
+    operation.extend(self.if_nonzero(var(&ops[1]), subtract));
+    let product = self.multiply("res", var("a"), var("b"));
+    operation.extend(self.if_nonzero(var(&ops[2]), product));
+    operation.extend(self.if_zero(var(&known), vec![assign("abort", constant(1))]));
+    operation.extend(self.set_var(var("left"), var("res")));
+
+    // xi := a
+    let mut declare = self.value("a", var("right"));
+    declare.extend(self.set_var(var("left"), var("a")));
+
+    // while xi != 0 do P od
+    let mut while_loop = self.get_var("a", var("left"));
+    let mut repeat = self.push(var("stmt"));
+    repeat.extend(self.push(var("right")));
+    while_loop.extend(self.if_nonzero(var("a"), repeat));
+
+    // P1; P2
+    let mut sequence = self.push(var("right"));
+    sequence.extend(self.push(var("left")));
+
+    let (tags, found) = (self.temps(4), self.temp());
+    out.extend(self.select(var("tag"), &tags, &found));
+    for (tag, body) in tags.iter().zip([operation, declare, while_loop, sequence]) {
+      out.extend(self.if_nonzero(var(tag), body));
+    }
+    out
+  }
+
+  fn program(&mut self, bounded: bool) -> Statement {
+    let mut out = vec![assign("one", constant(1))];
+    for k in 0..self.vars {
+      out.push(assign(&format!("r{k}"), constant(0)));
+    }
+    for k in 0..self.stack {
+      out.push(assign(&format!("s{k}"), constant(0)));
+    }
+    out.push(assign("sp", constant(0)));
+    out.push(assign("abort", constant(0)));
+    if bounded {
+      out.push(assign("fuel", var("x3")));
+    }
+
+    // Reading the input list into x1, x2, ...
+    out.push(assign("i", constant(1)));
+    out.push(assign("list", var("x2")));
+    let mut read = vec![apply("list", var("list"), Operator::Subtract, one())];
+    read.extend(self.unpair("head", "list", var("list")));
+    read.extend(self.set_var(var("i"), var("head")));
+    read.push(apply("i", var("i"), Operator::Add, one()));
+    out.push(looped("list", read));
+
+    out.extend(self.push(var("x1")));
+    out.extend(self.keep_going(bounded));
+    let mut body = self.step(bounded);
+    body.extend(self.keep_going(bounded));
+    out.push(looped("go", body));
+    out.push(assign("x0", var("r0")));
+    if bounded {
+      let (empty, finished) = (self.temp(), self.temp());
+      out.extend(self.is_zero(&empty, var("sp")));
+      out.extend(self.is_zero(&finished, var("abort")));
+      out.extend(self.multiply("x4", var(&empty), var(&finished)));
+    }
+    Statement::from_statements(out).expect("Empty program")
+  }
+}
+
+/// Generates the source code of a universal program that can simulate programs using the variables
+/// x0 to x(vars - 1) with a stack of at most `stack` statements. The bounded variant only takes as
+/// many steps as given in x3, and sets x4 to 1 if the simulated program finished within them.
+pub fn generate(vars: u64, stack: u64, bounded: bool) -> String {
+  let mut generator = Generator {
+    vars,
+    stack,
+    temps: 0,
+  };
+  let (canonical, renames) = canonicalise(&generator.program(bounded));
+  let prog = lower(canonical, false);
+  let bounded_doc = if bounded {
+    "
+  x3: the maximum number of steps to simulate
+  x4: set to 1 if the program finished within x3 steps, 0 otherwise"
+  } else {
+    ""
+  };
+  // Temporaries are left out
+  let names = renames
+    .iter()
+    .filter(|(name, _)| !(name.starts_with('t') && name[1..].parse::<u64>().is_ok()))
+    .map(|(name, xi)| format!("{name} = {xi}"))
+    .collect::<Vec<_>>()
+    .join(", ");
+  format!(
+    "[
+  A universal WHILE program, generated by 'whily universal'.
+  x1: the Gödel number of the program to run, as printed by 'whily encode'
+  x2: the input list, with the empty list as 0 and h :: t as <h, t> + 1. The values are put in x1, x2, ...
+  x0: the x0 of the program after running it{bounded_doc}
+  Variables start at 0 and subtraction stops at 0. Programs can use x0 to x{}, the operators +, - and *,
+  and {stack} stack entries. Using anything else sets abort to 1 and stops the simulation.
+  Decoding takes time linear in the Gödel numbers, so only small programs are practical, and x1
+  and x2 have to fit in 64 bits. Its loops run longer than allowed without a step limit, so run
+  it with --max_steps.
+  The registers of the variables (r) and the stack (s), and the other named variables, are
+  {names}
+]
+
+{prog}
+",
+    vars - 1,
+  )
+}
+
+// Decoding takes time linear in the Gödel numbers, so these are kept below a million
+const VALIDATION_PROGRAMS: [(&str, &[u64]); 7] = [
+  ("x0 := x1 + x2", &[3, 4]),
+  ("x0 := x1 - x2", &[3, 5]),
+  ("x0 := x1 * x2", &[6, 7]),
+  ("x0 := x0 + x1", &[9]),
+  ("x1 := 2; x0 := x1", &[4]),
+  ("x0 := x1; x0 := 2", &[4]),
+  ("while x1 != 0 do x1 := x2 od", &[5, 0]),
+];
+
+// The most steps a run of the universal program may take
+const MAX_STEPS: u64 = 100_000_000;
+
+/// Runs a couple of small programs both directly and through the universal program, printing the
+/// results. Returns whether all of them matched.
+pub fn validate(vars: u64, stack: u64) -> bool {
+  // Core WHILE, so the options stay disabled
+  let config = Config {
+    max_steps: Some(MAX_STEPS),
+    ..Config::default()
+  };
+  let universal = |bounded| {
+    compile(&mut config.clone(), &generate(vars, stack, bounded))
+      .expect("Universal program doesn't compile")
+  };
+  let (unbounded, bounded) = (universal(false), universal(true));
+  let direct_config = Config {
+    allow_underflow: true,
+    allow_constants_everywhere: true,
+    extra_operators: true,
+    ..Config::default()
+  };
+
+  let mut all_ok = true;
+  for (source, inputs) in VALIDATION_PROGRAMS {
+    let prog = compile(&mut direct_config.clone(), source).expect("Invalid validation program");
+    let inputs_text = inputs
+      .iter()
+      .map(|i| i.to_string())
+      .collect::<Vec<_>>()
+      .join(", ");
+    print!("{source} (input {inputs_text}): ");
+
+    let code = encode(&prog).expect("Validation program isn't x-indexed");
+    let list = encode_list(&inputs.iter().map(|i| BigUint::from(*i)).collect::<Vec<_>>());
+    let (Some(code), Some(list)) = (code.to_u64(), list.to_u64()) else {
+      println!("the encoding doesn't fit in 64 bits (FAILED)");
+      all_ok = false;
+      continue;
+    };
+
+    let mut init = (0..vars)
+      .map(|k| assign(&format!("x{k}"), constant(0)))
+      .collect::<Vec<_>>();
+    for (k, input) in inputs.iter().enumerate() {
+      init.push(assign(&format!("x{}", k + 1), constant(*input)));
+    }
+    init.push(prog);
+    let expected =
+      run(&direct_config, &Statement::from_statements(init).unwrap()).map(|state| state["x0"]);
+
+    let simulate = |prog: &Statement, steps: Option<u64>| {
+      let mut with_input = vec![assign("x1", constant(code)), assign("x2", constant(list))];
+      // Only the bounded program reads x3, the other one may use it as a register
+      with_input.extend(steps.map(|steps| assign("x3", constant(steps))));
+      with_input.push(prog.clone());
+      let with_input = Statement::from_statements(with_input).unwrap();
+      run(&config, &with_input).map(|state| (state["x0"], state.get("x4").copied()))
+    };
+    let result = simulate(&unbounded, None);
+    let within_bound = simulate(&bounded, Some(100));
+    let out_of_steps = simulate(&bounded, Some(0));
+
+    let ok = match (&expected, &result, &within_bound, &out_of_steps) {
+      (Ok(e), Ok((r, _)), Ok((b, Some(1))), Ok((_, Some(0)))) => e == r && e == b,
+      _ => false,
+    };
+    all_ok &= ok;
+    match (expected, result) {
+      (Ok(e), Ok((r, _))) => println!(
+        "expected {e}, got {r} ({})",
+        if ok { "ok" } else { "FAILED" }
+      ),
+      (e, r) => println!("expected {e:?}, got {r:?} (FAILED)"),
+    }
+  }
+  all_ok
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::printer::pragmas;
+
+  #[test]
+  fn validates() {
+    assert!(validate(4, 8));
+  }
+
+  #[test]
+  fn is_core_while() {
+    for bounded in [false, true] {
+      let mut config = Config::default();
+      compile(&mut config, &generate(4, 8, bounded)).unwrap();
+      assert_eq!(
+        pragmas(&config),
+        "",
+        "The universal program enables options"
+      );
+    }
+  }
+}